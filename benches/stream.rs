@@ -0,0 +1,37 @@
+//! Reading framed messages off a stream.
+use ::criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ::wesmaild::stream::{Reader, Writer};
+
+/// How many messages are read in each iteration.
+const MESSAGES: usize = 2000;
+
+/// Many small messages, framed and compressed as a client would send them,
+/// all already received, so the reader buffers lots of them at once.
+fn small_messages() -> Vec<u8> {
+    let rt = ::tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut writer = Writer::new(Vec::new());
+    rt.block_on(async {
+        for i in 0..MESSAGES {
+            let msg = format!("[message]\nmessage=\"{}\"\nsender=\"lol\"\n[/message]\n", i);
+            writer.write(msg.as_bytes()).await.unwrap();
+        }
+    });
+    writer.into_inner()
+}
+
+fn read_small_messages(c: &mut Criterion) {
+    let bytes = small_messages();
+    let rt = ::tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut group = c.benchmark_group("read_small_messages");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    group.bench_function("buffered", |b| b.iter(|| rt.block_on(async {
+        let mut reader = Reader::new(&bytes[..]);
+        for _ in 0..MESSAGES {
+            reader.read().await.unwrap();
+        }
+    })));
+    group.finish();
+}
+
+criterion_group!(benches, read_small_messages);
+criterion_main!(benches);
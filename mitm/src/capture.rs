@@ -0,0 +1,118 @@
+//! Recording messages to a file, to look at again later.
+//!
+//! A capture is [`MAGIC`] followed by any number of records, each of which is:
+//! - the side that sent the message, `0` for the client or `1` for the server,
+//! - when it was sent, in milliseconds since the Unix epoch, as a big endian `u64`,
+//! - the length of the message, as a big endian `u32`,
+//! - and the message itself, decompressed.
+use crate::Side;
+use ::core::convert::TryFrom;
+use ::std::io::{self, Read, Write};
+use ::std::sync::{Arc, Mutex};
+use ::std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The first bytes of every capture.
+const MAGIC: &[u8; 8] = b"mitmcap1";
+
+/// Where records go, shared by both sides of every connection.
+pub type Recorder = Arc<Mutex<dyn Write + Send>>;
+
+/// One message, as it was seen passing through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub side: Side,
+    /// Kept to the millisecond.
+    pub time: SystemTime,
+    pub msg: Vec<u8>,
+}
+
+impl Record {
+    pub fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        let side = match self.side {
+            Side::Client => 0,
+            Side::Server => 1,
+        };
+        let millis = self.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let len = u32::try_from(self.msg.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long to record"))?;
+        w.write_all(&[side])?;
+        w.write_all(&millis.to_be_bytes())?;
+        w.write_all(&len.to_be_bytes())?;
+        w.write_all(&self.msg)
+    }
+    /// Read the next record, or `None` if the capture ends before it.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Option<Self>> {
+        let mut side = [0];
+        match r.read_exact(&mut side) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            res => res?,
+        }
+        let side = match side {
+            [0] => Side::Client,
+            [1] => Side::Server,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown side in capture")),
+        };
+        let mut millis = [0; 8];
+        r.read_exact(&mut millis)?;
+        let mut len = [0; 4];
+        r.read_exact(&mut len)?;
+        let mut msg = Vec::new();
+        r.take(u32::from_be_bytes(len).into()).read_to_end(&mut msg)?;
+        if msg.len() != u32::from_be_bytes(len) as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into())
+        }
+        let time = UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis));
+        Ok(Some(Self { side, time, msg }))
+    }
+}
+
+/// Start a new capture in `w`.
+pub fn write_header<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(MAGIC)
+}
+
+/// Read every record from a capture.
+pub fn read_capture<R: Read>(mut r: R) -> io::Result<Vec<Record>> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a capture"))
+    }
+    let mut records = Vec::new();
+    while let Some(record) = Record::read_from(&mut r)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_capture, write_header, Record};
+    use crate::Side;
+    use ::std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn capture_round_trip() {
+        let start = UNIX_EPOCH + Duration::from_millis(1_634_000_000_123);
+        let session = [
+            (Side::Server, 0, &b"[version]\n[/version]\n"[..]),
+            (Side::Client, 15, b"[version]\nversion=\"1.16.0\"\n[/version]\n"),
+            (Side::Server, 16, b"[mustlogin]\n[/mustlogin]\n"),
+            (Side::Client, 2000, b""),
+        ];
+        let records = session.iter().map(|&(side, after, msg)| Record {
+            side,
+            time: start + Duration::from_millis(after),
+            msg: msg.to_vec(),
+        }).collect::<Vec<_>>();
+        let mut capture = Vec::new();
+        write_header(&mut capture).unwrap();
+        for record in &records {
+            record.write_to(&mut capture).unwrap();
+        }
+        assert_eq!(read_capture(&*capture).unwrap(), records);
+
+        assert!(read_capture(&capture[..capture.len() - 20]).is_err());
+        assert!(read_capture(&capture[1..]).is_err());
+    }
+}
@@ -1,20 +1,32 @@
 //! A tool for inspecting traffic between a Wesnoth client and the `wesnothd` server.
 
-use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
-use ::tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+mod capture;
+
+use crate::capture::{Record, Recorder};
+use ::std::collections::VecDeque;
+use ::std::fs::File;
+use ::std::io::{self, BufReader, BufWriter};
+use ::std::path::{Path, PathBuf};
+use ::std::sync::{Arc, Mutex};
+use ::std::time::SystemTime;
+use ::tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use ::tokio::net::{TcpListener, TcpStream};
 use ::tokio::sync::mpsc;
+use ::wml::DocProcessor;
 
-async fn run_middle(_shutdown: mpsc::Sender<()>, mut reader: OwnedReadHalf, mut writer: OwnedWriteHalf, mut on_msg: impl FnMut(&[u8])) {
+/// Forward everything `reader` produces to `writer`, untouched,
+/// passing each whole message it contains to `on_msg` as it completes.
+async fn run_middle<R, W>(_shutdown: mpsc::Sender<()>, mut reader: R, mut writer: W, mut framer: Framer, mut on_msg: impl FnMut(&[u8]))
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
     let mut buf = Vec::with_capacity(1024);
     loop {
         match reader.read_buf(&mut buf).await {
             Ok(n) if n != 0 => {
-                on_msg(&buf);
                 match writer.write_all(&buf).await {
                     Ok(()) => (),
                     Err(e) => ::tracing::warn!("write failure: {:?}", e),
                 }
+                framer.push(&buf, &mut on_msg);
                 buf.clear();
             },
             Ok(_) => {
@@ -26,6 +38,7 @@ async fn run_middle(_shutdown: mpsc::Sender<()>, mut reader: OwnedReadHalf, mut
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Side {
     Server,
     Client,
@@ -39,88 +52,237 @@ impl Side {
     }
 }
 
-fn process_msg(side: Side) -> impl FnMut(&[u8]) {
-    use ::flate2::read::MultiGzDecoder;
-    use ::std::io::Read;
-    // Let's give each connection a megabyte of wiggle room.
-    // This *should* be significantly more than it needs, but we'll see.
+/// The four bytes at the front of `buf`, if it has that many.
+fn prefix(buf: &VecDeque<u8>) -> Option<[u8; 4]> {
+    let mut bytes = buf.iter().copied();
+    Some([bytes.next()?, bytes.next()?, bytes.next()?, bytes.next()?])
+}
+
+enum State {
+    PreHandshake,
+    Normal,
+}
+
+/// Finds the edges of messages in what one side of a connection sends.
+struct Framer {
+    side: Side,
     // We use this to store up bytes from multiple `.read()` calls
     // and find the edges of messages as per Wesnoth's network protocol.
-    // TODO: consider using a `VecDeque` because of how much we drain the front of the buffer
-    let mut buf = Vec::<u8>::with_capacity(1024 * 1024);
-    enum State {
-        PreHandshake,
-        Normal,
+    // Messages are drained from the front, so this doesn't shuffle what's left over.
+    buf: VecDeque<u8>,
+    state: State,
+}
+impl Framer {
+    fn new(side: Side) -> Self {
+        // Let's give each connection a megabyte of wiggle room.
+        // This *should* be significantly more than it needs, but we'll see.
+        Self { side, buf: VecDeque::with_capacity(1024 * 1024), state: State::PreHandshake }
     }
-    let mut state = State::PreHandshake;
-    move |msg| {
-        buf.extend(msg);
-        match state {
-            // The Wesnoth network protocol goes like this:
-            // 1. Start TCP Session
-            // 2. Perform Handshake
-            // 3. Exchange data via gzipped WML
-            //   - Messages are denoted by the ending of segments of length
-            //     given by a big endian `u32` prefix.
-            State::PreHandshake => {
-                // consume initial handshake, consider pointing out incorrect handshakes
-                match side {
-                    Side::Server => {
-                        if let [0, 0, 0, 42, ..] = *buf {
-                            buf.drain(..4);
-                            state = State::Normal;
-                        } else if let [a, b, c, d, ..] = *buf {
-                            ::tracing::warn!("incorrect server handshake [{}, {}, {}, {}]", a, b, c, d);
-                            buf.drain(..4);
-                            state = State::Normal;
-                        }
-                    },
-                    Side::Client => {
-                        if let [0, 0, 0, 0, ..] = *buf {
-                            buf.drain(..4);
-                            state = State::Normal;
-                        } else if let [a, b, c, d, ..] = *buf {
-                            ::tracing::warn!("incorrect client handshake [{}, {}, {}, {}]", a, b, c, d);
-                            buf.drain(..4);
-                            state = State::Normal;
-                        }
-                    },
-                }
-            },
-            State::Normal => {
-                // decompress gzipped payloads
-                // Note, not necessarily all payloads will be gzipped.
-                // We decode them on a best effort basis, based on observation
-                // of patterns in payloads.
-                while let [a, b, c, d, ref rest @ ..] = *buf {
-                    let len = u32::from_be_bytes([a, b, c, d]) as usize;
-                    if rest.len() >= len {
-                        ::tracing::info!("{}: {:?}", side.name(), &rest[..len]);
-                        let mut gz = MultiGzDecoder::new(&rest[..len]);
-                        let mut gz_buf = Vec::new();
-                        let _ = dbg!(gz.read_to_end(&mut gz_buf));
-                        let s = String::from_utf8_lossy(&gz_buf);
-                        ::tracing::info!("[decoded] {}: {:?}", side.name(), gz_buf);
-                        ::tracing::info!("[decoded(utf-8)] {}: {:?}", side.name(), s);
-                        // remove the now handled message from the buffer
-                        buf.drain(..4 + len);
-                    } else {
-                        ::tracing::info!("waiting for more input...");
-                        break
+    /// Take in bytes as they were read, passing each message they complete to `on_msg`,
+    /// without its length prefix.
+    fn push(&mut self, bytes: &[u8], mut on_msg: impl FnMut(&[u8])) {
+        let buf = &mut self.buf;
+        buf.extend(bytes);
+        // The Wesnoth network protocol goes like this:
+        // 1. Start TCP Session
+        // 2. Perform Handshake
+        // 3. Exchange data via gzipped WML
+        //   - Messages are denoted by the ending of segments of length
+        //     given by a big endian `u32` prefix.
+        if let State::PreHandshake = self.state {
+            // consume initial handshake, pointing out incorrect handshakes
+            let expected = match self.side {
+                Side::Server => [0, 0, 0, 42],
+                Side::Client => [0, 0, 0, 0],
+            };
+            match prefix(buf) {
+                Some(handshake) => {
+                    if handshake != expected {
+                        let [a, b, c, d] = handshake;
+                        ::tracing::warn!("incorrect {} handshake [{}, {}, {}, {}]", self.side.name(), a, b, c, d);
                     }
-                }
+                    buf.drain(..4);
+                    self.state = State::Normal;
+                },
+                None => return,
             }
+        }
+        while let Some(prefix) = prefix(buf) {
+            let len = u32::from_be_bytes(prefix) as usize;
+            if buf.len() - 4 >= len {
+                // remove the now handled message from the buffer
+                let msg = buf.drain(..4 + len).skip(4).collect::<Vec<u8>>();
+                on_msg(&msg);
+            } else {
+                break
+            }
+        }
+    }
+}
+
+/// What a message turned out to hold.
+#[derive(Debug, PartialEq, Eq)]
+enum Payload {
+    Gzip(Vec<u8>),
+    Bzip2(Vec<u8>),
+    /// Not compressed in any way we recognize, so taken as it is.
+    Raw,
+}
+
+/// Decompress a message, if it starts with the magic bytes of gzip or bzip2.
+fn decode(msg: &[u8]) -> ::std::io::Result<Payload> {
+    use ::std::io::Read;
+    let mut decoded = Vec::new();
+    match msg {
+        [0x1f, 0x8b, ..] => {
+            ::flate2::read::MultiGzDecoder::new(msg).read_to_end(&mut decoded)?;
+            Ok(Payload::Gzip(decoded))
+        },
+        [b'B', b'Z', b'h', ..] => {
+            ::bzip2::read::MultiBzDecoder::new(msg).read_to_end(&mut decoded)?;
+            Ok(Payload::Bzip2(decoded))
+        },
+        _ => Ok(Payload::Raw),
+    }
+}
+
+/// Render a decompressed message for reading: pretty printed if it's WML,
+/// or as (lossy) text if it isn't.
+fn render(processor: &mut DocProcessor, msg: &[u8]) -> String {
+    let pretty = match processor.parse(msg.to_vec()) {
+        Ok(doc) => {
+            let mut out = Vec::new();
+            doc.write_pretty(&mut out, "  ").expect("writing to a Vec can't fail");
+            Some(out)
+        },
+        Err(e) => {
+            ::tracing::debug!("message isn't WML: {}", e);
+            None
+        },
+    };
+    processor.reset();
+    String::from_utf8_lossy(pretty.as_deref().unwrap_or(msg)).into_owned()
+}
+
+/// Log a message from `side`, and what it decompresses to,
+/// also recording the latter to `recorder`, if there is one.
+fn log_msg(side: Side, recorder: Option<Recorder>) -> impl FnMut(&[u8]) {
+    let mut processor = DocProcessor::new();
+    move |msg| {
+        ::tracing::info!("{}: {:?}", side.name(), msg);
+        // Not necessarily all payloads will be compressed,
+        // so we decode them on a best effort basis.
+        let decoded = match decode(msg) {
+            Ok(Payload::Gzip(decoded)) | Ok(Payload::Bzip2(decoded)) => decoded,
+            Ok(Payload::Raw) => {
+                record(&recorder, side, msg);
+                ::tracing::info!("[raw] {}:\n{}", side.name(), render(&mut processor, msg));
+                return
+            },
+            Err(e) => {
+                // Recorded as it is, since that's all there is to see.
+                record(&recorder, side, msg);
+                ::tracing::warn!("{}: failed to decompress message: {}", side.name(), e);
+                return
+            },
         };
+        record(&recorder, side, &decoded);
+        let crc = ::crc32fast::hash(&decoded);
+        ::tracing::info!("[decoded] {} (crc32 {}):\n{}", side.name(), crc, render(&mut processor, &decoded));
+    }
+}
+
+/// Add a message to the capture, if one is being recorded.
+fn record(recorder: &Option<Recorder>, side: Side, msg: &[u8]) {
+    if let Some(recorder) = recorder {
+        let record = Record { side, time: SystemTime::now(), msg: msg.to_vec() };
+        let mut w = recorder.lock().unwrap();
+        // Flushed every time, so the capture is whole however the tool is stopped.
+        if let Err(e) = record.write_to(&mut *w).and_then(|()| w.flush()) {
+            ::tracing::warn!("failed to record message: {:?}", e);
+        }
+    }
+}
+
+/// Print every message in the capture at `path`, for `--replay`.
+fn replay(path: &Path) -> io::Result<()> {
+    let records = capture::read_capture(BufReader::new(File::open(path)?))?;
+    let start = records.first().map_or(SystemTime::UNIX_EPOCH, |record| record.time);
+    let mut processor = DocProcessor::new();
+    for record in records {
+        let after = record.time.duration_since(start).unwrap_or_default();
+        println!("{} at +{:.3}s:", record.side.name(), after.as_secs_f64());
+        println!("{}", render(&mut processor, &record.msg));
+    }
+    Ok(())
+}
+
+/// Write every message the server sent in the capture at `path` to a file of its own in `dir`,
+/// for `--extract`, to use as benchmark fixtures.
+fn extract(path: &Path, dir: &Path) -> io::Result<()> {
+    let records = capture::read_capture(BufReader::new(File::open(path)?))?;
+    ::std::fs::create_dir_all(dir)?;
+    for (name, msg) in fixtures(&records) {
+        ::std::fs::write(dir.join(name), msg)?;
     }
+    Ok(())
 }
 
+/// The messages the server sent in `records`, each with a file name made of
+/// where it is in the capture and the name of its first tag.
+fn fixtures(records: &[Record]) -> impl Iterator<Item = (String, &[u8])> {
+    records.iter().enumerate().filter(|(_, record)| record.side == Side::Server).map(|(i, record)| {
+        let tag = ::wml::peek_first_tag_name(&record.msg).unwrap_or("message");
+        (format!("{:04}_{}.wml", i, tag), &*record.msg)
+    })
+}
 
-#[::tracing::instrument]
-async fn start_session(shutdown: mpsc::Sender<()>, client: TcpStream) -> Result<(), ()> {
+/// Configuration, taken from the command line.
+#[derive(Debug, PartialEq, Eq)]
+struct Config {
+    /// Address to accept client connections on, given `--listen <addr>`.
+    listen: String,
+    /// Address of the server to pass them on to, given `--connect <addr>`.
+    connect: String,
+    /// File to record messages to, given `--record <path>`.
+    record: Option<PathBuf>,
+    /// Capture to print instead of running, given `--replay <path>`.
+    replay: Option<PathBuf>,
+    /// Capture to take the server's messages out of instead of running,
+    /// and the directory to put them in, given `--extract <path> <dir>`.
+    extract: Option<(PathBuf, PathBuf)>,
+}
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut listen = String::from("127.0.0.1:10900");
+        let mut connect = String::from("127.0.0.1:15000");
+        let (mut record, mut replay, mut extract) = (None, None, None);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("missing value for {}", arg));
+            match &*arg {
+                "--listen" => listen = value()?,
+                "--connect" => connect = value()?,
+                "--record" => record = Some(value()?.into()),
+                "--replay" => replay = Some(value()?.into()),
+                "--extract" => extract = Some((value()?.into(), value()?.into())),
+                _ => return Err(format!("unrecognized argument {}", arg)),
+            }
+        }
+        Ok(Self { listen, connect, record, replay, extract })
+    }
+}
+
+#[::tracing::instrument(skip(recorder))]
+async fn start_session(shutdown: mpsc::Sender<()>, client: TcpStream, upstream: String, recorder: Option<Recorder>) -> Result<(), ()> {
     let (client_rx, client_tx) = client.into_split();
-    let (server_rx, server_tx) = TcpStream::connect("127.0.0.1:15000").await.map_err(|_| ())?.into_split();
-    ::tokio::spawn(run_middle(shutdown.clone(), client_rx, server_tx, process_msg(Side::Client)));
-    ::tokio::spawn(run_middle(shutdown, server_rx, client_tx, process_msg(Side::Server)));
+    let server = TcpStream::connect(&upstream).await.map_err(|e| {
+        eprintln!("failed to connect to {}: {}", upstream, e);
+        ::tracing::warn!("failed to connect to {}: {:?}", upstream, e);
+    })?;
+    let (server_rx, server_tx) = server.into_split();
+    let (client_log, server_log) = (log_msg(Side::Client, recorder.clone()), log_msg(Side::Server, recorder));
+    ::tokio::spawn(run_middle(shutdown.clone(), client_rx, server_tx, Framer::new(Side::Client), client_log));
+    ::tokio::spawn(run_middle(shutdown, server_rx, client_tx, Framer::new(Side::Server), server_log));
     Ok(())
 }
 
@@ -130,7 +292,42 @@ async fn main() {
     let file_appender = ::tracing_appender::rolling::hourly("log", "mitm.log");
     let (non_blocking, _guard) = ::tracing_appender::non_blocking(file_appender);
     tracing_subscriber::fmt().with_writer(non_blocking).init();
-    let listener = TcpListener::bind("127.0.0.1:10900").await.unwrap();
+    let config = Config::from_args(::std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        ::std::process::exit(2)
+    });
+    if let Some(path) = &config.replay {
+        replay(path).unwrap_or_else(|e| {
+            eprintln!("failed to replay {}: {}", path.display(), e);
+            ::std::process::exit(1)
+        });
+        return
+    }
+    if let Some((path, dir)) = &config.extract {
+        extract(path, dir).unwrap_or_else(|e| {
+            eprintln!("failed to extract {} into {}: {}", path.display(), dir.display(), e);
+            ::std::process::exit(1)
+        });
+        return
+    }
+    let recorder = config.record.as_ref().map(|path| {
+        let mut file = BufWriter::new(File::create(path)?);
+        capture::write_header(&mut file)?;
+        Ok::<Recorder, io::Error>(Arc::new(Mutex::new(file)))
+    });
+    let recorder = recorder.transpose().unwrap_or_else(|e| {
+        eprintln!("failed to start recording: {}", e);
+        ::std::process::exit(1)
+    });
+    // Catch a mistyped server address now, rather than when the first client connects.
+    if let Err(e) = ::tokio::net::lookup_host(&config.connect).await {
+        eprintln!("invalid server address {}: {}", config.connect, e);
+        ::std::process::exit(2)
+    }
+    let listener = TcpListener::bind(&config.listen).await.unwrap_or_else(|e| {
+        eprintln!("failed to listen on {}: {}", config.listen, e);
+        ::std::process::exit(1)
+    });
     let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
     loop {
         ::tokio::select! {
@@ -142,7 +339,7 @@ async fn main() {
                 match res {
                     Ok((stream, _)) => {
                         ::tracing::info!("starting new session...");
-                        ::tokio::spawn(start_session(shutdown_tx.clone(), stream));
+                        ::tokio::spawn(start_session(shutdown_tx.clone(), stream, config.connect.clone(), recorder.clone()));
                     },
                     Err(e) => {
                         ::tracing::warn!("failed to accept a connection: {:?}", e);
@@ -164,3 +361,152 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, fixtures, log_msg, render, run_middle, Config, Framer, Payload, Side};
+    use crate::capture::{self, Recorder};
+    use ::std::sync::{Arc, Mutex};
+    use ::wml::DocProcessor;
+    use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use ::tokio::sync::mpsc;
+
+    #[::tokio::test]
+    async fn message_split_across_reads() {
+        let mut sent = vec![0, 0, 0, 0, 0, 0, 0, 5];
+        sent.extend(b"hello");
+        let (mut client, middle_rx) = ::tokio::io::duplex(64);
+        let (middle_tx, mut server) = ::tokio::io::duplex(64);
+        let (shutdown, _) = mpsc::channel(1);
+        let mut msgs = Vec::new();
+        let middle = run_middle(shutdown, middle_rx, middle_tx, Framer::new(Side::Client), |msg| msgs.push(msg.to_vec()));
+        let client = async move {
+            client.write_all(&sent[..10]).await.unwrap();
+            ::tokio::time::sleep(::std::time::Duration::from_millis(10)).await;
+            client.write_all(&sent[10..]).await.unwrap();
+        };
+        ::tokio::join!(middle, client);
+        assert_eq!(msgs, [b"hello"]);
+        let mut forwarded = Vec::new();
+        server.read_to_end(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, [0, 0, 0, 0, 0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+    }
+
+    #[test]
+    fn split_frames() {
+        let mut framer = Framer::new(Side::Server);
+        let mut msgs = Vec::new();
+        for chunk in [&[0, 0][..], &[0, 42, 0, 0, 0], &[2, b'h', b'i', 0, 0, 0, 1, b'!', 0]] {
+            framer.push(chunk, |msg| msgs.push(msg.to_vec()));
+        }
+        assert_eq!(msgs, [&b"hi"[..], b"!"]);
+    }
+
+    #[test]
+    fn payloads() {
+        use ::std::io::Write;
+        let wml = b"[version]\n[/version]\n";
+        assert_eq!(decode(wml).unwrap(), Payload::Raw);
+        assert_eq!(decode(b"").unwrap(), Payload::Raw);
+
+        let mut gz = ::flate2::write::GzEncoder::new(Vec::new(), ::flate2::Compression::fast());
+        gz.write_all(wml).unwrap();
+        assert_eq!(decode(&gz.finish().unwrap()).unwrap(), Payload::Gzip(wml.to_vec()));
+        let mut bz = ::bzip2::write::BzEncoder::new(Vec::new(), ::bzip2::Compression::fast());
+        bz.write_all(wml).unwrap();
+        assert_eq!(decode(&bz.finish().unwrap()).unwrap(), Payload::Bzip2(wml.to_vec()));
+
+        assert!(decode(&[0x1f, 0x8b, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn render_gamelist_update() {
+        let mut processor = DocProcessor::new();
+        let update = concat!(
+            "[gamelist_diff]\n[change_child]\nindex=\"0\"\n[gamelist]\n[insert_child]\nindex=\"3\"\n",
+            "[game]\nid=\"12\"\nmp_scenario=\"multiplayer_Caves_of_the_Basilisk\"\nname=\"lol's game\"\n[/game]\n",
+            "[/insert_child]\n[/gamelist]\n[/change_child]\n[/gamelist_diff]\n",
+        );
+        assert_eq!(render(&mut processor, update.as_bytes()), concat!(
+            "[gamelist_diff]\n",
+            "  [change_child]\n",
+            "    index=\"0\"\n",
+            "    [gamelist]\n",
+            "      [insert_child]\n",
+            "        index=\"3\"\n",
+            "        [game]\n",
+            "          id=\"12\"\n",
+            "          mp_scenario=\"multiplayer_Caves_of_the_Basilisk\"\n",
+            "          name=\"lol's game\"\n",
+            "        [/game]\n",
+            "      [/insert_child]\n",
+            "    [/gamelist]\n",
+            "  [/change_child]\n",
+            "[/gamelist_diff]\n",
+        ));
+        assert_eq!(render(&mut processor, b"[gamelist_diff]\n\xff"), "[gamelist_diff]\n\u{fffd}");
+    }
+
+    #[test]
+    fn args() {
+        let args = |args: &[&str]| Config::from_args(args.iter().map(|&arg| arg.to_owned()));
+        assert_eq!(args(&[]), Ok(Config {
+            listen: "127.0.0.1:10900".into(),
+            connect: "127.0.0.1:15000".into(),
+            record: None,
+            replay: None,
+            extract: None,
+        }));
+        assert_eq!(
+            args(&["--connect", "server.wesnoth.org:15000", "--listen", "0.0.0.0:10900", "--record", "session.cap"]),
+            Ok(Config {
+                listen: "0.0.0.0:10900".into(),
+                connect: "server.wesnoth.org:15000".into(),
+                record: Some("session.cap".into()),
+                replay: None,
+                extract: None,
+            }),
+        );
+        assert_eq!(
+            args(&["--extract", "session.cap", "fixtures"]).map(|config| config.extract),
+            Ok(Some(("session.cap".into(), "fixtures".into()))),
+        );
+        assert!(args(&["--extract", "session.cap"]).is_err());
+        assert!(args(&["--connect"]).is_err());
+        assert!(args(&["--upstream", "x"]).is_err());
+    }
+
+    #[test]
+    fn records_decoded_messages() {
+        use ::std::io::Write;
+        let capture = Arc::new(Mutex::new(Vec::new()));
+        capture::write_header(&mut *capture.lock().unwrap()).unwrap();
+        let recorder: Recorder = capture.clone();
+        let mut gz = ::flate2::write::GzEncoder::new(Vec::new(), ::flate2::Compression::fast());
+        gz.write_all(b"[version]\n[/version]\n").unwrap();
+        let mut log = log_msg(Side::Server, Some(recorder));
+        log(&gz.finish().unwrap());
+        log(b"raw");
+
+        let records = capture::read_capture(&**capture.lock().unwrap()).unwrap();
+        let records = records.into_iter().map(|record| (record.side, record.msg)).collect::<Vec<_>>();
+        assert_eq!(records, [(Side::Server, b"[version]\n[/version]\n".to_vec()), (Side::Server, b"raw".to_vec())]);
+    }
+
+    #[test]
+    fn fixtures_from_server_messages() {
+        use crate::capture::Record;
+        let record = |side, msg: &[u8]| Record { side, time: ::std::time::SystemTime::UNIX_EPOCH, msg: msg.to_vec() };
+        let records = [
+            record(Side::Server, b"[version]\n[/version]\n"),
+            record(Side::Client, b"[version]\nversion=\"1.16.0\"\n[/version]\n"),
+            record(Side::Server, b"[mustlogin]\n[/mustlogin]\n"),
+            record(Side::Server, b"raw"),
+        ];
+        assert_eq!(fixtures(&records).collect::<Vec<_>>(), [
+            ("0000_version.wml".to_owned(), &b"[version]\n[/version]\n"[..]),
+            ("0002_mustlogin.wml".to_owned(), b"[mustlogin]\n[/mustlogin]\n"),
+            ("0003_message.wml".to_owned(), b"raw"),
+        ]);
+    }
+}
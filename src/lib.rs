@@ -0,0 +1,4 @@
+//! Protocol plumbing for `wesmaild`, a Wesnoth server for playing over email.
+pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
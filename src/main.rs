@@ -1,18 +1,229 @@
 //! A Wesnoth server for playing over email. Not close to ready for use.
-mod wml;
-mod stream;
-
-use ::core::cmp::Ordering;
-use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use ::core::fmt;
+use ::core::time::Duration;
+use ::std::borrow::Cow;
+use ::std::io;
+use ::tokio::io::{AsyncRead, AsyncWrite};
 use ::tokio::net::{TcpListener, TcpStream};
+use ::wesmaild::stream::{self, Compression, ReadError, Reader, Writer};
+use ::wml::{Doc, DocProcessor, NodeRef};
+#[cfg(feature = "tls")]
+use ::wesmaild::tls::{TlsAcceptor, TlsConfig};
+
+/// Server configuration, taken from the command line.
+#[derive(Debug)]
+struct Config {
+    /// Address to accept connections on, given `--listen <addr>`.
+    listen: String,
+    /// Serve WML over TLS, given `--tls-cert <path>` and `--tls-key <path>`.
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut listen = String::from("127.0.0.1:15000");
+        #[cfg(feature = "tls")]
+        let (mut cert_path, mut key_path) = (None, None);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("missing value for {}", arg));
+            match &*arg {
+                "--listen" => listen = value()?,
+                #[cfg(feature = "tls")]
+                "--tls-cert" => cert_path = Some(value()?.into()),
+                #[cfg(feature = "tls")]
+                "--tls-key" => key_path = Some(value()?.into()),
+                _ => return Err(format!("unrecognized argument {}", arg)),
+            }
+        }
+        #[cfg(feature = "tls")]
+        let tls = match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig { cert_path, key_path }),
+            (None, None) => None,
+            _ => return Err("--tls-cert and --tls-key must be given together".into()),
+        };
+        Ok(Self {
+            listen,
+            #[cfg(feature = "tls")]
+            tls,
+        })
+    }
+}
+
+/// How long a client may send nothing before it's disconnected.
+///
+/// Clients sitting in the lobby can go quiet for a long while, so this only catches ones that are gone.
+const READ_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// The slowest, in bytes per second, a client's message may arrive once it has started.
+///
+/// Even the largest messages a client sends are a few kilobytes, so only a peer
+/// holding the connection open on purpose goes this slowly.
+const MIN_FRAME_THROUGHPUT: u32 = 1024;
 
 #[::tracing::instrument]
 async fn handle_connection(s: TcpStream) -> Result<(), ()> {
-    let (mut reader, mut writer) = stream::server_handshake(s).await?;
+    let (reader, writer) = stream::server_handshake(s).await?;
+    serve(reader, writer).await
+}
+
+#[cfg(feature = "tls")]
+#[::tracing::instrument(skip(acceptor))]
+async fn handle_tls_connection(s: TcpStream, acceptor: TlsAcceptor) -> Result<(), ()> {
+    let s = acceptor.accept(s).await.map_err(|e| ::tracing::debug!("TLS handshake failure: {:?}", e))?;
+    let (reader, writer) = stream::server_handshake_split(s).await?;
+    serve(reader, writer).await
+}
+
+async fn serve<R, W>(reader: Reader<R>, mut writer: Writer<W>) -> Result<(), ()>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let mut reader = reader.with_read_timeout(READ_TIMEOUT).with_min_frame_throughput(MIN_FRAME_THROUGHPUT);
+    // The version query always goes out gzipped, since we don't know yet
+    // whether the client takes bzip2. If it answers in bzip2, it does.
     writer.write(b"[version]\n[/version]\n").await.map_err(|e| ::tracing::debug!("write failure: {:?}", e))?;
-    writer.write(b"[error]message=\"ur banned d00d\"\n[/error]").await.map_err(|e| ::tracing::debug!("write failure: {:?}", e))?;
+    let mut processor = DocProcessor::new();
+    // Documents can't be held across awaits, since they can't be sent between threads.
+    let version = {
+        let doc = read_wml(&mut reader, &mut processor).await.map_err(|e| ::tracing::debug!("{}", e))?;
+        client_version(&doc).map(Cow::into_owned)
+    };
+    if let Some(compression @ Compression::Bzip2) = reader.last_compression() {
+        ::tracing::debug!("switching to {:?}", compression);
+        writer.set_compression(compression);
+    }
+    match version.as_deref() {
+        Some(version) if version_accepted(version) => ::tracing::debug!("client version {}", version),
+        version => {
+            ::tracing::debug!("unacceptable client version {:?}", version);
+            let message = format!(
+                "The server accepts versions {}, while you are using version {}.",
+                ACCEPTED_VERSIONS.join(", "),
+                version.unwrap_or("unknown"),
+            );
+            send_error(&mut writer, &message).await.map_err(|e| ::tracing::debug!("write failure: {:?}", e))?;
+            return Err(())
+        },
+    }
+    let username = request_login(&mut reader, &mut writer).await.map_err(|e| ::tracing::debug!("{}", e))?;
+    ::tracing::debug!("{} logged in", username);
     loop {
-        ::tokio::task::yield_now().await
+        let msg = match reader.read().await {
+            Ok(msg) => msg,
+            Err(ReadError::Eof) => {
+                ::tracing::debug!("{} disconnected", username);
+                return Ok(())
+            },
+            Err(e @ (ReadError::Decompress(_) | ReadError::DecompressedTooLarge { .. } | ReadError::FrameTooLarge { .. })) => {
+                ::tracing::warn!("corrupt message from {}: {}", username, e);
+                return Err(())
+            },
+            Err(e) => {
+                ::tracing::debug!("{}", e);
+                return Err(())
+            },
+        };
+        ::tracing::debug!("received message: {:?}", String::from_utf8_lossy(&msg));
+    }
+}
+
+/// Versions of the client which may connect, and any of their patch releases.
+const ACCEPTED_VERSIONS: &[&str] = &["1.16", "1.18"];
+
+/// Whether a client reporting `version`, like `1.16.8`, may connect.
+fn version_accepted(version: &str) -> bool {
+    ACCEPTED_VERSIONS.iter().any(|accepted| {
+        version.strip_prefix(accepted).is_some_and(|patch| patch.is_empty() || patch.starts_with('.'))
+    })
+}
+
+/// The version a client reported in answer to the version query.
+fn client_version<'d>(doc: &'d Doc<'_>) -> Option<Cow<'d, str>> {
+    top_level_attr(doc, "version", "version")
+}
+
+/// The value of `key` in the last top level `[tag]` of `doc`, if it's valid UTF-8.
+///
+/// Later duplicates of either override earlier ones, as with [`TagRef::get`](::wml::TagRef::get).
+fn top_level_attr<'d>(doc: &'d Doc<'_>, tag: &str, key: &str) -> Option<Cow<'d, str>> {
+    let tag = doc.children().filter_map(|node| match node {
+        NodeRef::Tag(t) if t.name_str() == Ok(tag) => Some(t),
+        _ => None,
+    }).last()?;
+    match tag.get(key)? {
+        NodeRef::Attr(attr) => attr.value_str().ok(),
+        NodeRef::Tag(_) => None,
+    }
+}
+
+/// Why a client's session ended early.
+#[derive(Debug)]
+enum SessionError {
+    Read(ReadError),
+    Write(io::Error),
+    /// The client didn't log in with an acceptable username, and has been sent an `[error]` saying so.
+    BadLogin,
+}
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(e) => e.fmt(f),
+            Self::Write(e) => write!(f, "write failure: {}", e),
+            Self::BadLogin => f.write_str("client failed to log in"),
+        }
+    }
+}
+impl From<ReadError> for SessionError {
+    fn from(e: ReadError) -> Self { Self::Read(e) }
+}
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self { Self::Write(e) }
+}
+
+/// Read the client's next message and parse it with `processor`.
+async fn read_wml<'p, R: AsyncRead + Unpin>(reader: &'p mut Reader<R>, processor: &'p mut DocProcessor) -> Result<Doc<'p>, SessionError> {
+    Ok(reader.read_doc(processor).await?)
+}
+
+/// Send the client an `[error]` it will show to its user.
+///
+/// It's framed and compressed like any other message, with whatever compression `writer` is set to.
+async fn send_error<W: AsyncWrite + Unpin>(writer: &mut Writer<W>, message: &str) -> io::Result<()> {
+    writer.write(&::wml::wml! { [error] message = {message} [/error] }).await
+}
+
+/// Ask the client to log in, with `[mustlogin]`, and return the username it answers with.
+async fn request_login<R, W>(reader: &mut Reader<R>, writer: &mut Writer<W>) -> Result<String, SessionError>
+where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    writer.write(b"[mustlogin]\n[/mustlogin]\n").await?;
+    let mut processor = DocProcessor::new();
+    let username = {
+        let doc = read_wml(reader, &mut processor).await?;
+        top_level_attr(&doc, "login", "username").map(Cow::into_owned)
+    };
+    let problem = match username.as_deref() {
+        None => "Expected a [login] with a username.",
+        Some(username) => match check_username(username) {
+            Ok(()) => return Ok(username.into()),
+            Err(problem) => problem,
+        },
+    };
+    ::tracing::debug!("rejecting username {:?}: {}", username, problem);
+    send_error(writer, problem).await?;
+    Err(SessionError::BadLogin)
+}
+
+/// The longest username `wesnothd` allows.
+const MAX_USERNAME_LEN: usize = 20;
+
+/// Check `username` against the rules `wesnothd` has for them,
+/// returning a message for the client if it breaks one.
+fn check_username(username: &str) -> Result<(), &'static str> {
+    if username.is_empty() {
+        Err("The nickname must not be empty.")
+    } else if username.len() > MAX_USERNAME_LEN {
+        Err("The nickname is too long. Nicks must be 20 characters or less.")
+    } else if !username.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-') {
+        Err("The nickname contains invalid characters. Only alpha-numeric characters, underscores and hyphens are allowed.")
+    } else {
+        Ok(())
     }
 }
 
@@ -20,10 +231,27 @@ async fn handle_connection(s: TcpStream) -> Result<(), ()> {
 async fn main() {
     // install global collector configured based on RUST_LOG env var.
     tracing_subscriber::fmt::init();
-    let listener = TcpListener::bind("127.0.0.1:15000").await.unwrap();
+    let config = Config::from_args(::std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        ::std::process::exit(2)
+    });
+    #[cfg(feature = "tls")]
+    let acceptor = config.tls.as_ref().map(TlsConfig::acceptor).transpose().unwrap_or_else(|e| {
+        eprintln!("failed to load TLS configuration: {}", e);
+        ::std::process::exit(1)
+    });
+    let listener = TcpListener::bind(&config.listen).await.unwrap_or_else(|e| {
+        eprintln!("failed to listen on {}: {}", config.listen, e);
+        ::std::process::exit(1)
+    });
     loop {
         match listener.accept().await {
             Ok((socket, _)) => {
+                #[cfg(feature = "tls")]
+                if let Some(acceptor) = &acceptor {
+                    ::tokio::spawn(handle_tls_connection(socket, acceptor.clone()));
+                    continue
+                }
                 ::tokio::spawn(handle_connection(socket));
             },
             Err(e) => {
@@ -32,3 +260,177 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_username, read_wml, request_login, send_error, serve, top_level_attr, version_accepted, SessionError, READ_TIMEOUT};
+    use ::std::io::{Read, Write};
+    use ::tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use ::tokio::net::{TcpListener, TcpStream};
+    use ::wesmaild::stream::{self, Compression, ReadError, Reader, Writer};
+    use ::wml::DocProcessor;
+
+    /// The `[version]` a Wesnoth 1.16 client answers the version query with, byte for byte
+    /// as its `config` writer lays it out: keys sorted, and indented with tabs.
+    ///
+    /// This is written out from how the client builds and writes the message,
+    /// not recorded from one, so swap in a `mitm --record` capture of a real client if this drifts.
+    const CLIENT_VERSION: &[u8] = b"[version]\n\tclient_source=\"Default\"\n\tversion=\"1.16.11\"\n[/version]\n";
+
+    /// Read one frame, without decompressing it.
+    async fn raw_frame(client: &mut DuplexStream) -> Vec<u8> {
+        let mut len = [0; 4];
+        client.read_exact(&mut len).await.unwrap();
+        let mut body = vec![0; u32::from_be_bytes(len) as usize];
+        client.read_exact(&mut body).await.unwrap();
+        body
+    }
+
+    #[::tokio::test]
+    async fn negotiates_bzip2() {
+        let (mut client, server) = ::tokio::io::duplex(1024);
+        let (reader, writer) = ::tokio::io::split(server);
+        let server = ::tokio::spawn(serve(Reader::new(reader), Writer::new(writer)));
+
+        let query = raw_frame(&mut client).await;
+        let mut version = Vec::new();
+        ::flate2::read::GzDecoder::new(&*query).read_to_end(&mut version).unwrap();
+        assert_eq!(version, b"[version]\n[/version]\n");
+
+        let mut bz = ::bzip2::write::BzEncoder::new(Vec::new(), ::bzip2::Compression::fast());
+        bz.write_all(CLIENT_VERSION).unwrap();
+        let body = bz.finish().unwrap();
+        client.write_all(&u32::to_be_bytes(body.len() as u32)).await.unwrap();
+        client.write_all(&body).await.unwrap();
+
+        let reply = raw_frame(&mut client).await;
+        let mut mustlogin = Vec::new();
+        ::bzip2::read::BzDecoder::new(&*reply).read_to_end(&mut mustlogin).unwrap();
+        assert_eq!(mustlogin, b"[mustlogin]\n[/mustlogin]\n");
+
+        drop(client);
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[::tokio::test]
+    async fn rejects_old_version() {
+        let (client, server) = ::tokio::io::duplex(1024);
+        let (reader, writer) = ::tokio::io::split(server);
+        let server = ::tokio::spawn(serve(Reader::new(reader), Writer::new(writer)));
+        let (reader, writer) = ::tokio::io::split(client);
+        let (mut reader, mut writer) = (Reader::new(reader), Writer::new(writer));
+
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\n[/version]\n");
+        writer.write(b"[version]\nversion=\"1.14.17\"\nclient_source=\"Default\"\n[/version]\n").await.unwrap();
+        let error = reader.read().await.unwrap();
+        assert!(error.starts_with(b"[error]\n"));
+        assert!(String::from_utf8_lossy(&error).contains("you are using version 1.14.17"));
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[::tokio::test(start_paused = true)]
+    async fn disconnects_idle_client() {
+        let (mut client, server) = ::tokio::io::duplex(1024);
+        let (reader, writer) = ::tokio::io::split(server);
+        let start = ::tokio::time::Instant::now();
+        let server = ::tokio::spawn(serve(Reader::new(reader), Writer::new(writer)));
+        raw_frame(&mut client).await;
+        assert!(server.await.unwrap().is_err());
+        assert_eq!(start.elapsed(), READ_TIMEOUT);
+    }
+
+    #[test]
+    fn accepted_versions() {
+        assert!(version_accepted("1.16"));
+        assert!(version_accepted("1.16.8"));
+        assert!(version_accepted("1.18.0"));
+        assert!(!version_accepted("1.160"));
+        assert!(!version_accepted("1.14.17"));
+        assert!(!version_accepted(""));
+    }
+
+    #[::tokio::test]
+    async fn login_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let server = ::tokio::spawn(async move {
+            let (mut reader, mut writer) = stream::server_handshake(server).await.unwrap();
+            request_login(&mut reader, &mut writer).await.unwrap()
+        });
+        let (mut reader, mut writer) = stream::client_handshake(client).await.unwrap();
+        assert_eq!(&*reader.read().await.unwrap(), b"[mustlogin]\n[/mustlogin]\n");
+        writer.write(b"[login]\nusername=\"lol\"\nselective_ping=\"1\"\n[/login]\n").await.unwrap();
+        assert_eq!(server.await.unwrap(), "lol");
+    }
+
+    #[::tokio::test]
+    async fn rejects_bad_logins() {
+        for login in [&b"[login]\nusername=\"\"\n[/login]\n"[..], b"[login]\n[/login]\n", b"[version]\n[/version]\n"] {
+            let (client, server) = ::tokio::io::duplex(1024);
+            let (reader, writer) = ::tokio::io::split(server);
+            let (mut server_reader, mut server_writer) = (Reader::new(reader), Writer::new(writer));
+            let (reader, writer) = ::tokio::io::split(client);
+            let (mut reader, mut writer) = (Reader::new(reader), Writer::new(writer));
+            writer.write(login).await.unwrap();
+            let result = request_login(&mut server_reader, &mut server_writer).await;
+            assert!(matches!(result, Err(SessionError::BadLogin)));
+            assert_eq!(&*reader.read().await.unwrap(), b"[mustlogin]\n[/mustlogin]\n");
+            assert!(reader.read().await.unwrap().starts_with(b"[error]\n"));
+        }
+    }
+
+    #[::tokio::test]
+    async fn read_wml_login() {
+        let (client, server) = ::tokio::io::duplex(64);
+        let client = ::tokio::spawn(async move {
+            let mut writer = Writer::new(client);
+            writer.write(b"[login]\nusername=\"lol\"\n[/login]\n").await.unwrap();
+            writer.write(b"[login]\n").await.unwrap();
+        });
+        let mut reader = Reader::new(server);
+        let mut processor = DocProcessor::new();
+        {
+            let doc = read_wml(&mut reader, &mut processor).await.unwrap();
+            assert_eq!(top_level_attr(&doc, "login", "username").as_deref(), Some("lol"));
+        }
+        assert!(matches!(read_wml(&mut reader, &mut processor).await, Err(SessionError::Read(ReadError::Parse(_)))));
+        client.await.unwrap();
+        assert!(matches!(read_wml(&mut reader, &mut processor).await, Err(SessionError::Read(ReadError::Eof))));
+    }
+
+    #[::tokio::test]
+    async fn error_framing() {
+        for compression in [Compression::Gzip, Compression::Bzip2] {
+            let (client, server) = ::tokio::io::duplex(1024);
+            let mut writer = Writer::new(server);
+            writer.set_compression(compression);
+            send_error(&mut writer, "Nope.").await.unwrap();
+            let mut reader = Reader::new(client);
+            assert_eq!(&*reader.read().await.unwrap(), b"[error]\nmessage=\"Nope.\"\n[/error]\n");
+            assert_eq!(reader.last_compression(), Some(compression));
+        }
+    }
+
+    #[test]
+    fn usernames() {
+        assert_eq!(check_username("lol"), Ok(()));
+        assert_eq!(check_username("a_b-C9"), Ok(()));
+        assert!(check_username("").is_err());
+        assert!(check_username("with space").is_err());
+        assert!(check_username("\u{e9}t\u{e9}").is_err());
+        assert!(check_username(&"x".repeat(21)).is_err());
+        assert_eq!(check_username(&"x".repeat(20)), Ok(()));
+    }
+
+    #[test]
+    fn last_top_level_attr() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "[login]\nusername=\"first\"\n[/login]\n",
+            "[login]\nusername=\"a\"\nusername=\"b\" + \"c\"\n[/login]\n",
+        ))).unwrap();
+        assert_eq!(top_level_attr(&doc, "login", "username").as_deref(), Some("bc"));
+        assert!(top_level_attr(&doc, "login", "password").is_none());
+    }
+}
@@ -1,75 +1,450 @@
 //! A protocol wrapper for Wesnoth message streams on top of TCP.
 use ::core::convert::TryInto;
-use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use ::core::fmt;
+use ::core::future::Future;
+use ::core::pin::Pin;
+use ::core::task::{ready, Context, Poll};
+use ::core::time::Duration;
+use ::std::collections::VecDeque;
+use ::futures_core::Stream;
+use ::std::io;
+use ::tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
 use ::tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use ::tokio::net::TcpStream;
+use ::tokio::time::{Instant, Sleep};
+use ::wml::{Doc, DocProcessor};
 
-// TODO: have option for doing bzip2 based compression instead of gzip compression
-
-// TODO: decide how to do allocation limits for the Reader buffer,
-// as Wesnoth clients are, in the general case, run by untrusted users
-// (although `wesmaild` is, at least initially, meant to be run locally with the client)
-pub struct Reader {
-    half: OwnedReadHalf,
-    // TODO: strongly consider using VecDeque for this,
-    // since we constantly drain from the front
-    buf: Vec<u8>,
-}
-impl Reader {
-    fn from_raw(half: OwnedReadHalf, buf: Vec<u8>) -> Self { Self { half, buf } }
-    // TODO: implement either the AsyncRead or Stream trait,
-    // or even do WML parsing without copying out the buffer,
-    // in which case definitely implement the Stream trait
-    pub async fn read(&mut self) -> Result<Box<[u8]>, ()> {
+/// How the messages in a stream are compressed.
+///
+/// `wesnothd` speaks gzip unless a client shows it can take bzip2,
+/// by sending bzip2 compressed messages itself.
+/// [`Reader`] accepts either, and [`Reader::last_compression`] reports which it saw.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    Gzip,
+    Bzip2,
+}
+impl Compression {
+    /// Recognize a compressed message by its magic bytes.
+    fn detect(msg: &[u8]) -> Option<Self> {
+        match msg {
+            [0x1f, 0x8b, ..] => Some(Self::Gzip),
+            [b'B', b'Z', b'h', ..] => Some(Self::Bzip2),
+            _ => None,
+        }
+    }
+}
+
+/// Why [`Reader::read`] failed to produce a message.
+#[derive(Debug)]
+pub enum ReadError {
+    /// The underlying stream ended between two messages, as it does when the peer disconnects.
+    Eof,
+    /// The underlying stream failed, or ended partway through a message.
+    Io(io::Error),
+    /// A message couldn't be decompressed.
+    Decompress(io::Error),
+    /// A message's bytes arrived slower than [`Reader::with_min_frame_throughput`] allows.
+    SlowFrame,
+    /// Nothing arrived for as long as [`Reader::with_read_timeout`] allows.
+    Timeout,
+    /// A message's length prefix was `len`, over the [`Reader::with_max_message_len`] limit.
+    ///
+    /// The message isn't read, so the stream can't continue past it,
+    /// and every later read fails the same way.
+    FrameTooLarge { len: usize, limit: usize },
+    /// A message decompressed to more than `limit` bytes, and was dropped.
+    DecompressedTooLarge { limit: usize },
+    /// A message read with [`Reader::read_doc`] wasn't valid WML.
+    Parse(::wml::ParseError),
+}
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Eof => f.write_str("connection closed"),
+            Self::Io(e) => write!(f, "read failure: {}", e),
+            Self::Decompress(e) => write!(f, "decompression failed: {}", e),
+            Self::SlowFrame => f.write_str("message arrived too slowly"),
+            Self::Timeout => f.write_str("timed out waiting for a message"),
+            Self::FrameTooLarge { len, limit } => write!(f, "message of {} bytes is over the limit of {}", len, limit),
+            Self::DecompressedTooLarge { limit } => {
+                write!(f, "message decompressed to over the limit of {} bytes", limit)
+            },
+            Self::Parse(e) => write!(f, "invalid message: {}", e),
+        }
+    }
+}
+impl ::std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) | Self::Decompress(e) => Some(e),
+            Self::Parse(e) => Some(e),
+            Self::Eof | Self::SlowFrame | Self::Timeout | Self::FrameTooLarge { .. } | Self::DecompressedTooLarge { .. } => None,
+        }
+    }
+}
+
+/// How long a message may take to arrive before [`Reader::with_min_frame_throughput`] applies.
+///
+/// This keeps a slow start, like the first packets of a connection, from counting against it.
+const SLOW_FRAME_GRACE: Duration = Duration::from_secs(5);
+
+/// How many bytes [`Reader`] reads from its stream at a time, at most.
+const READ_CHUNK: usize = 8 << 10;
+/// The default for [`Reader::with_max_message_len`], 16 MiB.
+const DEFAULT_MAX_MESSAGE_LEN: usize = 16 << 20;
+/// The default for [`Reader::with_max_decompressed_len`], 64 MiB.
+const DEFAULT_MAX_DECOMPRESSED_LEN: usize = 64 << 20;
+
+// Wesnoth clients are, in the general case, run by untrusted users,
+// so nothing they send may make us allocate without bound.
+pub struct Reader<R = OwnedReadHalf> {
+    half: R,
+    /// Bytes read but not yet returned as part of a message.
+    /// Messages are taken off the front, so this doesn't shuffle what's left over.
+    buf: VecDeque<u8>,
+    /// The last message read, decompressed. Cleared and reused for each message,
+    /// so it only grows, up to [`with_max_decompressed_len`](Self::with_max_decompressed_len).
+    msg: Vec<u8>,
+    /// Minimum bytes per second a partially received message must arrive at.
+    min_frame_throughput: Option<u32>,
+    /// When we started receiving the message at the front of `buf`.
+    frame_start: Option<Instant>,
+    /// Whether to log a [`checksum`] of every message.
+    log_checksums: bool,
+    /// How the last message read was compressed.
+    last_compression: Option<Compression>,
+    /// The longest length prefix accepted.
+    max_message_len: usize,
+    /// The most bytes a message may decompress to.
+    max_decompressed_len: usize,
+    /// How long to wait for bytes to arrive.
+    read_timeout: Option<Duration>,
+    /// When the wait for bytes that's underway times out.
+    idle: Option<Pin<Box<Sleep>>>,
+    /// Whether the [`Stream`] of messages has ended with an error it can't go on past.
+    terminated: bool,
+}
+impl<R: AsyncRead + Unpin> Reader<R> {
+    /// Wrap a byte stream which has already gone through the handshake,
+    /// such as an in-memory pipe or an encrypted stream.
+    pub fn new(half: R) -> Self { Self::from_raw(half, Vec::new()) }
+    fn from_raw(half: R, buf: Vec<u8>) -> Self {
+        Self {
+            half,
+            buf: buf.into(),
+            msg: Vec::new(),
+            min_frame_throughput: None,
+            frame_start: None,
+            log_checksums: false,
+            last_compression: None,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            max_decompressed_len: DEFAULT_MAX_DECOMPRESSED_LEN,
+            read_timeout: None,
+            idle: None,
+            terminated: false,
+        }
+    }
+    /// How the last message read was compressed, if any have been read.
+    ///
+    /// A server should answer bzip2 with bzip2, via [`Writer::set_compression`].
+    pub fn last_compression(&self) -> Option<Compression> {
+        self.last_compression
+    }
+    /// Fail with [`ReadError::FrameTooLarge`] on a message whose length prefix is over `limit`,
+    /// before buffering any of it. The default is 16 MiB.
+    pub fn with_max_message_len(mut self, limit: usize) -> Self {
+        self.max_message_len = limit;
+        self
+    }
+    /// Fail with [`ReadError::DecompressedTooLarge`] on a message which decompresses
+    /// to more than `limit` bytes, without decompressing any more of it than that.
+    /// The default is 64 MiB.
+    ///
+    /// Compression can shrink a message enormously, so this limits how much memory
+    /// a message may take separately from [`with_max_message_len`](Self::with_max_message_len).
+    pub fn with_max_decompressed_len(mut self, limit: usize) -> Self {
+        self.max_decompressed_len = limit;
+        self
+    }
+    /// Fail with [`ReadError::Timeout`] when nothing arrives for `timeout`
+    /// while waiting for a message, whether or not it's started arriving.
+    ///
+    /// This frees up peers that stop sending partway through a message,
+    /// or never send one at all.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+    /// Log the [`checksum`] of every message read, at the `debug` level.
+    ///
+    /// This lets the same message be recognized wherever else it's logged,
+    /// like on the other side of a proxy.
+    pub fn with_checksums(mut self) -> Self {
+        self.log_checksums = true;
+        self
+    }
+    /// Recover the underlying stream, along with any bytes which were read from it
+    /// but not yet returned as part of a message.
+    ///
+    /// For a `TcpStream`, the halves can then be put back together with
+    /// [`OwnedReadHalf::reunite`].
+    pub fn into_inner(self) -> (R, Vec<u8>) {
+        (self.half, self.buf.into())
+    }
+    /// Fail with [`ReadError::SlowFrame`] when a message that has started arriving
+    /// comes in at less than `bytes_per_sec`, on average.
+    ///
+    /// This catches peers that trickle in a message to hold the connection open,
+    /// which a read timeout wouldn't, since the connection is never idle.
+    /// Every message gets a grace period of five seconds before this applies.
+    pub fn with_min_frame_throughput(mut self, bytes_per_sec: u32) -> Self {
+        self.min_frame_throughput = Some(bytes_per_sec);
+        self
+    }
+    /// Check that the message at the front of `buf` is arriving quickly enough.
+    fn check_frame_throughput(&mut self) -> Result<(), ReadError> {
+        let min = match self.min_frame_throughput {
+            Some(min) => min,
+            None => return Ok(()),
+        };
+        let elapsed = self.frame_start.get_or_insert_with(Instant::now).elapsed();
+        if elapsed >= SLOW_FRAME_GRACE && (self.buf.len() as f64) < elapsed.as_secs_f64() * f64::from(min) {
+            ::tracing::debug!("{} bytes of message received over {:?}", self.buf.len(), elapsed);
+            return Err(ReadError::SlowFrame)
+        }
+        Ok(())
+    }
+    /// Read the next message, waiting for it to arrive, and decompress it.
+    ///
+    /// `Reader` is also a [`Stream`] of messages, for use with stream combinators.
+    pub async fn read(&mut self) -> Result<Box<[u8]>, ReadError> {
+        ::std::future::poll_fn(|cx| self.poll_read(cx)).await?;
+        Ok(self.msg.as_slice().into())
+    }
+    /// Read the next message and parse it with `processor`.
+    ///
+    /// The document borrows the buffer the reader decompresses every message into,
+    /// instead of copying the message out like [`read`](Self::read) does,
+    /// so once that buffer has grown to fit the messages coming in, reading them allocates nothing.
+    /// The next message can't be read until the document is dropped.
+    ///
+    /// `processor` is only borrowed mutably so this can be awaited in a spawned task,
+    /// since a shared `DocProcessor` can't be sent between threads.
+    pub async fn read_doc<'p>(&'p mut self, processor: &'p mut DocProcessor) -> Result<Doc<'p>, ReadError> {
+        ::std::future::poll_fn(|cx| self.poll_read(cx)).await?;
+        let processor: &'p DocProcessor = processor;
+        processor.parse_borrowed(&self.msg).map_err(|e| {
+            ::tracing::debug!("invalid message: {}", e);
+            ReadError::Parse(e)
+        })
+    }
+    /// Decompress the next message into `msg`, reading more of the stream for it as needed.
+    fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ReadError>> {
         loop {
-            if let [a, b, c, d, ref rest @ ..] = *self.buf {
-                let len = u32::from_be_bytes([a, b, c, d]) as usize;
-                if rest.len() >= len {
-                    // decompress message and return
-                    use ::flate2::read::MultiGzDecoder;
-                    use ::std::io::Read;
-                    let mut gz = MultiGzDecoder::new(&rest[..len]);
-                    let mut gz_buf = Vec::new();
-                    match gz.read_to_end(&mut gz_buf) {
-                        Ok(_n) => (),
-                        Err(e) => {
-                            ::tracing::debug!("decompression failed: {:?}", e);
-                            return Err(())
-                        },
-                    }
-                    return Ok(gz_buf.into_boxed_slice())
-                }
+            if let Some(res) = self.take_message() {
+                return Poll::Ready(res)
             }
-            match self.half.read_buf(&mut self.buf).await {
-                Ok(n) if n != 0 => (),
-                Ok(_) => {
+            let mut chunk = [0; READ_CHUNK];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            let res = match Pin::new(&mut self.half).poll_read(cx, &mut chunk) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return self.poll_idle(cx),
+            };
+            self.idle = None;
+            match res {
+                Ok(()) if !chunk.filled().is_empty() => {
+                    self.buf.extend(chunk.filled());
+                    self.check_frame_throughput()?
+                },
+                Ok(()) => {
                     // since we currently assume that `self.buf` will
                     // never run out of space, this means that
                     // the stream is no longer able to produce bytes
-                    return Err(())
+                    return Poll::Ready(Err(match self.buf.is_empty() {
+                        true => ReadError::Eof,
+                        false => ReadError::Io(io::ErrorKind::UnexpectedEof.into()),
+                    }))
                 },
                 Err(e) => {
                     ::tracing::debug!("read failure: {:?}", e);
-                    return Err(())
+                    return Poll::Ready(Err(ReadError::Io(e)))
                 }
             }
         }
     }
+    /// Check whether the stream has been waited on for too long, once it has nothing to read.
+    fn poll_idle<T>(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, ReadError>> {
+        let timeout = match self.read_timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+        let idle = self.idle.get_or_insert_with(|| Box::pin(::tokio::time::sleep(timeout)));
+        ready!(idle.as_mut().poll(cx));
+        self.idle = None;
+        ::tracing::debug!("nothing received for {:?}", timeout);
+        Poll::Ready(Err(ReadError::Timeout))
+    }
+    /// Take the message at the front of `buf` and decompress it into `msg`, if it's all arrived.
+    fn take_message(&mut self) -> Option<Result<(), ReadError>> {
+        use ::bzip2::read::MultiBzDecoder;
+        use ::flate2::read::MultiGzDecoder;
+        use ::std::io::Read;
+        let len = u32::from_be_bytes(prefix(&self.buf)?) as usize;
+        if len > self.max_message_len {
+            ::tracing::debug!("message of {} bytes is too large", len);
+            return Some(Err(ReadError::FrameTooLarge { len, limit: self.max_message_len }))
+        }
+        if self.buf.len() - 4 < len {
+            return None
+        }
+        self.buf.drain(..4);
+        // the message may wrap around the end of the buffer
+        let (front, back) = self.buf.as_slices();
+        let msg = || Read::take(Read::chain(front, back), len as u64);
+        let mut magic = [0; 3];
+        let magic_len = msg().read(&mut magic).unwrap_or(0);
+        // anything unrecognized is left for the gzip decoder to reject
+        let compression = Compression::detect(&magic[..magic_len]).unwrap_or_default();
+        self.msg.clear();
+        let limit = self.max_decompressed_len;
+        let res = match compression {
+            Compression::Gzip => decompress(MultiGzDecoder::new(msg()), limit, &mut self.msg),
+            Compression::Bzip2 => decompress(MultiBzDecoder::new(msg()), limit, &mut self.msg),
+        };
+        // remove the now handled message from the buffer
+        self.buf.drain(..len);
+        // the next message, if any, has already started arriving
+        self.frame_start = if self.buf.is_empty() { None } else { Some(Instant::now()) };
+        if let Err(e) = res {
+            return Some(Err(e))
+        }
+        self.last_compression = Some(compression);
+        if self.log_checksums {
+            ::tracing::debug!(len = self.msg.len(), crc32 = checksum(&self.msg), "received message");
+        }
+        Some(Ok(()))
+    }
+}
+
+/// Messages, as [`Reader::read`] returns them, until the stream ends between two messages.
+///
+/// After [`ReadError::Decompress`] and [`ReadError::DecompressedTooLarge`], the next message
+/// may still be read, and after [`ReadError::Timeout`], the wait for it goes on.
+/// Anything else, like a message cut off by the end of the stream, is the last item.
+impl<R: AsyncRead + Unpin> Stream for Reader<R> {
+    type Item = Result<Box<[u8]>, ReadError>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.terminated {
+            return Poll::Ready(None)
+        }
+        match ready!(self.poll_read(cx)) {
+            Err(ReadError::Eof) => Poll::Ready(None),
+            Ok(()) => Poll::Ready(Some(Ok(self.msg.as_slice().into()))),
+            Err(e) => {
+                self.terminated = !matches!(e, ReadError::Decompress(_) | ReadError::DecompressedTooLarge { .. } | ReadError::Timeout);
+                Poll::Ready(Some(Err(e)))
+            },
+        }
+    }
+}
+
+/// The length prefix of the message at the front of `buf`, if it's all arrived.
+fn prefix(buf: &VecDeque<u8>) -> Option<[u8; 4]> {
+    let mut bytes = buf.iter().copied();
+    Some([bytes.next()?, bytes.next()?, bytes.next()?, bytes.next()?])
+}
+
+/// Decompress everything `decoder` produces into `out`, unless it's more than `limit` bytes.
+fn decompress<D: io::Read>(decoder: D, limit: usize, out: &mut Vec<u8>) -> Result<(), ReadError> {
+    use ::std::io::Read;
+    let mut bounded = Bounded { inner: decoder, remaining: limit, exceeded: false };
+    match bounded.read_to_end(out) {
+        Ok(_n) => Ok(()),
+        Err(_) if bounded.exceeded => {
+            ::tracing::debug!("message decompressed to over {} bytes", limit);
+            Err(ReadError::DecompressedTooLarge { limit })
+        },
+        Err(e) => {
+            ::tracing::debug!("decompression failed: {:?}", e);
+            Err(ReadError::Decompress(e))
+        },
+    }
+}
+
+/// A reader which fails once `inner` has produced more than a fixed number of bytes,
+/// never asking it for more than one past that.
+struct Bounded<R> {
+    inner: R,
+    /// How many more bytes may be read.
+    remaining: usize,
+    /// Whether the failure was for having more than that.
+    exceeded: bool,
+}
+impl<R: io::Read> io::Read for Bounded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            // Only the end of the input is allowed now.
+            return match self.inner.read(&mut [0])? {
+                0 => Ok(0),
+                _ => {
+                    self.exceeded = true;
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed message too large"))
+                },
+            }
+        }
+        let len = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+/// A stable hash of a decompressed message, for correlating the same message
+/// across different logs. This is the CRC-32 used by gzip.
+pub fn checksum(msg: &[u8]) -> u32 {
+    ::crc32fast::hash(msg)
 }
 
-pub struct Writer {
-    half: OwnedWriteHalf,
+/// The sending side of a message stream, the counterpart to [`Reader`].
+pub struct Writer<W = OwnedWriteHalf> {
+    half: W,
+    compression: Compression,
 }
-impl Writer {
-    fn from_raw(half: OwnedWriteHalf) -> Self { Self { half } }
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    /// Wrap a byte sink which has already gone through the handshake,
+    /// such as an in-memory pipe or an encrypted stream.
+    pub fn new(half: W) -> Self { Self::from_raw(half) }
+    fn from_raw(half: W) -> Self { Self { half, compression: Compression::Gzip } }
+    /// Compress messages written from now on with `compression`, instead of gzip.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+    /// Recover the underlying stream.
+    pub fn into_inner(self) -> W {
+        self.half
+    }
+    /// Send one message: `msg` compressed, after its compressed length as a big endian `u32`.
+    ///
+    /// Compression is quick rather than thorough, since messages are mostly small
+    /// and sent as they're made.
     pub async fn write(&mut self, msg: &[u8]) -> ::std::io::Result<()> {
+        use ::bzip2::write::BzEncoder;
         use ::flate2::write::GzEncoder;
-        use ::flate2::Compression;
         use ::std::io::Write;
         let mut gz_buf = vec![0; 4];
-        {
-            let mut gz = GzEncoder::new(&mut gz_buf, Compression::fast());
-            gz.write_all(msg)?;
+        match self.compression {
+            Compression::Gzip => {
+                let mut gz = GzEncoder::new(&mut gz_buf, ::flate2::Compression::fast());
+                gz.write_all(msg)?;
+                gz.finish()?;
+            },
+            Compression::Bzip2 => {
+                let mut bz = BzEncoder::new(&mut gz_buf, ::bzip2::Compression::fast());
+                bz.write_all(msg)?;
+                bz.finish()?;
+            },
         }
         let len = gz_buf.len() - 4;
         gz_buf[..4].copy_from_slice(&u32::to_be_bytes(len.try_into().unwrap()));
@@ -91,13 +466,65 @@ const SERVER_HANDSHAKE_RESPONSE: [u8; 4] = u32::to_be_bytes(42);
 // at this level, between the Wesnoth client and server.
 /// Perform the necessary handshake, as the server, to go from raw TCP to
 /// distinct, compressed, blobs of WML.
-pub async fn server_handshake(mut stream: TcpStream) -> Result<(Reader, Writer), ()> {
+pub async fn server_handshake(stream: TcpStream) -> Result<(Reader, Writer), ()> {
+    server_handshake_opts(stream, &HandshakeOptions::new()).await
+}
+
+/// Options for [`server_handshake_opts`].
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeOptions {
+    tolerate_incorrect: bool,
+}
+impl HandshakeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Take any four bytes as the client handshake, logging ones that aren't `[0, 0, 0, 0]`,
+    /// instead of failing on them.
+    ///
+    /// This is for interoperating with, or inspecting, clients that don't quite follow the protocol.
+    pub fn with_tolerate_incorrect(mut self) -> Self {
+        self.tolerate_incorrect = true;
+        self
+    }
+}
+
+/// Like [`server_handshake`], but configured by `opts`.
+pub async fn server_handshake_opts(mut stream: TcpStream, opts: &HandshakeOptions) -> Result<(Reader, Writer), ()> {
+    let buf = accept_handshake(&mut stream, opts).await?;
+    // now return the Reader and Writer, which are ready to do
+    // compressed WML messages
+    let (reader, writer) = stream.into_split();
+    Ok((Reader::from_raw(reader, buf), Writer::from_raw(writer)))
+}
+
+/// Like [`server_handshake`], but over any bidirectional byte stream,
+/// such as a TLS session on top of TCP.
+///
+/// The stream is divided with [`tokio::io::split`], so prefer [`server_handshake`]
+/// for plain `TcpStream`s.
+pub async fn server_handshake_split<S>(mut stream: S) -> Result<(Reader<ReadHalf<S>>, Writer<WriteHalf<S>>), ()>
+where S: AsyncRead + AsyncWrite + Unpin {
+    let buf = accept_handshake(&mut stream, &HandshakeOptions::new()).await?;
+    let (reader, writer) = ::tokio::io::split(stream);
+    Ok((Reader::from_raw(reader, buf), Writer::from_raw(writer)))
+}
+
+/// Consume the client handshake and respond to it, returning any bytes read past it.
+async fn accept_handshake<S>(stream: &mut S, opts: &HandshakeOptions) -> Result<Vec<u8>, ()>
+where S: AsyncRead + AsyncWrite + Unpin {
     let mut buf = Vec::<u8>::with_capacity(1024);
     loop {
         match stream.read_buf(&mut buf).await {
             Ok(n) if n != 0 => {
-                if let [0, 0, 0, 0, ..] = *buf {
-                    // correct client handshake, consume and send server handshake
+                if let [a, b, c, d, ..] = *buf {
+                    if [a, b, c, d] != [0, 0, 0, 0] {
+                        ::tracing::debug!("incorrect client handshake [{}, {}, {}, {}]", a, b, c, d);
+                        if !opts.tolerate_incorrect {
+                            return Err(())
+                        }
+                    }
+                    // consume client handshake and send server handshake
                     match stream.write_all(&SERVER_HANDSHAKE_RESPONSE).await {
                         Ok(()) => (),
                         Err(e) => {
@@ -106,14 +533,7 @@ pub async fn server_handshake(mut stream: TcpStream) -> Result<(Reader, Writer),
                         }
                     }
                     buf.drain(..4);
-                    // now return the Reader and Writer, which are ready to do
-                    // compressed WML messages
-                    let (reader, writer) = stream.into_split();
-                    return Ok((Reader::from_raw(reader, buf), Writer::from_raw(writer)))
-                } else if let [a, b, c, d, ..] = *buf {
-                    ::tracing::debug!("incorrect client handshake [{}, {}, {}, {}]", a, b, c, d);
-                    // TODO: consider having an option to tolerate incorrect handshakes
-                    return Err(())
+                    return Ok(buf)
                 }
             },
             Ok(_) => {
@@ -130,4 +550,524 @@ pub async fn server_handshake(mut stream: TcpStream) -> Result<(Reader, Writer),
     }
 }
 
-// TODO: consider providing client_handshake
+/// Perform the necessary handshake, as the client, to go from raw TCP to
+/// distinct, compressed, blobs of WML.
+pub async fn client_handshake(mut stream: TcpStream) -> Result<(Reader, Writer), ()> {
+    let buf = initiate_handshake(&mut stream).await?;
+    let (reader, writer) = stream.into_split();
+    Ok((Reader::from_raw(reader, buf), Writer::from_raw(writer)))
+}
+
+/// Send the client handshake and check the server's response to it,
+/// returning any bytes read past it.
+async fn initiate_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<Vec<u8>, ()> {
+    match stream.write_all(&[0, 0, 0, 0]).await {
+        Ok(()) => (),
+        Err(e) => {
+            ::tracing::debug!("failed to send client handshake: {:?}", e);
+            return Err(())
+        }
+    }
+    let mut buf = Vec::<u8>::with_capacity(1024);
+    loop {
+        match stream.read_buf(&mut buf).await {
+            Ok(n) if n != 0 => {
+                if buf.starts_with(&SERVER_HANDSHAKE_RESPONSE) {
+                    buf.drain(..4);
+                    return Ok(buf)
+                } else if let [a, b, c, d, ..] = *buf {
+                    ::tracing::debug!("incorrect server handshake [{}, {}, {}, {}]", a, b, c, d);
+                    return Err(())
+                }
+            },
+            Ok(_) => {
+                ::tracing::debug!("connection ended");
+                return Err(())
+            },
+            Err(e) => {
+                ::tracing::debug!("read failure: {:?}", e);
+                return Err(())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, client_handshake, decompress, server_handshake, server_handshake_opts, server_handshake_split};
+    use super::{Compression, HandshakeOptions, ReadError, Reader, Writer};
+    use ::core::pin::Pin;
+    use ::core::task::{ready, Context, Poll};
+    use ::core::time::Duration;
+    use ::std::io;
+    use ::tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+    use ::tokio::net::{TcpListener, TcpStream};
+
+    /// Frame a message the way a Wesnoth peer would, without going through `Writer`.
+    fn frame(msg: &[u8]) -> Vec<u8> {
+        use ::flate2::write::GzEncoder;
+        use ::flate2::Compression;
+        use ::std::io::Write;
+        let mut gz = GzEncoder::new(Vec::new(), Compression::fast());
+        gz.write_all(msg).unwrap();
+        let body = gz.finish().unwrap();
+        let mut framed = u32::to_be_bytes(body.len() as u32).to_vec();
+        framed.extend(body);
+        framed
+    }
+
+    #[::tokio::test]
+    async fn read_over_duplex() {
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server);
+        let msgs: [&[u8]; 2] = [b"[version]\n[/version]\n", b"[error]\nmessage=\"nope\"\n[/error]\n"];
+        let writer = ::tokio::spawn(async move {
+            for msg in msgs {
+                client.write_all(&frame(msg)).await.unwrap();
+            }
+        });
+        for msg in msgs {
+            assert_eq!(&*reader.read().await.unwrap(), msg);
+        }
+        writer.await.unwrap();
+        // the peer hung up, so there's nothing left to read
+        assert!(reader.read().await.is_err());
+    }
+
+    #[::tokio::test]
+    async fn eof_between_messages() {
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server);
+        client.write_all(&frame(b"[version]\n[/version]\n")).await.unwrap();
+        drop(client);
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\n[/version]\n");
+        assert!(matches!(reader.read().await, Err(ReadError::Eof)));
+
+        // Hanging up partway through a message isn't a clean disconnect.
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server);
+        client.write_all(&frame(b"[version]\n[/version]\n")[..10]).await.unwrap();
+        drop(client);
+        match reader.read().await {
+            Err(ReadError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            res => panic!("expected an IO error, got {:?}", res),
+        }
+    }
+
+    #[::tokio::test]
+    async fn write_into_buffer() {
+        let mut writer = Writer::new(Vec::new());
+        writer.write(b"[version]\n[/version]\n").await.unwrap();
+        writer.write(b"[mustlogin]\n[/mustlogin]\n").await.unwrap();
+        let mut expected = frame(b"[version]\n[/version]\n");
+        expected.extend(frame(b"[mustlogin]\n[/mustlogin]\n"));
+        assert_eq!(writer.half, expected);
+    }
+
+    #[::tokio::test]
+    async fn write_then_read() {
+        let (client, server) = ::tokio::io::duplex(64);
+        let mut writer = Writer::new(client);
+        let mut reader = Reader::new(server);
+        ::tokio::spawn(async move {
+            writer.write(b"[version]\n[/version]\n").await.unwrap();
+        });
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\n[/version]\n");
+    }
+
+    #[::tokio::test(start_paused = true)]
+    async fn handshake_and_frame_together() {
+        let (mut client, server) = ::tokio::io::duplex(1024);
+        let mut bytes = vec![0, 0, 0, 0];
+        bytes.extend(frame(b"[version]\n[/version]\n"));
+        // Both arrive before the server reads anything, and nothing follows them.
+        client.write_all(&bytes).await.unwrap();
+        let (mut reader, _writer) = server_handshake_split(server).await.unwrap();
+        let msg = ::tokio::time::timeout(Duration::from_secs(1), reader.read()).await
+            .expect("the frame should already be buffered");
+        assert_eq!(&*msg.unwrap(), b"[version]\n[/version]\n");
+        let mut response = [0; 4];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0, 0, 0, 42]);
+    }
+
+    #[::tokio::test(start_paused = true)]
+    async fn slow_frame() {
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server).with_min_frame_throughput(10);
+        let msg = frame(&[b'x'; 1000]);
+        ::tokio::spawn(async move {
+            for byte in msg {
+                client.write_all(&[byte]).await.unwrap();
+                ::tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+        assert!(matches!(reader.read().await, Err(ReadError::SlowFrame)));
+    }
+
+    #[::tokio::test(start_paused = true)]
+    async fn steady_frame() {
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server).with_min_frame_throughput(10);
+        let msg = frame(b"[version]\n[/version]\n");
+        ::tokio::spawn(async move {
+            for chunk in msg.chunks(20) {
+                client.write_all(chunk).await.unwrap();
+                ::tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\n[/version]\n");
+    }
+
+    #[::tokio::test]
+    async fn into_inner() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let mut sent = vec![0, 0, 0, 0];
+        sent.extend(frame(b"[version]\n[/version]\n"));
+        // only the start of the second message arrives
+        sent.extend(&frame(b"[login]\n[/login]\n")[..6]);
+        client.write_all(&sent).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (mut reader, writer) = server_handshake(server).await.unwrap();
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\n[/version]\n");
+
+        let (read_half, buffered) = reader.into_inner();
+        assert_eq!(buffered, &frame(b"[login]\n[/login]\n")[..6]);
+        let mut server = read_half.reunite(writer.into_inner()).unwrap();
+        server.write_all(b"bye").await.unwrap();
+        let mut response = [0; 7];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"\0\0\0\x2abye");
+    }
+
+    #[::tokio::test]
+    async fn handshake_pair() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let server = ::tokio::spawn(async move {
+            let (mut reader, mut writer) = server_handshake(server).await.unwrap();
+            let msg = reader.read().await.unwrap();
+            writer.write(&msg).await.unwrap();
+        });
+        let (mut reader, mut writer) = client_handshake(client).await.unwrap();
+        writer.write(b"[version]\n[/version]\n").await.unwrap();
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\n[/version]\n");
+        server.await.unwrap();
+    }
+
+    /// Hands out at most one byte per read, like a very slow connection.
+    struct Trickle<S>(S);
+    impl<S: AsyncRead + Unpin> AsyncRead for Trickle<S> {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let mut byte = [0];
+            let mut one = ReadBuf::new(&mut byte);
+            ready!(Pin::new(&mut self.0).poll_read(cx, &mut one))?;
+            buf.put_slice(one.filled());
+            Poll::Ready(Ok(()))
+        }
+    }
+    impl<S: AsyncWrite + Unpin> AsyncWrite for Trickle<S> {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    /// Isn't ready every other time it's polled, so every byte takes two polls to arrive.
+    struct Stutter<S> {
+        inner: S,
+        ready: bool,
+    }
+    impl<S: AsyncRead + Unpin> AsyncRead for Stutter<S> {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending
+            }
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    #[::tokio::test]
+    async fn read_byte_by_byte() {
+        let msgs = [
+            b"[version]\n[/version]\n".to_vec(),
+            format!("[message]\nmessage=\"{}\"\n[/message]\n", "x".repeat(30_000)).into_bytes(),
+            b"[mustlogin]\n[/mustlogin]\n".to_vec(),
+        ];
+        let mut writer = Writer::new(Vec::new());
+        for (i, msg) in msgs.iter().enumerate() {
+            writer.set_compression(if i % 2 == 0 { Compression::Gzip } else { Compression::Bzip2 });
+            writer.write(msg).await.unwrap();
+        }
+        let bytes = writer.into_inner();
+        let mut reader = Reader::new(Stutter { inner: Trickle(&bytes[..]), ready: false });
+        for msg in &msgs {
+            assert_eq!(&*reader.read().await.unwrap(), &msg[..]);
+        }
+        assert!(matches!(reader.read().await, Err(ReadError::Eof)));
+    }
+
+    #[::tokio::test]
+    async fn handshake_byte_by_byte() {
+        let (mut client, server) = ::tokio::io::duplex(1024);
+        let mut sent = vec![0, 0, 0, 0];
+        sent.extend(frame(b"[version]\nversion=\"1.16.0\"\n[/version]\n"));
+        client.write_all(&sent).await.unwrap();
+        let (mut reader, _writer) = server_handshake_split(Trickle(server)).await.unwrap();
+        let mut handshake = [0; 4];
+        client.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(handshake, [0, 0, 0, 42]);
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\nversion=\"1.16.0\"\n[/version]\n");
+    }
+
+    #[::tokio::test]
+    async fn tolerated_client_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let server = ::tokio::spawn(async move {
+            let opts = HandshakeOptions::new().with_tolerate_incorrect();
+            let (mut reader, mut writer) = server_handshake_opts(server, &opts).await.unwrap();
+            let msg = reader.read().await.unwrap();
+            writer.write(&msg).await.unwrap();
+        });
+        client.write_all(&[0, 0, 0, 7]).await.unwrap();
+        let mut handshake = [0; 4];
+        client.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(handshake, [0, 0, 0, 42]);
+        let (reader, writer) = client.into_split();
+        let (mut reader, mut writer) = (Reader::new(reader), Writer::new(writer));
+        writer.write(b"[version]\n[/version]\n").await.unwrap();
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\n[/version]\n");
+        server.await.unwrap();
+
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(&[0, 0, 0, 7]).await.unwrap();
+        assert!(server_handshake(server).await.is_err());
+    }
+
+    #[::tokio::test]
+    async fn wrong_server_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.write_all(&[0, 0, 0, 41]).await.unwrap();
+        assert!(client_handshake(client).await.is_err());
+        let mut handshake = [1; 4];
+        server.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(handshake, [0; 4]);
+    }
+
+    #[::tokio::test]
+    async fn checksums() {
+        let (client, server) = ::tokio::io::duplex(64);
+        let mut writer = Writer::new(client);
+        let mut reader = Reader::new(server).with_checksums();
+        ::tokio::spawn(async move {
+            writer.write(b"[version]\n[/version]\n").await.unwrap();
+        });
+        let msg = reader.read().await.unwrap();
+        let same = Vec::from("[version]\n[/version]\n");
+        assert_eq!(checksum(&msg), checksum(&same));
+        assert_ne!(checksum(&msg), checksum(b"[version]\n[/version]"));
+    }
+
+    #[::tokio::test]
+    async fn bzip2_round_trip() {
+        let (client, server) = ::tokio::io::duplex(64);
+        let mut writer = Writer::new(client);
+        let mut reader = Reader::new(server);
+        writer.write(b"[a]\n[/a]\n").await.unwrap();
+        assert_eq!(&*reader.read().await.unwrap(), b"[a]\n[/a]\n");
+        assert_eq!(reader.last_compression(), Some(Compression::Gzip));
+        writer.set_compression(Compression::Bzip2);
+        writer.write(b"[b]\n[/b]\n").await.unwrap();
+        assert_eq!(&*reader.read().await.unwrap(), b"[b]\n[/b]\n");
+        assert_eq!(reader.last_compression(), Some(Compression::Bzip2));
+    }
+
+    #[::tokio::test]
+    async fn unknown_compression() {
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server);
+        let mut framed = u32::to_be_bytes(5).to_vec();
+        framed.extend(b"[a]\n\n");
+        framed.extend(frame(b"[b]\n[/b]\n"));
+        client.write_all(&framed).await.unwrap();
+        assert!(matches!(reader.read().await, Err(ReadError::Decompress(_))));
+        assert_eq!(reader.last_compression(), None);
+        // The bad message is still skipped over.
+        assert_eq!(&*reader.read().await.unwrap(), b"[b]\n[/b]\n");
+    }
+
+    #[::tokio::test]
+    async fn frame_too_large() {
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server).with_max_message_len(1000);
+        client.write_all(&frame(b"[a]\n[/a]\n")).await.unwrap();
+        assert_eq!(&*reader.read().await.unwrap(), b"[a]\n[/a]\n");
+        // Only the prefix is sent, so this would wait forever if the reader did.
+        client.write_all(&u32::to_be_bytes(u32::MAX)).await.unwrap();
+        assert!(matches!(
+            reader.read().await,
+            Err(ReadError::FrameTooLarge { len, limit: 1000 }) if len == u32::MAX as usize,
+        ));
+        assert!(matches!(reader.read().await, Err(ReadError::FrameTooLarge { .. })));
+    }
+
+    #[::tokio::test]
+    async fn stream_ends_after_fatal_error() {
+        use ::futures_util::StreamExt;
+        let msg = frame(b"[a]\n[/a]\n");
+        let mut truncated = Reader::new(&msg[..msg.len() - 1]);
+        assert!(matches!(truncated.next().await, Some(Err(ReadError::Io(e))) if e.kind() == ::std::io::ErrorKind::UnexpectedEof));
+        assert!(truncated.next().await.is_none());
+        assert!(truncated.next().await.is_none());
+        let prefix = u32::to_be_bytes(u32::MAX);
+        let mut too_large = Reader::new(&prefix[..]).with_max_message_len(1000);
+        assert!(matches!(too_large.next().await, Some(Err(ReadError::FrameTooLarge { .. }))));
+        assert!(too_large.next().await.is_none());
+        // A message that doesn't decompress is skipped.
+        let mut bad = u32::to_be_bytes(3).to_vec();
+        bad.extend(b"lol");
+        bad.extend(&msg);
+        let mut bad = Reader::new(&bad[..]);
+        assert!(matches!(bad.next().await, Some(Err(ReadError::Decompress(_)))));
+        assert_eq!(bad.next().await.unwrap().unwrap(), Box::from(&b"[a]\n[/a]\n"[..]));
+    }
+
+    #[test]
+    fn bounded_decompression() {
+        use ::std::io::Read;
+        /// Counts the bytes read through it.
+        struct Counting<'c, R>(R, &'c mut usize);
+        impl<R: Read> Read for Counting<'_, R> {
+            fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+                let n = self.0.read(buf)?;
+                *self.1 += n;
+                Ok(n)
+            }
+        }
+        let zeros = frame(&vec![0; 1 << 20]);
+        let body = &zeros[4..];
+        let mut inflated = 0;
+        let mut out = Vec::new();
+        let decoder = Counting(::flate2::read::GzDecoder::new(body), &mut inflated);
+        assert!(matches!(decompress(decoder, 1000, &mut out), Err(ReadError::DecompressedTooLarge { limit: 1000 })));
+        assert_eq!(inflated, 1001);
+        // Exactly at the limit is fine.
+        out.clear();
+        decompress(::flate2::read::GzDecoder::new(body), 1 << 20, &mut out).unwrap();
+        assert_eq!(out.len(), 1 << 20);
+    }
+
+    #[::tokio::test]
+    async fn decompressed_too_large() {
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server).with_max_decompressed_len(1000);
+        let mut framed = frame(&[b'x'; 1001]);
+        framed.extend(frame(&[b'y'; 1000]));
+        ::tokio::spawn(async move { client.write_all(&framed).await.unwrap() });
+        assert!(matches!(reader.read().await, Err(ReadError::DecompressedTooLarge { limit: 1000 })));
+        assert_eq!(&*reader.read().await.unwrap(), &[b'y'; 1000][..]);
+    }
+
+    #[::tokio::test]
+    async fn many_buffered_messages() {
+        let mut writer = Writer::new(Vec::new());
+        let msgs = (0..500).map(|i| format!("[message]\nmessage=\"{}\"\n[/message]\n", "x".repeat(i))).collect::<Vec<_>>();
+        for msg in &msgs {
+            writer.write(msg.as_bytes()).await.unwrap();
+        }
+        let bytes = writer.into_inner();
+        // Messages are read out of the buffer as more arrive behind them,
+        // so some of them wrap around its end.
+        let mut reader = Reader::new(&bytes[..]);
+        for msg in &msgs {
+            assert_eq!(&*reader.read().await.unwrap(), msg.as_bytes());
+        }
+        assert!(matches!(reader.read().await, Err(ReadError::Eof)));
+    }
+
+    #[::tokio::test]
+    async fn read_as_stream() {
+        use ::futures_util::{StreamExt, TryStreamExt};
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let msgs: [&[u8]; 3] = [b"[a]\n[/a]\n", b"[b]\n[/b]\n", b"[c]\n[/c]\n"];
+        ::tokio::spawn(async move {
+            for msg in msgs {
+                client.write_all(&frame(msg)).await.unwrap();
+            }
+            // Stay open, so only taking what's wanted can finish.
+            ::core::future::pending::<()>().await;
+        });
+        let read = Reader::new(server).take(2).try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(read, [msgs[0], msgs[1]].map(Box::<[u8]>::from));
+
+        // The stream ends when its input does, between messages.
+        let mut writer = Writer::new(Vec::new());
+        for msg in msgs {
+            writer.write(msg).await.unwrap();
+        }
+        let bytes = writer.into_inner();
+        let read = Reader::new(&bytes[..]).try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(read, msgs.map(Box::<[u8]>::from));
+        let mut partial = Reader::new(&bytes[..bytes.len() - 1]).skip(2);
+        assert!(matches!(partial.next().await, Some(Err(ReadError::Io(_)))));
+        assert!(partial.next().await.is_none());
+    }
+
+    #[::tokio::test]
+    async fn read_doc() {
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server);
+        let mut processor = ::wml::DocProcessor::new();
+        let mut framed = frame(b"[user]\nname=\"lol\"\nstatus=\"lobby\"\n[/user]\n");
+        framed.extend(frame(b"[user]\n"));
+        ::tokio::spawn(async move { client.write_all(&framed).await.unwrap() });
+        let doc = reader.read_doc(&mut processor).await.unwrap();
+        let user = match doc.children().next() {
+            Some(::wml::NodeRef::Tag(user)) => user,
+            _ => panic!("expected a tag"),
+        };
+        assert_eq!(user.name_str(), Ok("user"));
+        assert_eq!(user.get_all("name").collect::<Vec<_>>(), [&b"lol"[..]]);
+        drop(doc);
+        let buf = reader.msg.as_ptr();
+        assert!(matches!(reader.read_doc(&mut processor).await, Err(ReadError::Parse(_))));
+        // The shorter message went into the same buffer.
+        assert_eq!((reader.msg.as_ptr(), &*reader.msg), (buf, &b"[user]\n"[..]));
+    }
+
+    #[::tokio::test(start_paused = true)]
+    async fn read_timeout() {
+        let (mut client, server) = ::tokio::io::duplex(64);
+        let mut reader = Reader::new(server).with_read_timeout(Duration::from_secs(10));
+        let msg = frame(b"[version]\n[/version]\n");
+        client.write_all(&msg[..2]).await.unwrap();
+        let start = ::tokio::time::Instant::now();
+        assert!(matches!(reader.read().await, Err(ReadError::Timeout)));
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+
+        // Anything arriving starts the wait over.
+        ::tokio::spawn(async move {
+            for chunk in msg[2..].chunks(10) {
+                ::tokio::time::sleep(Duration::from_secs(9)).await;
+                client.write_all(chunk).await.unwrap();
+            }
+            ::core::future::pending::<()>().await;
+        });
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\n[/version]\n");
+        assert!(matches!(reader.read().await, Err(ReadError::Timeout)));
+    }
+}
@@ -0,0 +1,97 @@
+//! Optional TLS transport, so WML can be served over an encrypted connection.
+//!
+//! Once a connection is accepted, it goes through [`server_handshake_split`]
+//! exactly like an unencrypted one would.
+//!
+//! [`server_handshake_split`]: crate::stream::server_handshake_split
+use ::std::io;
+use ::std::path::PathBuf;
+use ::std::sync::Arc;
+use ::tokio_rustls::rustls::crypto::ring;
+use ::tokio_rustls::rustls::pki_types::pem::PemObject;
+use ::tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use ::tokio_rustls::rustls::ServerConfig;
+pub use ::tokio_rustls::TlsAcceptor;
+
+/// Where to find the server's certificate chain and private key, both PEM encoded.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+impl TlsConfig {
+    /// Load the certificate chain and key, producing an acceptor for incoming connections.
+    pub fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid(format!("{}: {}", self.cert_path.display(), e)))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| invalid(format!("{}: {}", self.key_path.display(), e)))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TlsConfig;
+    use crate::stream::{server_handshake_split, Reader, Writer};
+    use ::core::convert::TryFrom;
+    use ::std::sync::Arc;
+    use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use ::tokio::net::{TcpListener, TcpStream};
+    use ::tokio_rustls::rustls::crypto::ring;
+    use ::tokio_rustls::rustls::pki_types::ServerName;
+    use ::tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use ::tokio_rustls::TlsConnector;
+
+    #[::tokio::test]
+    async fn handshake_over_tls() {
+        let cert = ::rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = ::std::env::temp_dir().join(format!("wesmaild-tls-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        };
+        ::std::fs::write(&config.cert_path, cert.cert.pem()).unwrap();
+        ::std::fs::write(&config.key_path, cert.key_pair.serialize_pem()).unwrap();
+        let acceptor = config.acceptor().unwrap();
+        ::std::fs::remove_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = ::tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let tls = acceptor.accept(tcp).await.unwrap();
+            let (mut reader, mut writer) = server_handshake_split(tls).await.unwrap();
+            writer.write(b"[version]\n[/version]\n").await.unwrap();
+            reader.read().await.unwrap()
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let mut tls = connector.connect(ServerName::try_from("localhost").unwrap(), tcp).await.unwrap();
+        tls.write_all(&[0, 0, 0, 0]).await.unwrap();
+        let mut response = [0; 4];
+        tls.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0, 0, 0, 42]);
+
+        let (reader, writer) = ::tokio::io::split(tls);
+        let (mut reader, mut writer) = (Reader::new(reader), Writer::new(writer));
+        assert_eq!(&*reader.read().await.unwrap(), b"[version]\n[/version]\n");
+        writer.write(b"[version]\nversion=\"1.16.0\"\n[/version]\n").await.unwrap();
+        assert_eq!(&*server.await.unwrap(), b"[version]\nversion=\"1.16.0\"\n[/version]\n");
+    }
+}
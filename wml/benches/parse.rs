@@ -0,0 +1,165 @@
+//! Parsing server messages, as the server would.
+use ::criterion::measurement::{Measurement, ValueFormatter};
+use ::criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ::wml::DocProcessor;
+
+/// The user list from the `parse_users` test, repeated until it's about as large as
+/// the list a busy server sends to clients joining the lobby.
+fn users() -> Vec<u8> {
+    let user = "[user]\navailable=\"yes\"\nforum_id=\"0\"\ngame_id=\"0\"\nlocation=\"\"\nmoderator=\"no\"\nname=\"lol\"\nregistered=\"no\"\nstatus=\"lobby\"\n[/user]\n";
+    format!("[gamelist]\n[/gamelist]\n{}", user.repeat(500)).into_bytes()
+}
+
+/// Parse the user list with each kind of processor.
+///
+/// This times whole documents, where comparing closing tags with the names they close
+/// is a small part of the work, so it shows what interning costs or saves overall,
+/// not what it does to that comparison. See [`parse_closing_tags`] for that.
+fn parse_users(c: &mut Criterion) {
+    let users = users();
+    let mut group = c.benchmark_group("parse_users");
+    group.throughput(Throughput::Bytes(users.len() as u64));
+    let processors = [
+        ("plain", DocProcessor::new()),
+        ("interning", DocProcessor::new().with_interning()),
+        ("iterative", DocProcessor::new().with_iterative_parsing()),
+    ];
+    for (name, mut processor) in processors {
+        group.bench_function(name, |b| b.iter(|| {
+            processor.parse_borrowed(&users).unwrap();
+            processor.reset();
+        }));
+    }
+    group.finish();
+}
+
+/// Empty tags with long names, so comparing each closing tag with the name it closes
+/// is most of the work, with and without interning.
+///
+/// Interning also looks up each name as it's read, both when a tag opens and when it closes,
+/// so this shows that cost against the cheaper comparison together, not the comparison alone.
+fn parse_closing_tags(c: &mut Criterion) {
+    let names = ["multiplayer_side_configuration", "scenario_and_era_options_list", "story_part_with_background"];
+    let tags = names.iter().map(|name| format!("[{}]\n[/{}]\n", name, name)).collect::<String>();
+    let tags = tags.repeat(1000).into_bytes();
+    let mut group = c.benchmark_group("parse_closing_tags");
+    group.throughput(Throughput::Bytes(tags.len() as u64));
+    let processors = [
+        ("plain", DocProcessor::new()),
+        ("interning", DocProcessor::new().with_interning()),
+    ];
+    for (name, mut processor) in processors {
+        group.bench_function(name, |b| b.iter(|| {
+            processor.parse_borrowed(&tags).unwrap();
+            processor.reset();
+        }));
+    }
+    group.finish();
+}
+
+/// Messages in the shape `wesnothd` sends them, as a client joining a busy lobby would see them.
+///
+/// These are synthetic, written to follow that shape rather than captured from a live server,
+/// so their sizes and values only approximate real traffic. To measure real messages instead,
+/// record a session with `mitm --record session.cap`, take the server's messages out of it
+/// with `mitm --extract session.cap fixtures/`, and point these at the files it writes,
+/// noting which capture each came from.
+const MESSAGES: [(&str, &[u8]); 3] = [
+    ("users", include_bytes!("fixtures/users.wml")),
+    ("gamelist", include_bytes!("fixtures/gamelist.wml")),
+    ("gamelist_diff", include_bytes!("fixtures/gamelist_diff.wml")),
+];
+
+/// Parse each message.
+fn parse_messages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_messages");
+    let mut processor = DocProcessor::new();
+    for (name, msg) in MESSAGES {
+        group.throughput(Throughput::Bytes(msg.len() as u64));
+        group.bench_function(name, |b| b.iter(|| {
+            processor.parse_borrowed(msg).unwrap();
+            processor.reset();
+        }));
+    }
+    group.finish();
+}
+
+/// Tags nested nearly as deep as the default limit allows, each with an attribute.
+fn parse_nested(c: &mut Criterion) {
+    let depth = 500;
+    let nested = format!("{}{}", "[a]\nb=\"c\"\n".repeat(depth), "[/a]\n".repeat(depth)).into_bytes();
+    let mut group = c.benchmark_group("parse_nested");
+    group.throughput(Throughput::Bytes(nested.len() as u64));
+    let processors = [
+        ("recursive", DocProcessor::new()),
+        ("iterative", DocProcessor::new().with_iterative_parsing()),
+    ];
+    for (name, mut processor) in processors {
+        group.bench_function(name, |b| b.iter(|| {
+            processor.parse_borrowed(&nested).unwrap();
+            processor.reset();
+        }));
+    }
+    group.finish();
+}
+
+/// Bytes of arena taken up, measured in place of time, so how much of the arena
+/// each message takes up is reported and compared between runs like a timing.
+struct ArenaBytes;
+impl Measurement for ArenaBytes {
+    type Intermediate = ();
+    type Value = usize;
+    fn start(&self) {}
+    // Only measured with `iter_custom`, which gives the value itself.
+    fn end(&self, (): ()) -> usize {
+        0
+    }
+    fn add(&self, a: &usize, b: &usize) -> usize {
+        a + b
+    }
+    fn zero(&self) -> usize {
+        0
+    }
+    fn to_f64(&self, value: &usize) -> f64 {
+        *value as f64
+    }
+    fn formatter(&self) -> &dyn ValueFormatter {
+        self
+    }
+}
+impl ValueFormatter for ArenaBytes {
+    fn scale_values(&self, _typical: f64, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+    fn scale_throughputs(&self, _typical: f64, _throughput: &Throughput, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+}
+
+/// How many bytes of the arena each message takes up once parsed.
+fn arena_usage(c: &mut Criterion<ArenaBytes>) {
+    let mut group = c.benchmark_group("arena_usage");
+    let mut processor = DocProcessor::new();
+    for (name, msg) in MESSAGES {
+        // Criterion picks how many iterations to run by how long they take,
+        // so each one really parses the message.
+        group.bench_function(name, |b| b.iter_custom(|iters| (0..iters).map(|_| {
+            processor.parse_borrowed(msg).unwrap();
+            let used = processor.allocated_bytes();
+            processor.reset();
+            used
+        }).sum()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse_users, parse_closing_tags, parse_messages, parse_nested);
+criterion_group! {
+    name = arena;
+    config = Criterion::default().with_measurement(ArenaBytes);
+    targets = arena_usage
+}
+criterion_main!(benches, arena);
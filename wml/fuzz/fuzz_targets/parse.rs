@@ -0,0 +1,16 @@
+//! Parse arbitrary bytes as a document, both recursively and iteratively,
+//! which must agree, and as a prefix of a document.
+#![no_main]
+use ::libfuzzer_sys::fuzz_target;
+use ::wml::DocProcessor;
+
+fuzz_target!(|data: &[u8]| {
+    let recursive = DocProcessor::new();
+    let iterative = DocProcessor::new().with_iterative_parsing();
+    let doc = recursive.parse_borrowed(data);
+    assert_eq!(doc, iterative.parse_borrowed(data));
+    let prefix = recursive.parse_prefix(data);
+    if let Ok((_, rest)) = prefix {
+        assert!(data.ends_with(rest));
+    }
+});
@@ -0,0 +1,5 @@
+//! Parse arbitrary bytes as a quoted string.
+#![no_main]
+use ::libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| ::wml::fuzz::wstring(data));
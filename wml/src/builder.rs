@@ -0,0 +1,252 @@
+//! Building serialized WML documents programmatically.
+use ::core::fmt::{self, Write};
+
+/// Builds a serialized WML document, one tag or attribute at a time.
+///
+/// Every value is written as a double quoted string, and every line ends in `\n`,
+/// so the output is exactly what [`Doc::write_to`](crate::Doc::write_to) would write
+/// after parsing it.
+/// See also the [`wml!`](crate::wml) macro, which drives a `DocBuilder` using WML syntax.
+///
+/// ```
+/// use wml::DocBuilder;
+/// let mut builder = DocBuilder::new();
+/// builder.push_tag("version").push_attr("version", "1.16.0").close_tag("version");
+/// assert_eq!(builder.finish(), b"[version]\nversion=\"1.16.0\"\n[/version]\n");
+/// ```
+///
+/// Tags can be empty, as when asking a client for its version:
+///
+/// ```
+/// # use wml::DocBuilder;
+/// let mut builder = DocBuilder::new();
+/// builder.push_tag("version").close_tag("version");
+/// assert_eq!(builder.finish(), b"[version]\n[/version]\n");
+/// ```
+#[derive(Debug, Default)]
+pub struct DocBuilder {
+    buf: Vec<u8>,
+    /// Names of the tags which are currently open, innermost last.
+    open: Vec<String>,
+}
+
+impl DocBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Open a tag, so that everything pushed until it's closed goes inside it.
+    ///
+    /// # Panics
+    /// If `name` isn't a valid WML name, made of ASCII letters, digits and `_`.
+    #[track_caller]
+    pub fn push_tag(&mut self, name: &str) -> &mut Self {
+        check_name(name);
+        self.buf.push(b'[');
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.extend_from_slice(b"]\n");
+        self.open.push(name.into());
+        self
+    }
+    /// Add an attribute, with `value` written as a quoted string.
+    ///
+    /// # Panics
+    /// If `key` isn't a valid WML name, made of ASCII letters, digits and `_`.
+    #[track_caller]
+    pub fn push_attr(&mut self, key: &str, value: impl fmt::Display) -> &mut Self {
+        check_name(key);
+        self.buf.extend_from_slice(key.as_bytes());
+        self.buf.extend_from_slice(b"=\"");
+        write!(Escaper(&mut self.buf), "{}", value).expect("writing to a Vec can't fail");
+        self.buf.extend_from_slice(b"\"\n");
+        self
+    }
+    /// Like [`push_attr`](Self::push_attr), but writing `value` byte for byte,
+    /// for values which may not be valid UTF-8, like ones taken from another document.
+    ///
+    /// # Panics
+    /// If `key` isn't a valid WML name, made of ASCII letters, digits and `_`.
+    #[track_caller]
+    pub fn push_attr_bytes(&mut self, key: &str, value: &[u8]) -> &mut Self {
+        check_name(key);
+        self.buf.extend_from_slice(key.as_bytes());
+        self.buf.extend_from_slice(b"=\"");
+        escape(&mut self.buf, value);
+        self.buf.extend_from_slice(b"\"\n");
+        self
+    }
+    /// Close the innermost open tag.
+    ///
+    /// # Panics
+    /// If `name` isn't the name of the innermost open tag.
+    #[track_caller]
+    pub fn close_tag(&mut self, name: &str) -> &mut Self {
+        match self.open.pop() {
+            Some(open) if open == name => (),
+            Some(open) => panic!("tried to close [{}] while [{}] is open", name, open),
+            None => panic!("tried to close [{}] with no open tags", name),
+        }
+        self.buf.extend_from_slice(b"[/");
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.extend_from_slice(b"]\n");
+        self
+    }
+    /// Get the serialized document.
+    ///
+    /// # Panics
+    /// If any tags are still open.
+    #[track_caller]
+    pub fn finish(self) -> Vec<u8> {
+        if let Some(open) = self.open.last() {
+            panic!("finished document with [{}] still open", open);
+        }
+        self.buf
+    }
+}
+
+/// Whether `name` can be written as the name of a tag or attribute,
+/// which is `wml_name` in the WML grammar.
+pub(crate) fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// Panic unless `name` is a valid name, so nothing else can be written in its place.
+#[track_caller]
+fn check_name(name: &str) {
+    if !is_name(name) {
+        panic!("{:?} isn't a valid WML name", name);
+    }
+}
+
+/// Writes strings with `"` escaped as `""`.
+struct Escaper<'a>(&'a mut Vec<u8>);
+impl Write for Escaper<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        escape(self.0, s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Write `bytes` to `buf` with `"` escaped as `""`.
+fn escape(buf: &mut Vec<u8>, bytes: &[u8]) {
+    for &byte in bytes {
+        if byte == b'"' {
+            buf.push(b'"');
+        }
+        buf.push(byte);
+    }
+}
+
+/// Build a serialized WML document using WML syntax.
+///
+/// Values are single tokens: literals, or any expression in braces.
+/// They're written with [`DocBuilder::push_attr`], so anything implementing `Display` works.
+///
+/// ```
+/// let username = "lol";
+/// let doc = wml::wml! {
+///     [login]
+///         username = {username}
+///         selective_ping = 1
+///     [/login]
+/// };
+/// assert_eq!(doc, b"[login]\nusername=\"lol\"\nselective_ping=\"1\"\n[/login]\n");
+/// ```
+#[macro_export]
+macro_rules! wml {
+    (@build $builder:ident) => {};
+    (@build $builder:ident [/ $name:ident] $($rest:tt)*) => {
+        $builder.close_tag(stringify!($name));
+        $crate::wml!(@build $builder $($rest)*);
+    };
+    (@build $builder:ident [$name:ident] $($rest:tt)*) => {
+        $builder.push_tag(stringify!($name));
+        $crate::wml!(@build $builder $($rest)*);
+    };
+    (@build $builder:ident $key:ident = $value:tt $($rest:tt)*) => {
+        $builder.push_attr(stringify!($key), $value);
+        $crate::wml!(@build $builder $($rest)*);
+    };
+    ($($body:tt)*) => {{
+        let mut builder = $crate::DocBuilder::new();
+        $crate::wml!(@build builder $($body)*);
+        builder.finish()
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DocBuilder, DocProcessor, NodeRef};
+
+    #[test]
+    fn macro_matches_parsed() {
+        let processor = DocProcessor::new();
+        let built = processor.parse(wml! {
+            [version]
+                version = "1.16"
+            [/version]
+            [user]
+                name = "haha"
+                forum_id = 0
+                [info]
+                    bio = "says \"hi\""
+                [/info]
+            [/user]
+        }).unwrap();
+        let parsed = processor.parse(Vec::from(concat!(
+            "[version]\nversion=\"1.16\"\n[/version]\n",
+            "[user]\nname=\"haha\"\nforum_id=0\n[info]\nbio=\"says \"\"hi\"\"\"\n[/info]\n[/user]\n",
+        ))).unwrap();
+        assert_eq!(built, parsed);
+        let different = processor.parse(Vec::from("[version]\nversion=\"1.14\"\n[/version]\n")).unwrap();
+        assert!(built != different);
+    }
+
+    #[test]
+    fn built_is_canonical() {
+        let processor = DocProcessor::new();
+        let built = wml! {
+            [version][/version]
+            [gamelist]
+                [game]
+                    name = "a \"b\""
+                    id = 3
+                [/game]
+            [/gamelist]
+        };
+        let mut written = Vec::new();
+        processor.parse(built.clone()).unwrap().write_to(&mut written).unwrap();
+        assert_eq!(written, built);
+    }
+
+    #[test]
+    fn attr_bytes() {
+        let mut builder = DocBuilder::new();
+        builder.push_tag("a").push_attr_bytes("x", b"l\xff\"l").close_tag("a");
+        let built = builder.finish();
+        assert_eq!(built, b"[a]\nx=\"l\xff\"\"l\"\n[/a]\n");
+        let processor = DocProcessor::new();
+        let doc = processor.parse(built).unwrap();
+        match doc.find("a/x") {
+            Some(NodeRef::Attr(x)) => assert_eq!(x.value(), &b"l\xff\"l"[..]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn mismatched_close() {
+        let _ = wml! { [a] [/b] };
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't a valid WML name")]
+    fn invalid_tag_name() {
+        DocBuilder::new().push_tag("bad name]\n[x");
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't a valid WML name")]
+    fn invalid_attr_key() {
+        DocBuilder::new().push_attr("a=\"1\"\n[admin]\nx", "y");
+    }
+}
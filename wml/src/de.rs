@@ -0,0 +1,458 @@
+//! Reading parsed documents into Rust types with `serde`.
+use crate::tree::{parse_bool, piece_of};
+use crate::{Doc, NodeRef, TagRef};
+use ::core::fmt;
+use ::serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Unexpected, Visitor};
+use ::std::borrow::Cow;
+
+/// Why a document couldn't be deserialized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeserializeError(String);
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl ::std::error::Error for DeserializeError {}
+
+impl de::Error for DeserializeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+/// Deserialize a `T` from the top level of `doc`, as [`Deserializer`] describes.
+///
+/// ```
+/// #[derive(serde::Deserialize)]
+/// struct Version {
+///     version: String,
+/// }
+/// let processor = wml::DocProcessor::new();
+/// let doc = processor.parse(Vec::from("[version]\nversion=\"1.16.0\"\n[/version]\n")).unwrap();
+/// let versions: Vec<Version> = wml::from_doc(&doc).unwrap();
+/// assert_eq!(versions[0].version, "1.16.0");
+/// ```
+pub fn from_doc<'d, T: de::Deserialize<'d>>(doc: &'d Doc<'_>) -> Result<T, DeserializeError> {
+    T::deserialize(Deserializer::from_doc(doc))
+}
+
+/// Deserialize a `T` from what's inside `tag`, as [`Deserializer`] describes.
+pub fn from_tag<'d, T: de::Deserialize<'d>>(tag: TagRef<'d>) -> Result<T, DeserializeError> {
+    T::deserialize(Deserializer::from_tag(tag))
+}
+
+/// A [`serde::Deserializer`] over what's inside a tag, or the top level of a document.
+///
+/// - It's read as a map, or a struct, with the names of the tags and attributes in it as keys.
+///   A name that appears more than once, like a repeated child tag, may be read as a sequence,
+///   such as a `Vec`. Otherwise, only its last appearance is read,
+///   since a later one overrides an earlier one, as with [`TagRef::get`].
+/// - It may also be read as a sequence itself, of the tags in it.
+/// - Tags are read like this in turn, and attribute values are read as strings,
+///   numbers, or booleans, which are read like [`AttrRef::as_bool`](crate::AttrRef::as_bool) reads them.
+/// - An attribute with a key sequence, like `a,b=1,2`, gives each of its keys a value
+///   like [`TagRef::get_all`] does.
+pub struct Deserializer<'d> {
+    nodes: Vec<NodeRef<'d>>,
+}
+
+impl<'d> Deserializer<'d> {
+    pub fn from_doc(doc: &'d Doc<'_>) -> Self {
+        Self { nodes: doc.children().collect() }
+    }
+    pub fn from_tag(tag: TagRef<'d>) -> Self {
+        Self { nodes: tag.children().collect() }
+    }
+    /// Group everything by name, in the order each name first appears.
+    fn entries(self) -> Vec<(&'d str, Vec<Item<'d>>)> {
+        let mut entries = Vec::<(&'d str, Vec<Item<'d>>)>::new();
+        let mut add = |name, item| match entries.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, items)) => items.push(item),
+            None => entries.push((name, vec![item])),
+        };
+        for node in self.nodes {
+            match node {
+                // Names are always ASCII.
+                NodeRef::Tag(tag) => add(tag.name_str().unwrap(), Item::Tag(tag)),
+                NodeRef::Attr(attr) => {
+                    let keys = attr.key_str().unwrap();
+                    if keys.contains(',') {
+                        for (n, key) in keys.split(',').enumerate() {
+                            add(key, Item::Value(piece_of(&attr.value(), n)));
+                        }
+                    } else {
+                        add(keys, Item::Value(attr.value()));
+                    }
+                },
+            }
+        }
+        entries
+    }
+    fn tags(self) -> Items<'d> {
+        let tags = self.nodes.into_iter().filter_map(|node| match node {
+            NodeRef::Tag(tag) => Some(Item::Tag(tag)),
+            NodeRef::Attr(_) => None,
+        });
+        Items(tags.collect::<Vec<_>>().into_iter())
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'de> {
+    type Error = DeserializeError;
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(Entries { entries: self.entries().into_iter(), value: None })
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_seq(self.tags())
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+    ::serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+/// A tag, or the value an attribute gives a key.
+enum Item<'d> {
+    Tag(TagRef<'d>),
+    Value(Cow<'d, [u8]>),
+}
+
+impl<'d> Item<'d> {
+    fn value(self, expected: &dyn de::Expected) -> Result<Cow<'d, [u8]>, DeserializeError> {
+        match self {
+            Self::Value(value) => Ok(value),
+            Self::Tag(_) => Err(de::Error::invalid_type(Unexpected::Map, expected)),
+        }
+    }
+    fn str(self, expected: &dyn de::Expected) -> Result<Cow<'d, str>, DeserializeError> {
+        match self.value(expected)? {
+            Cow::Borrowed(value) => ::core::str::from_utf8(value).map(Cow::Borrowed).ok(),
+            Cow::Owned(value) => String::from_utf8(value).map(Cow::Owned).ok(),
+        }.ok_or_else(|| de::Error::custom("value isn't valid UTF-8"))
+    }
+}
+
+/// Parse the value as a number for each `deserialize_*` method given.
+macro_rules! parse_numbers {
+    ($($method:ident => $visit:ident,)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+            let value = self.str(&visitor)?;
+            match value.trim().parse() {
+                Ok(n) => visitor.$visit(n),
+                Err(_) => Err(de::Error::invalid_value(Unexpected::Str(&value), &visitor)),
+            }
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for Item<'de> {
+    type Error = DeserializeError;
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Self::Tag(tag) => Deserializer::from_tag(tag).deserialize_any(visitor),
+            Self::Value(Cow::Borrowed(value)) => match ::core::str::from_utf8(value) {
+                Ok(value) => visitor.visit_borrowed_str(value),
+                Err(_) => visitor.visit_borrowed_bytes(value),
+            },
+            Self::Value(Cow::Owned(value)) => match String::from_utf8(value) {
+                Ok(value) => visitor.visit_string(value),
+                Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+            },
+        }
+    }
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let value = self.str(&visitor)?;
+        match parse_bool(&value) {
+            Some(b) => visitor.visit_bool(b),
+            None => Err(de::Error::invalid_value(Unexpected::Str(&value), &visitor)),
+        }
+    }
+    parse_numbers! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.value(&visitor)? {
+            Cow::Borrowed(value) => visitor.visit_borrowed_bytes(value),
+            Cow::Owned(value) => visitor.visit_byte_buf(value),
+        }
+    }
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_bytes(visitor)
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Self::Tag(tag) => Deserializer::from_tag(tag).deserialize_seq(visitor),
+            value => value.deserialize_any(visitor),
+        }
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+    /// Only enums with unit variants can be written as attribute values, by name.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.str(&visitor)? {
+            Cow::Borrowed(value) => visitor.visit_enum(IntoDeserializer::<DeserializeError>::into_deserializer(value)),
+            Cow::Owned(value) => visitor.visit_enum(IntoDeserializer::<DeserializeError>::into_deserializer(value)),
+        }
+    }
+    ::serde::forward_to_deserialize_any! {
+        str string map struct identifier ignored_any
+    }
+}
+
+/// Every appearance of one name, as the value of a map entry.
+struct Field<'d>(Vec<Item<'d>>);
+
+impl<'d> Field<'d> {
+    fn last(self) -> Item<'d> {
+        // Entries are only made with an item in them.
+        self.0.into_iter().last().unwrap()
+    }
+}
+
+/// Read the last appearance of a name for each `deserialize_*` method given.
+macro_rules! last {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {$(
+        fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, Self::Error> {
+            self.last().$method($($arg,)* visitor)
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for Field<'de> {
+    type Error = DeserializeError;
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_seq(Items(self.0.into_iter()))
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+    last! {
+        deserialize_any();
+        deserialize_bool();
+        deserialize_i8();
+        deserialize_i16();
+        deserialize_i32();
+        deserialize_i64();
+        deserialize_i128();
+        deserialize_u8();
+        deserialize_u16();
+        deserialize_u32();
+        deserialize_u64();
+        deserialize_u128();
+        deserialize_f32();
+        deserialize_f64();
+        deserialize_char();
+        deserialize_str();
+        deserialize_string();
+        deserialize_bytes();
+        deserialize_byte_buf();
+        deserialize_unit();
+        deserialize_unit_struct(name: &'static str);
+        deserialize_tuple_struct(name: &'static str, len: usize);
+        deserialize_map();
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+        deserialize_identifier();
+        deserialize_ignored_any();
+    }
+}
+
+struct Items<'d>(::std::vec::IntoIter<Item<'d>>);
+
+impl<'de> SeqAccess<'de> for Items<'de> {
+    type Error = DeserializeError;
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error> {
+        self.0.next().map(|item| seed.deserialize(item)).transpose()
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct Entries<'d> {
+    entries: ::std::vec::IntoIter<(&'d str, Vec<Item<'d>>)>,
+    /// The items for the key last returned.
+    value: Option<Vec<Item<'d>>>,
+}
+
+impl<'de> MapAccess<'de> for Entries<'de> {
+    type Error = DeserializeError;
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        match self.entries.next() {
+            Some((key, items)) => {
+                self.value = Some(items);
+                seed.deserialize(de::value::BorrowedStrDeserializer::new(key)).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, Self::Error> {
+        let items = self.value.take().ok_or_else(|| de::Error::custom("value requested before its key"))?;
+        seed.deserialize(Field(items))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_doc, from_tag};
+    use crate::DocProcessor;
+    use crate::test_util::{first_tag, USERS};
+    use ::serde::Deserialize;
+    use ::std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct User {
+        name: String,
+        available: bool,
+        forum_id: u64,
+        game_id: u64,
+        location: String,
+        moderator: bool,
+        registered: bool,
+        status: String,
+    }
+
+    #[test]
+    fn deserialize_users() {
+        let processor = DocProcessor::new();
+        let users = Vec::from(USERS);
+        let doc = processor.parse(users).unwrap();
+        let users: Vec<User> = from_doc(&doc).unwrap();
+        let user = |name: &str| User {
+            name: name.into(),
+            available: true,
+            forum_id: 0,
+            game_id: 0,
+            location: String::new(),
+            moderator: false,
+            registered: false,
+            status: "lobby".into(),
+        };
+        assert_eq!(users, [user("lol"), user("haha")]);
+
+        #[derive(Debug, Deserialize)]
+        struct Users {
+            user: Vec<User>,
+        }
+        assert_eq!(from_doc::<Users>(&doc).unwrap().user, users);
+
+        #[derive(Debug, Deserialize)]
+        struct LastUser {
+            user: User,
+        }
+        assert_eq!(from_doc::<LastUser>(&doc).unwrap().user, user("haha"));
+    }
+
+    #[test]
+    fn deserialize_nested() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Game<'a> {
+            id: u32,
+            name: &'a str,
+            password: Option<bool>,
+            #[serde(default)]
+            slot: Vec<Slot>,
+        }
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Slot {
+            side: i8,
+            #[serde(rename = "type")]
+            kind: Kind,
+        }
+        #[derive(Debug, PartialEq, Deserialize)]
+        #[serde(rename_all = "lowercase")]
+        enum Kind {
+            Human,
+            Ai,
+        }
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "[gamelist]\n",
+            "[game]\nid=\"1\"\nname=\"first\"\npassword=yes\n",
+            "[slot]\nside,type=1,human\n[/slot]\n[slot]\nside=2\ntype=ai\n[/slot]\n",
+            "[/game]\n",
+            "[game]\nid=\" 2 \"\nname=\"second\"\n[/game]\n",
+            "[/gamelist]\n",
+        ))).unwrap();
+        let gamelist = first_tag(&doc);
+        #[derive(Debug, Deserialize)]
+        struct GameList<'a> {
+            #[serde(borrow)]
+            game: Vec<Game<'a>>,
+        }
+        let games = from_tag::<GameList<'_>>(gamelist).unwrap().game;
+        assert_eq!(games, [
+            Game {
+                id: 1,
+                name: "first",
+                password: Some(true),
+                slot: vec![Slot { side: 1, kind: Kind::Human }, Slot { side: 2, kind: Kind::Ai }],
+            },
+            Game { id: 2, name: "second", password: None, slot: Vec::new() },
+        ]);
+
+        let attrs: BTreeMap<String, BTreeMap<String, String>> = from_doc(&processor.parse(Vec::from(
+            "[a]\nx=1\ny=\"2\"\n[/a]\n",
+        )).unwrap()).unwrap();
+        assert_eq!(attrs["a"]["x"], "1");
+        assert_eq!(attrs["a"]["y"], "2");
+
+        let bad = processor.parse(Vec::from("[game]\nid=x\nname=\"\"\n[/game]\n")).unwrap();
+        let err = from_doc::<GameList<'_>>(&bad).unwrap_err();
+        assert_eq!(err.to_string(), "invalid value: string \"x\", expected u32");
+    }
+}
@@ -0,0 +1,123 @@
+//! Changing attribute values without reserializing the whole document.
+use crate::{Doc, TagOrAttr};
+use crate::tree::is_named;
+
+impl Doc<'_> {
+    /// Replace the value of an attribute, for the next [`dump`](Self::dump).
+    ///
+    /// `path` is the names of the tags leading to the attribute, then its key,
+    /// each matching the last tag or attribute of that name, as with [`TagRef::get`](crate::TagRef::get).
+    /// The new value is written as a plain quoted string, replacing every component of the old one,
+    /// along with any `_` marking it for translation.
+    /// An attribute with a key sequence, like `a,b=1,2`, shares its value between its keys,
+    /// so it isn't changed.
+    ///
+    /// Only [`dump`](Self::dump) sees the change: reading or comparing the document,
+    /// or writing it with [`write_to`](Self::write_to), still gives the parsed value.
+    ///
+    /// Returns whether the value was replaced.
+    pub fn set_attr_value(&mut self, path: &[&str], new_value: &str) -> bool {
+        let (key, tags) = match path.split_last() {
+            Some(split) => split,
+            None => return false,
+        };
+        let mut content = &self.top[..];
+        for name in tags {
+            match content.iter().rev().find(|node| is_named(node, &self.text, name.as_bytes())) {
+                Some(TagOrAttr::Tag(tag)) => content = &tag.content,
+                _ => return false,
+            }
+        }
+        let attr = content.iter().rev().find_map(|node| match node {
+            TagOrAttr::Attr(attr) if is_named(node, &self.text, key.as_bytes()) => Some(attr),
+            _ => None,
+        });
+        let span = match attr {
+            Some(attr) if attr.key_sequence.names.is_empty() => attr.value.span(&self.text),
+            _ => return false,
+        };
+        let mut value = Vec::with_capacity(new_value.len() + 2);
+        value.push(b'"');
+        for &byte in new_value.as_bytes() {
+            if byte == b'"' {
+                value.push(b'"');
+            }
+            value.push(byte);
+        }
+        value.push(b'"');
+        match self.overrides.binary_search_by_key(&span.start, |(span, _)| span.start) {
+            Ok(i) => self.overrides[i].1 = value,
+            Err(i) => self.overrides.insert(i, (span, value)),
+        }
+        true
+    }
+    /// Get the document as it was parsed, with the values replaced by
+    /// [`set_attr_value`](Self::set_attr_value) spliced in.
+    ///
+    /// Everything else is copied byte for byte, comments and formatting included,
+    /// so a large document with a few changed values is cheap to dump.
+    pub fn dump(&self) -> Vec<u8> {
+        let added = self.overrides.iter().map(|(_, value)| value.len()).sum::<usize>();
+        let mut out = Vec::with_capacity(self.text.len() + added);
+        let mut copied = 0;
+        for (span, value) in &self.overrides {
+            out.extend_from_slice(&self.text[copied..span.start]);
+            out.extend_from_slice(value);
+            copied = span.end;
+        }
+        out.extend_from_slice(&self.text[copied..]);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DocProcessor, NodeRef};
+
+    #[test]
+    fn flip_available() {
+        let processor = DocProcessor::new();
+        let users = "[user]\navailable=\"yes\"\nname=\"lol\"\n[/user]\n# second\n[user]\navailable=\"yes\"\nname=\"haha\"\n[/user]\n";
+        let mut doc = processor.parse(Vec::from(users)).unwrap();
+        assert_eq!(doc.dump(), users.as_bytes());
+        assert!(doc.set_attr_value(&["user", "available"], "maybe"));
+        assert!(doc.set_attr_value(&["user", "available"], "no"));
+        assert!(!doc.set_attr_value(&["user", "missing"], "no"));
+        assert!(!doc.set_attr_value(&["available"], "no"));
+        assert_eq!(doc.dump(), users.replacen("\"yes\"", "\"no\"", 2).replacen("\"no\"", "\"yes\"", 1).as_bytes());
+    }
+
+    #[test]
+    fn replace_whole_value() {
+        let processor = DocProcessor::new();
+        let source = "a=  some text  \r\nb=\"x\" + <<y>>+\n#textdomain wesnoth\n_\"z\"\nc=1\n";
+        let mut doc = processor.parse(Vec::from(source)).unwrap();
+        doc.set_attr_value(&["c"], "say \"hi\"");
+        doc.set_attr_value(&["a"], "");
+        doc.set_attr_value(&["b"], "w");
+        let dumped = doc.dump();
+        assert_eq!(String::from_utf8(dumped.clone()).unwrap(), "a=  \"\"  \r\nb=\"w\"\nc=\"say \"\"hi\"\"\"\n");
+        let doc = processor.parse(dumped).unwrap();
+        match doc.children().next() {
+            Some(NodeRef::Attr(attr)) => assert!(attr.value().is_empty()),
+            _ => panic!("expected an attribute"),
+        };
+    }
+
+    #[test]
+    fn duplicate_key() {
+        let processor = DocProcessor::new();
+        let mut doc = processor.parse(Vec::from("[u]\na=\"1\"\na=\"2\"\n[/u]\n")).unwrap();
+        assert!(doc.set_attr_value(&["u", "a"], "X"));
+        assert_eq!(doc.dump(), b"[u]\na=\"1\"\na=\"X\"\n[/u]\n");
+    }
+
+    #[test]
+    fn untranslatable_and_key_sequences() {
+        let processor = DocProcessor::new();
+        let mut doc = processor.parse(Vec::from("[u]\nname=_\"lol\"\na,b=1,2\n[/u]\n")).unwrap();
+        assert!(doc.set_attr_value(&["u", "name"], "new"));
+        assert!(!doc.set_attr_value(&["u", "b"], "new"));
+        assert_eq!(doc.dump(), b"[u]\nname=\"new\"\na,b=1,2\n[/u]\n");
+    }
+}
@@ -1,32 +1,105 @@
-//! Trying out an idea that's been sitting around for a bit.
-//! I'll probably want to rip this module out before publishing this library anywhere.
+//! Resolving strings out of a document through a chain of handlers,
+//! so callers can observe or replace how it's done.
 
+use crate::StringKey;
+use ::core::cell::RefCell;
+
+/// A handler which handles nothing, so everything falls through to the next one.
 #[derive(Debug, Copy, Clone)]
-pub(crate) struct NullHandler;
+pub struct NullHandler;
 
+/// The default handler, which gets strings straight out of the buffer a document was parsed from.
 #[derive(Debug, Copy, Clone)]
-pub(crate) struct Printer<'a> {
+pub struct Printer<'a> {
     data: &'a [u8],
 }
 impl<'a> Printer<'a> {
-    pub(crate) fn new(data: &'a [u8]) -> Self {
+    /// Resolve strings out of `data`, which must be the buffer being parsed.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+/// Like [`Printer`], but for a buffer known to be valid UTF-8, so getting strings
+/// out of it only checks that they start and end on character boundaries,
+/// instead of checking every byte of them again.
+///
+/// Strings in a document end at ASCII delimiters, so the boundary check only fails
+/// for keys into some other buffer.
+/// Get one for a document parsed [`with_utf8_validation`](crate::DocProcessor::with_utf8_validation)
+/// with [`Doc::str_printer`](crate::Doc::str_printer).
+#[derive(Debug, Copy, Clone)]
+pub struct StrPrinter<'a> {
+    data: &'a str,
+}
+impl<'a> StrPrinter<'a> {
+    pub fn new(data: &'a str) -> Self {
         Self { data }
     }
 }
 
-pub(crate) struct FallbackHandler<'a, A, B> {
+/// A handler which tallies up the strings resolved through it, without handling them,
+/// for profiling what's being parsed.
+///
+/// Put it in front of a handler which does resolve strings, like `stats.or(&printer)`,
+/// as [`DocProcessor::parse_with`](crate::DocProcessor::parse_with) does.
+///
+/// ```
+/// use wml::{DocProcessor, StatsHandler};
+/// let processor = DocProcessor::new();
+/// let stats = StatsHandler::new();
+/// processor.parse_with(Vec::from("[a]\nx=\"hello\" + <<world>>\n[/a]\n"), &stats).unwrap();
+/// assert_eq!(stats.report().strings, 2);
+/// assert_eq!(stats.report().bytes, 10);
+/// ```
+#[derive(Debug, Default)]
+pub struct StatsHandler {
+    stats: RefCell<ParseStats>,
+}
+impl StatsHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Everything tallied so far.
+    pub fn report(&self) -> ParseStats {
+        self.stats.borrow().clone()
+    }
+}
+
+/// What a [`StatsHandler`] has seen.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseStats {
+    /// How many strings were resolved.
+    pub strings: usize,
+    /// How long they were altogether, in bytes.
+    pub bytes: usize,
+    /// How many strings there were of each length, in buckets by powers of two:
+    /// bucket 0 counts empty strings, and bucket `n` lengths from `2^(n-1)` to `2^n - 1`.
+    pub length_histogram: Vec<usize>,
+}
+
+/// Tries one handler, then another if the first doesn't handle the request.
+/// Made with [`Effects::or`].
+#[derive(Debug)]
+pub struct FallbackHandler<'a, A, B> {
     first: &'a A,
     second: &'a B,
 }
 
-pub(crate) enum EffectResult<T> {
+/// Whether a handler handled a request, and what it gave back.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EffectResult<T> {
     // TODO: decide how to report reason for failure
     Unhandled,
     Handled(T),
 }
 impl<T> EffectResult<T> {
+    /// Get what the request was handled with.
+    ///
+    /// # Panics
+    /// If it wasn't handled.
     #[track_caller]
-    pub(crate) fn unwrap(self) -> T {
+    pub fn unwrap(self) -> T {
         match self {
             Self::Unhandled => panic!("unhandled effect"),
             Self::Handled(x) => x,
@@ -34,9 +107,38 @@ impl<T> EffectResult<T> {
     }
 }
 
-pub(crate) trait Effects {
-    fn get_bytes(&self, key: super::StringKey) -> EffectResult<&[u8]>;
-    fn get_str(&self, key: super::StringKey) -> EffectResult<&str> {
+/// Something which resolves the strings in a document, given their keys.
+///
+/// Parsing with [`DocProcessor::parse_with`](crate::DocProcessor::parse_with) resolves
+/// every value component once, as it's parsed, through the given handler,
+/// falling back to a [`Printer`] if it isn't handled.
+/// Handlers can use this to watch what's parsed, or to check it, or to replace it:
+/// bytes a handler gives back other than what's written become the component's content,
+/// trimmed or with `""` collapsed as if they had been written there.
+///
+/// ```
+/// use wml::{DocProcessor, EffectResult, Effects, StringKey};
+/// use std::cell::Cell;
+///
+/// /// Counts strings, but leaves resolving them to the next handler.
+/// struct Counter(Cell<usize>);
+/// impl Effects for Counter {
+///     fn get_bytes(&self, _: StringKey) -> EffectResult<&[u8]> {
+///         self.0.set(self.0.get() + 1);
+///         EffectResult::Unhandled
+///     }
+/// }
+///
+/// let counter = Counter(Cell::new(0));
+/// let processor = DocProcessor::new();
+/// processor.parse_with(Vec::from("[a]\nx=\"1\" + \"2\"\ny=3\n[/a]\n"), &counter).unwrap();
+/// assert_eq!(counter.0.get(), 3);
+/// ```
+pub trait Effects {
+    /// Get the bytes `key` refers to.
+    fn get_bytes(&self, key: StringKey) -> EffectResult<&[u8]>;
+    /// Get the string `key` refers to, which is unhandled if it isn't valid UTF-8.
+    fn get_str(&self, key: StringKey) -> EffectResult<&str> {
         match self.get_bytes(key) {
             EffectResult::Handled(bytes) => {
                 match ::core::str::from_utf8(bytes) {
@@ -47,7 +149,8 @@ pub(crate) trait Effects {
             EffectResult::Unhandled => EffectResult::Unhandled,
         }
     }
-    fn or<'a, H: Effects>(&'a self, other: &'a H) -> FallbackHandler<'_, Self, H> where Self: Sized {
+    /// Try this handler, then `other` for whatever this one doesn't handle.
+    fn or<'a, H: Effects>(&'a self, other: &'a H) -> FallbackHandler<'a, Self, H> where Self: Sized {
         FallbackHandler {
             first: self,
             second: other,
@@ -56,7 +159,7 @@ pub(crate) trait Effects {
 }
 
 impl<A: Effects, B: Effects> Effects for FallbackHandler<'_, A, B> {
-    fn get_bytes(&self, key: super::StringKey) -> EffectResult<&[u8]> {
+    fn get_bytes(&self, key: StringKey) -> EffectResult<&[u8]> {
         match self.first.get_bytes(key) {
             EffectResult::Unhandled => self.second.get_bytes(key),
             x @ EffectResult::Handled(_) => x,
@@ -64,14 +167,123 @@ impl<A: Effects, B: Effects> Effects for FallbackHandler<'_, A, B> {
     }
 }
 
+impl Effects for StatsHandler {
+    fn get_bytes(&self, key: StringKey) -> EffectResult<&[u8]> {
+        let len = key.span().len();
+        let mut stats = self.stats.borrow_mut();
+        stats.strings += 1;
+        stats.bytes += len;
+        let bucket = (usize::BITS - len.leading_zeros()) as usize;
+        if stats.length_histogram.len() <= bucket {
+            stats.length_histogram.resize(bucket + 1, 0);
+        }
+        stats.length_histogram[bucket] += 1;
+        EffectResult::Unhandled
+    }
+}
+
 impl Effects for NullHandler {
-    fn get_bytes(&self, _: super::StringKey) -> EffectResult<&[u8]> {
+    fn get_bytes(&self, _: StringKey) -> EffectResult<&[u8]> {
         EffectResult::Unhandled
     }
 }
 
+impl<'a> Effects for StrPrinter<'a> {
+    fn get_bytes(&self, key: StringKey) -> EffectResult<&[u8]> {
+        match self.data.as_bytes().get(key.span()) {
+            Some(bytes) => EffectResult::Handled(bytes),
+            None => EffectResult::Unhandled,
+        }
+    }
+    fn get_str(&self, key: StringKey) -> EffectResult<&str> {
+        match self.data.get(key.span()) {
+            Some(s) => EffectResult::Handled(s),
+            None => EffectResult::Unhandled,
+        }
+    }
+}
+
 impl<'a> Effects for Printer<'a> {
-    fn get_bytes(&self, key: super::StringKey) -> EffectResult<&[u8]> {
-        EffectResult::Handled(&self.data[key.idx .. key.idx + key.len])
+    fn get_bytes(&self, key: StringKey) -> EffectResult<&[u8]> {
+        EffectResult::Handled(key.get(self.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EffectResult, Effects, ParseStats, StatsHandler};
+    use crate::{DocProcessor, NodeRef, ParseError, StringKey};
+    use crate::test_util::{first_tag, USERS};
+
+    #[test]
+    fn users_stats() {
+        let processor = DocProcessor::new();
+        let stats = StatsHandler::new();
+        let users = Vec::from(USERS);
+        processor.parse_with(users, &stats).unwrap();
+        assert_eq!(stats.report(), ParseStats {
+            strings: 16,
+            bytes: 2 * (3 + 1 + 1 + 2 + 2 + 5) + 3 + 4,
+            // Empty, 1, 2 to 3, and 4 to 7 bytes long.
+            length_histogram: vec![2, 4, 7, 3],
+        });
+    }
+
+    #[test]
+    fn validated_utf8() {
+        let processor = DocProcessor::new().with_utf8_validation();
+        let err = processor.parse(b"[user]\nname=\"l\xffl\"\n[/user]\n".to_vec()).unwrap_err();
+        assert_eq!(err, ParseError::InvalidUtf8 { offset: 14 });
+        // Without validation, it's just bytes.
+        DocProcessor::new().parse(b"[user]\nname=\"l\xffl\"\n[/user]\n".to_vec()).unwrap();
+
+        let source = "name=\"l\u{f6}l\"\n";
+        let doc = processor.parse(Vec::from(source)).unwrap();
+        assert!(doc.is_utf8());
+        match doc.children().next() {
+            Some(NodeRef::Attr(name)) => assert_eq!(name.value_str().as_deref(), Ok("l\u{f6}l")),
+            other => panic!("{:?}", other),
+        }
+        assert!(DocProcessor::new().parse(Vec::from(source)).unwrap().str_printer().is_none());
+        let printer = doc.str_printer().unwrap();
+        assert_eq!(printer.get_str(StringKey { idx: 6, len: 4 }), EffectResult::Handled("l\u{f6}l"));
+        assert_eq!(printer.get_str(StringKey { idx: 6, len: 2 }), EffectResult::Unhandled);
+        assert_eq!(printer.get_bytes(StringKey { idx: 6, len: 2 }), EffectResult::Handled(&b"l\xc3"[..]));
+        // A prefix cut off before a partial character is still all valid.
+        let (doc, rest) = processor.parse_prefix(b"a=1\nb=\"\xc3").unwrap();
+        assert_eq!((doc.is_utf8(), rest), (true, &b"b=\"\xc3"[..]));
+    }
+
+    #[test]
+    fn replaced_values() {
+        /// Replaces any value component that's exactly `secret` with the bytes it holds.
+        struct Censor<'a>(&'a [u8], &'static [u8]);
+        impl Effects for Censor<'_> {
+            fn get_bytes(&self, key: StringKey) -> EffectResult<&[u8]> {
+                match key.get(self.0) {
+                    b"secret" => EffectResult::Handled(self.1),
+                    _ => EffectResult::Unhandled,
+                }
+            }
+        }
+        let source = b"[user]\nname=\"lol\" + <<secret>>\npassword=\"secret\"\n[/user]\n";
+        let processor = DocProcessor::new().with_utf8_validation();
+        let doc = processor.parse_with(source.to_vec(), &Censor(source, b"x\"\"y")).unwrap();
+        let user = first_tag(&doc);
+        let value = |key| match user.get(key) {
+            Some(NodeRef::Attr(attr)) => attr.value().into_owned(),
+            other => panic!("{:?}", other),
+        };
+        // Replaced content is read the way what it replaced would be, so only the string's escape collapses.
+        assert_eq!(value("name"), b"lolx\"\"y");
+        assert_eq!(value("password"), b"x\"y");
+        let mut out = Vec::new();
+        doc.write_to(&mut out).unwrap();
+        assert_eq!(out, b"[user]\nname=\"lol\" + \"x\"\"\"\"y\"\npassword=\"x\"\"y\"\n[/user]\n");
+
+        let err = processor.parse_with(source.to_vec(), &Censor(source, b"\xff")).unwrap_err();
+        assert_eq!(err, ParseError::InvalidUtf8 { offset: 22 });
+        // Without validation, it's just bytes.
+        DocProcessor::new().parse_with(source.to_vec(), &Censor(source, b"\xff")).unwrap();
     }
 }
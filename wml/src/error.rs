@@ -0,0 +1,202 @@
+//! Reasons a WML document may be rejected.
+use ::core::fmt;
+use ::std::io;
+use ::core::ops::Range;
+
+/// Why [`DocProcessor::parse`](crate::DocProcessor::parse) rejected its input.
+///
+/// Offsets are byte offsets into the buffer that was parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseError {
+    /// The input starting at `offset` isn't valid WML.
+    TrailingInput { offset: usize },
+    /// The tag opened at `at` has more children than
+    /// [`DocProcessor::with_max_children_per_tag`](crate::DocProcessor::with_max_children_per_tag) allows.
+    TooManyChildren { at: usize },
+    /// The tag or attribute starting at `at` takes the document over the total number of
+    /// tags and attributes [`DocProcessor::with_max_nodes`](crate::DocProcessor::with_max_nodes) allows.
+    TooManyNodes { at: usize },
+    /// A closing tag was found at the top level, where there's no tag for it to close.
+    /// `name_span` is the location of its name.
+    UnexpectedClosingTag { name_span: Range<usize> },
+    /// Something other than what could come next was found at `offset`,
+    /// or the input ended there, if `found` is `None`.
+    UnexpectedByte { offset: usize, found: Option<u8> },
+    /// The quoted string opened at `offset` is never closed.
+    UnterminatedString { offset: usize },
+    /// The raw string opened by the `<<` at `offset` is never closed.
+    UnterminatedRawString { offset: usize },
+    /// The tag name or attribute key before `offset` is followed by `byte`, which can't be in a name,
+    /// as in `[na me]` or `key with space=x`.
+    InvalidNameChar { offset: usize, byte: u8 },
+    /// The tag named `open` was closed at `offset` with a closing tag named `close`.
+    MismatchedTag { open: String, close: String, offset: usize },
+    /// The tag opened at `at` is nested deeper than
+    /// [`DocProcessor::with_max_depth`](crate::DocProcessor::with_max_depth) allows.
+    DepthExceeded { at: usize },
+    /// The input isn't valid UTF-8, starting at `offset`.
+    /// Only checked with [`DocProcessor::with_utf8_validation`](crate::DocProcessor::with_utf8_validation).
+    InvalidUtf8 { offset: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TrailingInput { offset } => write!(f, "unparseable input at byte {}", offset),
+            Self::TooManyChildren { at } => write!(f, "too many children in tag at byte {}", at),
+            Self::TooManyNodes { at } => write!(f, "too many tags and attributes, at byte {}", at),
+            Self::UnexpectedClosingTag { name_span } => {
+                write!(f, "closing tag at byte {} has no matching opening tag", name_span.start - 2)
+            },
+            Self::UnexpectedByte { offset, found: Some(found) } => {
+                write!(f, "unexpected {:?} at byte {}", char::from(*found), offset)
+            },
+            Self::UnexpectedByte { offset, found: None } => write!(f, "unexpected end of input at byte {}", offset),
+            Self::InvalidNameChar { offset, byte } => {
+                write!(f, "name can't contain {:?}, at byte {}", char::from(*byte), offset)
+            },
+            Self::UnterminatedString { offset } => write!(f, "unterminated string starting at byte {}", offset),
+            Self::UnterminatedRawString { offset } => {
+                write!(f, "unterminated raw string starting at byte {}", offset)
+            },
+            Self::MismatchedTag { open, close, offset } => {
+                write!(f, "[{}] closed by [/{}] at byte {}", open, close, offset)
+            },
+            Self::DepthExceeded { at } => write!(f, "tag at byte {} is nested too deeply", at),
+            Self::InvalidUtf8 { offset } => write!(f, "invalid UTF-8 at byte {}", offset),
+        }
+    }
+}
+
+impl ::std::error::Error for ParseError {}
+
+impl ParseError {
+    /// The byte offset this error points at.
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::TrailingInput { offset } | Self::InvalidUtf8 { offset } => *offset,
+            Self::TooManyChildren { at } | Self::TooManyNodes { at } | Self::DepthExceeded { at } => *at,
+            Self::UnexpectedClosingTag { name_span } => name_span.start,
+            Self::UnexpectedByte { offset, .. }
+            | Self::InvalidNameChar { offset, .. }
+            | Self::UnterminatedString { offset }
+            | Self::UnterminatedRawString { offset }
+            | Self::MismatchedTag { offset, .. } => *offset,
+        }
+    }
+    /// Whether this is from going over one of the processor's limits,
+    /// rather than a problem with the input itself.
+    pub(crate) fn is_limit(&self) -> bool {
+        matches!(self, Self::TooManyChildren { .. } | Self::TooManyNodes { .. } | Self::DepthExceeded { .. })
+    }
+    /// The line and column, both starting at 1, of this error in `source`,
+    /// which must be the buffer that was parsed.
+    ///
+    /// Columns count bytes, so a tab is one column, as is each byte of a multibyte character.
+    /// See [`line_col_opts`](Self::line_col_opts) to count tabs the way an editor displays them.
+    ///
+    /// An error at the end of the input is located just past its last byte,
+    /// which is the start of a new line if the input ends in a newline.
+    pub fn location(&self, source: &[u8]) -> (usize, usize) {
+        self.line_col_opts(source, 1)
+    }
+    /// Like [`location`](Self::location), but with tabs advancing the column
+    /// to the next multiple of `tab_width`, so it matches an editor displaying
+    /// tabs at that width.
+    ///
+    /// # Panics
+    /// If `tab_width` is 0.
+    pub fn line_col_opts(&self, source: &[u8], tab_width: usize) -> (usize, usize) {
+        assert!(tab_width > 0, "tab width must be at least 1");
+        let before = &source[..self.offset().min(source.len())];
+        let line_start = before.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let line = before[..line_start].iter().filter(|&&b| b == b'\n').count() + 1;
+        let col = before[line_start..].iter().fold(0, |col, &b| match b {
+            b'\t' => (col / tab_width + 1) * tab_width,
+            _ => col + 1,
+        });
+        (line, col + 1)
+    }
+}
+
+/// Why [`DocProcessor::parse_reader`](crate::DocProcessor::parse_reader) failed.
+#[derive(Debug)]
+pub enum ReadError {
+    /// The input couldn't be read.
+    Io(io::Error),
+    /// The input was read, but isn't a valid document.
+    Parse(ParseError),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "read failure: {}", e),
+            Self::Parse(e) => e.fmt(f),
+        }
+    }
+}
+
+impl ::std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn ::std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Parse(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self { Self::Io(e) }
+}
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self { Self::Parse(e) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DocProcessor, ReadError};
+    use ::std::io::{self, Cursor, Read};
+
+    #[test]
+    fn columns_with_tabs() {
+        let processor = DocProcessor::new().with_max_children_per_tag(1);
+        let source = b"[a]\n\t\t[b]\n\t\t\tx=1\n\t\t\ty=2\n\t\t[/b]\n[/a]\n";
+        let err = processor.parse(source.to_vec()).unwrap_err();
+        assert_eq!(err.offset(), 6);
+        assert_eq!(err.location(source), (2, 3));
+        assert_eq!(err.line_col_opts(source, 1), (2, 3));
+        assert_eq!(err.line_col_opts(source, 4), (2, 9));
+    }
+
+    #[test]
+    fn unterminated_string_location() {
+        let processor = DocProcessor::new();
+        let source = b"[multiplayer]\nscenario=\"2p_Caves\"\nname=\"game\n[/multiplayer]\n";
+        let err = processor.parse(source.to_vec()).unwrap_err();
+        assert_eq!(err.location(source), (3, 6));
+        let source = b"[multiplayer]\n";
+        let err = processor.parse(source.to_vec()).unwrap_err();
+        assert_eq!(err.location(source), (2, 1));
+    }
+
+    #[test]
+    fn parse_reader() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse_reader(Cursor::new(b"[version]\nversion=\"1.16\"\n[/version]\n")).unwrap();
+        assert_eq!(doc.len(), 1);
+        let err = processor.parse_reader(Cursor::new(b"[version]\n")).unwrap_err();
+        assert!(matches!(err, ReadError::Parse(_)), "{:?}", err);
+
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+        }
+        let err = processor.parse_reader(Broken).unwrap_err();
+        assert!(matches!(err, ReadError::Io(_)), "{:?}", err);
+        assert_eq!(err.to_string(), "read failure: broken");
+    }
+}
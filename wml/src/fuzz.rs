@@ -0,0 +1,36 @@
+//! Entry points into the pieces of the parser, for the fuzz targets in `fuzz/`,
+//! which can't reach them otherwise.
+//!
+//! Each one parses the start of `input` and panics if what it got doesn't fit inside it.
+use crate::{collapse_quotes, NullHandler, RawString, TextDomain, WString};
+
+/// Parse a quoted string, and resolve its escapes.
+pub fn wstring(input: &[u8]) {
+    if let Ok((rest, string)) = WString::parse(input, 0) {
+        check_rest(input, rest);
+        let content = string.content.get(input);
+        assert!(collapse_quotes(content).len() <= content.len());
+    }
+}
+
+/// Parse a raw string.
+pub fn raw_string(input: &[u8]) {
+    if let Ok((rest, string)) = RawString::parse(input, 0) {
+        check_rest(input, rest);
+        assert!(!string.content.get(input).windows(2).any(|w| w == b">>"));
+    }
+}
+
+/// Parse a textdomain directive.
+pub fn textdomain(input: &[u8]) {
+    if let Ok((rest, domain)) = TextDomain::parse(&NullHandler, input, 0) {
+        check_rest(input, rest);
+        assert!(!domain.name.get(input).is_empty());
+    }
+}
+
+/// Check that `rest` is what's left of `input` after something was parsed from its start.
+fn check_rest(input: &[u8], rest: &[u8]) {
+    assert!(rest.len() < input.len());
+    assert_eq!(rest.as_ptr(), input[input.len() - rest.len()..].as_ptr());
+}
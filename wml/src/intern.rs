@@ -0,0 +1,49 @@
+//! Interning tag names and attribute keys, so comparing them is comparing integers.
+use ::core::cell::RefCell;
+use ::std::collections::HashMap;
+
+/// A tag name or attribute key, interned by a [`DocProcessor`](crate::DocProcessor)
+/// built [`with_interning`](crate::DocProcessor::with_interning).
+///
+/// Ids are only meaningful to the processor which gave them out, but it gives
+/// the same name the same id in every document it parses, even after a
+/// [`reset`](crate::DocProcessor::reset).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NameId(u32);
+
+/// Every name seen so far, and their ids.
+#[derive(Debug, Default)]
+pub(crate) struct Interner {
+    names: RefCell<Names>,
+}
+
+#[derive(Debug, Default)]
+struct Names {
+    ids: HashMap<Box<[u8]>, NameId>,
+    /// Indexed by id.
+    by_id: Vec<Box<[u8]>>,
+}
+
+impl Interner {
+    /// Get the id for `name`, giving it a new one if it hasn't been seen before.
+    pub(crate) fn intern(&self, name: &[u8]) -> NameId {
+        let mut names = self.names.borrow_mut();
+        if let Some(&id) = names.ids.get(name) {
+            return id
+        }
+        let id = NameId(u32::try_from(names.by_id.len()).expect("too many distinct names to intern"));
+        names.by_id.push(name.into());
+        names.ids.insert(name.into(), id);
+        id
+    }
+    /// Get the name `id` was given for.
+    pub(crate) fn resolve(&self, id: NameId) -> Option<String> {
+        let names = self.names.borrow();
+        // Names are always ASCII.
+        names.by_id.get(id.0 as usize).map(|name| String::from_utf8_lossy(name).into_owned())
+    }
+    /// How many distinct names have been interned.
+    pub(crate) fn len(&self) -> usize {
+        self.names.borrow().by_id.len()
+    }
+}
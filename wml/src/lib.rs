@@ -1,23 +1,28 @@
 //! # Wesnoth Markup Language (WML) parsing and serialization.
 //! This library adopts a somewhat similar approach to the `simple_wml`
 //! library used in the official Wesnoth multiplayer server.
-//! The tree representation is currently read-only.
+//! The tree representation is currently read-only, except that attribute values
+//! may be replaced for dumping with [`Doc::set_attr_value`].
 //!
 //! # WML Grammar
 //! See <https://wiki.wesnoth.org/GrammarWML> for a fuller explanation of the WML grammar.
 //! ```text
-//! wml_doc := (wml_tag | wml_attribute)*
-//! wml_tag := '[' wml_name ']' wml_doc '[/' wml_name ']'
+//! wml_doc := (wml_tag | wml_attribute | comment | textdomain | blank)*
+//! wml_tag := '[' '+'? wml_name ']' wml_doc '[/' wml_name ']'
 //! wml_name := [a-zA-Z0-9_]+
-//! wml_attribute := textdomain? wml_key_sequence '=' wml_value «nl»
+//! wml_attribute := textdomain? wml_key_sequence [ \t]* '=' [ \t]* wml_value [ \t]* («nl» | &closing_tag | EOF)
 //! wml_key_sequence := wml_name (',' wml_name)*
 //! wml_value := wml_value_component ('+' («nl» textdomain?)? wml_value_component)*
 //! wml_value_component := text | '_'? string | '_'? raw_string
 //!
-//! text := [^+«nl»]*
+//! text := ([^+«nl»[] | '[' !closing_tag)*
+//! closing_tag := '[/' wml_name ']'
 //! string := '"' ([^"] | '""')* '"'
 //! raw_string := '<<' ([^>] | >[^>])* '>>'
-//! textdomain = '#textdomain' [a-zA-Z0-9_-]+ «nl»
+//! textdomain = '#textdomain' [a-zA-Z0-9_-]+ («nl» | EOF)
+//! comment := [ \t]* '#' [^«nl»]* «nl»   (except for textdomain)
+//! blank := [ \t\r] | «nl»
+//! «nl» := '\r'? '\n'
 //! ```
 //!
 //! # Use
@@ -26,19 +31,120 @@
 //! As the available documentation on WML syntax is not fully unambiguous, it is plausible
 //! that the Wesnoth client accepts a wider range of inputs than does this parser.
 //!
-//! Additionally, this parser is not hardened against inputs crafted to cause stack overflows.
+//! Parsing recurses for every level of tag nesting, bounded by
+//! [`DocProcessor::with_max_depth`], so keep that limit within what the stack can hold,
+//! or parse without recursion using [`DocProcessor::with_iterative_parsing`].
+//! With the `stacker` feature enabled, the stack is grown as needed instead,
+//! so the limit may be raised as far as memory allows.
+//!
+//! With the `serde` feature enabled, documents may be read into Rust types with `from_doc`,
+//! and written from them with `to_vec`.
+mod builder;
+#[cfg(feature = "serde")]
+mod de;
+mod edit;
 mod effects;
+mod error;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+mod intern;
+mod merge;
+mod pool;
+#[cfg(feature = "serde")]
+mod ser;
+mod serialize;
+mod simple_wml;
+mod tokens;
+mod tree;
+#[cfg(test)]
+mod test_util;
+mod visit;
 
 use ::bumpalo::Bump;
-use effects::Effects;
+use ::core::cell::Cell;
+use ::core::ops::Range;
+use ::std::borrow::Cow;
+use ::std::io::Read;
+use intern::Interner;
+pub use builder::DocBuilder;
+#[cfg(feature = "serde")]
+pub use de::{from_doc, from_tag, DeserializeError, Deserializer};
+pub use effects::{EffectResult, Effects, FallbackHandler, NullHandler, ParseStats, Printer, StatsHandler, StrPrinter};
+pub use error::{ParseError, ReadError};
+pub use intern::NameId;
+pub use pool::{ArenaPool, PooledDoc};
+#[cfg(feature = "serde")]
+pub use ser::{to_vec, SerializeError};
+pub use tokens::{tokenize_line, LineState, Token, TokenKind};
+pub use tree::{AttrRef, ComponentRef, NodeRef, TagRef};
+pub use visit::Visitor;
 
 mod bump {
-    pub use ::bumpalo::boxed::Box;
     pub use ::bumpalo::collections::Vec;
 }
 
 type PResult<'a, T, E, I = &'a [u8]> = Result<(I, T), E>;
 
+/// Why a parser gave up, in the style of `nom`'s `Error`/`Failure` split.
+#[derive(Debug)]
+enum Failure {
+    /// The input doesn't match, but an alternative may, or the caller may stop here.
+    Mismatch,
+    /// Parsing can't continue at all.
+    Fatal(ParseError),
+}
+impl From<()> for Failure {
+    fn from(_: ()) -> Self { Self::Mismatch }
+}
+
+/// Resource limits applied while parsing a document.
+#[derive(Debug, Copy, Clone)]
+struct Limits {
+    max_children_per_tag: usize,
+    max_depth: usize,
+    max_nodes: usize,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_children_per_tag: 1 << 16,
+            max_depth: 512,
+            max_nodes: 1 << 20,
+        }
+    }
+}
+
+/// Everything parsing needs besides the input, passed down through every tag.
+#[derive(Debug, Copy, Clone)]
+struct Context<'p> {
+    limits: &'p Limits,
+    /// Where names are interned, if they are.
+    interner: Option<&'p Interner>,
+    /// How many tags and attributes have been parsed so far.
+    nodes: &'p Cell<usize>,
+    /// Whether `[/]` closes whatever tag is open.
+    implicit_close: bool,
+    /// Whether the input may go on past where it ends, as for [`DocProcessor::parse_prefix`],
+    /// so reaching the end doesn't finish an attribute.
+    partial: bool,
+    /// The last `#textdomain` directive between nodes, waiting for the next attribute.
+    domain: &'p Cell<Option<TextDomain>>,
+    /// Whether the document is being checked for valid UTF-8,
+    /// so whatever handlers replace values with must be too.
+    utf8: bool,
+}
+impl Context<'_> {
+    /// Count a tag or attribute starting at `at`, checking it doesn't go over the limit.
+    fn count_node(&self, at: usize) -> Result<(), ParseError> {
+        if self.nodes.get() == self.limits.max_nodes {
+            return Err(ParseError::TooManyNodes { at })
+        }
+        self.nodes.set(self.nodes.get() + 1);
+        Ok(())
+    }
+}
+
 fn tagged<'a>(tag: &[u8], input: &'a [u8]) -> Result<&'a [u8], ()> {
     if input.starts_with(tag) {
         Ok(&input[tag.len()..])
@@ -47,71 +153,122 @@ fn tagged<'a>(tag: &[u8], input: &'a [u8]) -> Result<&'a [u8], ()> {
     }
 }
 
+/// Check whether `input` starts with `[/name]`, for any name.
+fn closing_tag_ahead(input: &[u8]) -> bool {
+    tagged(b"[/", input).ok()
+        .and_then(|rest| Name::parse(rest, 0).ok())
+        .is_some_and(|(rest, _)| rest.starts_with(b"]"))
+}
+
 // TODO: add more error types, and corresponding messages
 
 #[derive(Debug)]
 struct NoWhitespace;
 impl From<NoWhitespace> for () {
-    fn from(_: NoWhitespace) -> Self {
-        ()
-    }
+    fn from(_: NoWhitespace) -> Self {}
 }
 
-// TODO: use this more, presumably?
-/// Consume whitespace however the official WML tokenizer would.
-fn whitespace(input: &[u8]) -> Result<&[u8], NoWhitespace> {
+/// Skip whitespace within a line, spaces and tabs.
+///
+/// A carriage return isn't skipped, so a line can't end with one on its own.
+fn inline_ws(input: &[u8]) -> &[u8] {
     let mut cursor = input;
     while let [b' ' | b'\t', rest @ ..] = cursor {
         cursor = rest;
     }
-    if cursor.as_ptr() as usize - input.as_ptr() as usize > 0 {
-        Ok(cursor)
+    cursor
+}
+
+/// Consume whitespace within a line, requiring at least some.
+fn whitespace(input: &[u8]) -> Result<&[u8], NoWhitespace> {
+    let rest = inline_ws(input);
+    if rest.len() < input.len() {
+        Ok(rest)
     } else {
         Err(NoWhitespace)
     }
 }
 
-struct Or<A, B> {
-    a: A,
-    b: B,
+/// Consume a line comment: a line starting with `#`, after any indentation,
+/// which isn't a `#textdomain` directive.
+fn comment(input: &[u8]) -> Result<&[u8], ()> {
+    let rest = inline_ws(input);
+    if !rest.starts_with(b"#") || rest.starts_with(b"#textdomain") {
+        return Err(())
+    }
+    // The comment runs through the end of the line, or the document.
+    let len = rest.iter().position(|&b| b == b'\n').map_or(rest.len(), |i| i + 1);
+    Ok(&rest[len..])
 }
 
-trait StripPredicate {
-    fn count(&self, input: &[u8]) -> usize;
-    fn or<P: StripPredicate>(self, other: P) -> Or<Self, P> where Self: Sized {
-        Or { a: self, b: other }
+/// Skip any mix of line endings, whitespace within lines and carriage returns,
+/// which is `blank*` in the WML grammar, and covers blank and indented lines.
+fn blank(input: &[u8]) -> &[u8] {
+    let mut cursor = input;
+    loop {
+        let rest = inline_ws(cursor);
+        match line_sep(rest) {
+            Ok(rest) => cursor = rest,
+            Err(()) => match rest {
+                [b'\r', rest @ ..] => cursor = rest,
+                _ => break rest,
+            },
+        }
     }
 }
-impl<A: StripPredicate, B: StripPredicate> StripPredicate for Or<A, B> {
-    fn count(&self, input: &[u8]) -> usize {
-        let count = self.a.count(input);
-        if count > 0 { count } else { self.b.count(input) }
+
+/// Skip everything that may come between nodes: blank lines, indentation and comments.
+fn between_nodes(input: &[u8]) -> &[u8] {
+    let mut cursor = input;
+    loop {
+        let rest = blank(cursor);
+        match comment(rest) {
+            Ok(rest) => cursor = rest,
+            Err(()) => break rest,
+        }
     }
 }
-impl StripPredicate for &[u8] {
-    fn count(&self, input: &[u8]) -> usize {
-        if input.starts_with(self) { self.len() } else { 0 }
+
+/// Like [`between_nodes`], but also consuming `#textdomain` directives,
+/// the last of which is kept in `cx` for the next attribute to take.
+/// One with no attribute left after it to apply to is ignored, like a comment.
+fn between_nodes_in<'b>(cx: &Context<'_>, input: &'b [u8], offset: usize) -> &'b [u8] {
+    let mut cursor = input;
+    loop {
+        let rest = between_nodes(cursor);
+        let at = rest.as_ptr() as usize - input.as_ptr() as usize + offset;
+        match TextDomain::parse(&NullHandler, rest, at) {
+            // A directive cut off by the end of partial input may go on past it.
+            Ok((after, domain)) if !(after.is_empty() && cx.partial) => {
+                cx.domain.set(Some(domain));
+                cursor = after;
+            },
+            _ => break rest,
+        }
     }
 }
-impl<const N: usize> StripPredicate for &[u8; N] {
-    fn count(&self, input: &[u8]) -> usize {
-        if input.starts_with(*self) { self.len() } else { 0 }
-    }
+
+/// Fail fatally, because `input` doesn't start with what it should.
+fn unexpected(input: &[u8], offset: usize) -> Failure {
+    Failure::Fatal(ParseError::UnexpectedByte { offset, found: input.first().copied() })
 }
 
-fn tagged_many0<'a, P: StripPredicate>(tag: P, input: &'a [u8]) -> &'a [u8] {
-    let mut cursor = input;
-    loop {
-        let count = tag.count(cursor);
-        if count > 0 {
-            cursor = &cursor[count..];
-        } else { break cursor }
+/// Fail fatally, because the name before `offset` is followed by `byte`, which can't be in one.
+fn invalid_name_char(byte: u8, offset: usize) -> Failure {
+    Failure::Fatal(ParseError::InvalidNameChar { offset, byte })
+}
+
+/// Consume a single line ending, `\n` or `\r\n`, which is `«nl»` in the WML grammar.
+fn line_sep(input: &[u8]) -> Result<&[u8], ()> {
+    match input {
+        [b'\n', rest @ ..] | [b'\r', b'\n', rest @ ..] => Ok(rest),
+        _ => Err(()),
     }
 }
 
-/// Key for retrieving a slice of bytes for a string.
-#[derive(Debug, Copy, Clone)]
-struct StringKey {
+/// Key for retrieving a slice of bytes for a string, out of the buffer a document was parsed from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StringKey {
     // I'll add a layer of indirection if I make changes that make indices unstable,
     // like allowing mutation.
     // It may make sense to intern tag names, too.
@@ -121,12 +278,21 @@ struct StringKey {
     /// The length of the string.
     len: usize,
 }
+impl StringKey {
+    /// Get the string this key refers to, out of the buffer it came from.
+    pub fn get(self, text: &[u8]) -> &[u8] {
+        &text[self.span()]
+    }
+    /// Where the string this key refers to is in the buffer it came from.
+    pub fn span(self) -> Range<usize> {
+        self.idx .. self.idx + self.len
+    }
+}
 
-// TODO: consider storing more span information,
-// so we can do the strategy `simple_wml` does with coloring
-// mutated parts of the tree and adjusting only those
-// when dumping output with our stored buffer.
-// I'm not sure that would be a gain on small documents, though.
+// TODO: `Doc::set_attr_value` does the strategy `simple_wml` does with coloring
+// mutated parts of the tree and adjusting only those when dumping output
+// with our stored buffer, but only for attribute values.
+// Adding and removing nodes would need more span information than we store.
 /// `(wml_tag | wml_attribute)` in the WML grammar.
 #[derive(Debug)]
 enum TagOrAttr<'a> {
@@ -134,75 +300,147 @@ enum TagOrAttr<'a> {
     Attr(Attribute<'a>),
 }
 impl<'a> TagOrAttr<'a> {
-    fn parse<'b, E: Effects>(e: &E, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, ()> {
+    /// Parse a tag or attribute inside `depth` enclosing tags.
+    fn parse<'b, E: Effects>(e: &E, cx: &Context<'_>, depth: usize, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, Failure> {
         // Right here, `Tag::parse` may recurse.
-        Tag::parse(e, arena, input, offset)
-            .map(|(rest, tag)| (rest, Self::Tag(tag)))
-            .or_else(|()| {
-                Attribute::parse(e, arena, input, offset).map(|(rest, attr)| (rest, Self::Attr(attr)))
-            })
+        #[cfg(feature = "stacker")]
+        let tag = ::stacker::maybe_grow(32 * 1024, 1024 * 1024, || Tag::parse(e, cx, depth, arena, input, offset));
+        #[cfg(not(feature = "stacker"))]
+        let tag = Tag::parse(e, cx, depth, arena, input, offset);
+        match tag {
+            Ok((rest, tag)) => Ok((rest, Self::Tag(tag))),
+            Err(Failure::Mismatch) => Self::parse_attr(e, cx, arena, input, offset),
+            Err(fatal @ Failure::Fatal(_)) => Err(fatal),
+        }
+    }
+    // Everything an attribute needs is only on the stack while parsing one,
+    // not in the frame of each nested `Tag::parse` on the way down.
+    #[inline(never)]
+    fn parse_attr<'b, E: Effects>(e: &E, cx: &Context<'_>, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, Failure> {
+        let (rest, attr) = Attribute::parse(e, cx, arena, input, offset)?;
+        Ok((rest, Self::Attr(attr)))
     }
 }
 
 /// `wml_tag` in the WML grammar.
 ///
 /// ```text
-/// wml_tag := '[' wml_name ']' wml_doc '[/' wml_name ']'
+/// wml_tag := '[' '+'? wml_name ']' wml_doc '[/' wml_name ']'
 /// ```
+///
+/// A `+` before the name makes it a merge tag, which amends the last tag before it with that name.
+/// See [`DocProcessor::merge`].
+/// With [`DocProcessor::with_implicit_close`], the closing tag may also be `[/]`.
 #[derive(Debug)]
 struct Tag<'a> {
-    // TODO: consider giving Name its own type,
-    // and doing string interning.
     // Whether string interning is a gain for us depends
     // on the spread of kinds of thing we do with WML, which I
-    // haven't figured out yet.
+    // haven't figured out yet, so it's opt-in.
     name: Name,
+    /// Whether this was written `[+name]`.
+    merge: bool,
     content: bump::Vec<'a, TagOrAttr<'a>>,
 }
 // Note: `Tag`, and *only* `Tag`, is recursive.
 // Alternatively, `TagOrAttr` could possibly handle the recursion?
 impl<'a> Tag<'a> {
-    fn parse<'b, E: Effects>(e: &E, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, ()> {
+    /// Parse a tag inside `depth` enclosing tags.
+    fn parse<'b, E: Effects>(e: &E, cx: &Context<'_>, depth: usize, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, Failure> {
         let offset = |slc: &[u8]| slc.as_ptr() as usize - input.as_ptr() as usize + offset;
-        let rest = tagged(b"[", input)?;
-        let (rest, name) = Name::parse(rest, offset(rest))?;
-        let rest = tagged(b"]", rest)?;
-        let rest = tagged_many0(b"\n".or(b"\t"), rest);
-        // TODO: parse without recursing, or otherwise prevent stack overflows
-        // (consider using `stacker` to be lazy)
+        let (rest, (name, merge)) = Self::open(cx, depth, input, offset(input))?;
         let mut cursor = rest;
-        // TODO: this *would* benefit from using `with_capacity_in`
+        // Until a nested tag is parsed, this is the last thing allocated in the arena,
+        // so growing it doesn't move it. Tags holding other tags still leave
+        // their old buffers behind, which a heap allocated stack of content could fix,
+        // but that costs more time than it saves memory for the messages we see.
         let mut content = bump::Vec::<TagOrAttr>::new_in(arena);
         loop {
-            cursor = tagged_many0(b"\n".or(b"\t"), cursor);
+            cursor = between_nodes_in(cx, cursor, offset(cursor));
             // Every single tag or attribute in here is optional.
-            match TagOrAttr::parse(e, arena, cursor, offset(cursor)) {
+            match TagOrAttr::parse(e, cx, depth + 1, arena, cursor, offset(cursor)) {
                 Ok((rest, tag_or_attr)) => {
+                    if content.len() == cx.limits.max_children_per_tag {
+                        return Err(Failure::Fatal(ParseError::TooManyChildren { at: offset(input) }))
+                    }
+                    cx.count_node(offset(cursor)).map_err(Failure::Fatal)?;
                     content.push(tag_or_attr);
                     cursor = rest;
                 },
-                Err(()) => break,
+                Err(Failure::Mismatch) => break,
+                Err(fatal @ Failure::Fatal(_)) => return Err(fatal),
             }
         }
-        let rest = tagged(b"[/", cursor)?;
-        let (rest, name_again) = Name::parse(rest, offset(rest))?;
-        // How do we want to perform string equality checks?
-        // We can just index into `input`, of course.
-        let name_base = name.content.idx - offset(input);
-        let name_c = &input[name_base .. name_base + name.content.len];
-        let name_again_base = name_again.content.idx - offset(input);
-        let name_again = &input[name_again_base .. name_again_base + name_again.content.len];
-        if name_c != name_again { return Err(()) }
-        let rest = tagged(b"]", rest)?;
-        let rest = tagged_many0(b"\n".or(b"\t"), rest);
-        Ok((rest, Self { name, content }))
+        let rest = Self::close(cx, &name, input, offset(input), cursor, offset(cursor))?;
+        Ok((rest, Self { name, merge, content }))
+    }
+    /// Parse the opening tag of a tag inside `depth` enclosing tags,
+    /// along with whether it's a merge tag.
+    fn open<'b>(cx: &Context<'_>, depth: usize, input: &'b [u8], offset: usize) -> PResult<'b, (Name, bool), Failure> {
+        let offset = |slc: &[u8]| slc.as_ptr() as usize - input.as_ptr() as usize + offset;
+        let rest = tagged(b"[", input)?;
+        let (rest, merge) = match tagged(b"+", rest) {
+            Ok(rest) => (rest, true),
+            Err(()) => (rest, false),
+        };
+        let (rest, name) = Name::parse_interned(cx, rest, offset(rest))?;
+        // Nothing else starts with `[name`, so anything but `]` here is a bad name.
+        let rest = match rest {
+            [b']', rest @ ..] => rest,
+            [byte, ..] => return Err(invalid_name_char(*byte, offset(rest))),
+            [] => return Err(Failure::Mismatch),
+        };
+        // This is what keeps recursion from overflowing the stack.
+        if depth >= cx.limits.max_depth {
+            return Err(Failure::Fatal(ParseError::DepthExceeded { at: offset(input) }))
+        }
+        Ok((between_nodes(rest), (name, merge)))
+    }
+    /// Parse the closing tag for `name`, where `text` is the input at `text_offset`,
+    /// which `name` refers into.
+    // Kept out of `parse`, which recurses, so comparing names and building
+    // the mismatch error doesn't add to every level's stack frame.
+    #[inline(never)]
+    fn close<'b>(cx: &Context<'_>, name: &Name, text: &[u8], text_offset: usize, input: &'b [u8], offset: usize) -> Result<&'b [u8], Failure> {
+        let offset = |slc: &[u8]| slc.as_ptr() as usize - input.as_ptr() as usize + offset;
+        // Nothing else starts with `[name]`, so from here on, failures are fatal.
+        let rest = tagged(b"[/", input).map_err(|()| unexpected(input, offset(input)))?;
+        if cx.implicit_close {
+            if let Ok(rest) = tagged(b"]", rest) {
+                return Ok(between_nodes(rest))
+            }
+        }
+        let (rest, name_again) = Name::parse_interned(cx, rest, offset(rest)).map_err(|_| unexpected(rest, offset(rest)))?;
+        match rest {
+            [b']', ..] | [] => (),
+            [byte, ..] => return Err(invalid_name_char(*byte, offset(rest))),
+        }
+        let name_base = name.content.idx - text_offset;
+        let name_c = &text[name_base .. name_base + name.content.len];
+        let name_again_base = name_again.content.idx - text_offset;
+        let name_again_c = &text[name_again_base .. name_again_base + name_again.content.len];
+        let same = match (name.id, name_again.id) {
+            (Some(id), Some(id_again)) => id == id_again,
+            _ => name_c == name_again_c,
+        };
+        if !same {
+            return Err(Failure::Fatal(ParseError::MismatchedTag {
+                open: String::from_utf8_lossy(name_c).into_owned(),
+                close: String::from_utf8_lossy(name_again_c).into_owned(),
+                offset: offset(input),
+            }))
+        }
+        let rest = tagged(b"]", rest).map_err(|()| unexpected(rest, offset(rest)))?;
+        Ok(between_nodes(rest))
     }
 }
 
 #[derive(Debug)]
 struct EmptyName;
 impl From<EmptyName> for () {
-    fn from(_: EmptyName) -> Self { () }
+    fn from(_: EmptyName) -> Self {}
+}
+impl From<EmptyName> for Failure {
+    fn from(_: EmptyName) -> Self { Self::Mismatch }
 }
 
 /// `wml_name` in the WML grammar.
@@ -213,9 +451,11 @@ impl From<EmptyName> for () {
 #[derive(Debug)]
 struct Name {
     content: StringKey,
+    /// Set when parsing with an interner.
+    id: Option<NameId>,
 }
 impl Name {
-    fn parse(input: &[u8], offset: usize) -> PResult<Name, EmptyName> {
+    fn parse(input: &[u8], offset: usize) -> PResult<'_, Name, EmptyName> {
         let mut cursor = input;
         while let [b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_', rest @ ..] = cursor {
             cursor = rest;
@@ -225,18 +465,26 @@ impl Name {
             let name = Name { content: StringKey {
                 len: name_len,
                 idx: offset,
-            }};
+            }, id: None };
             Ok((cursor, name))
         } else {
             Err(EmptyName)
         }
     }
+    /// Parse a name, interning it if `cx` has an interner.
+    fn parse_interned<'b>(cx: &Context<'_>, input: &'b [u8], offset: usize) -> PResult<'b, Name, EmptyName> {
+        let (rest, mut name) = Self::parse(input, offset)?;
+        if let Some(interner) = cx.interner {
+            name.id = Some(interner.intern(&input[..name.content.len]));
+        }
+        Ok((rest, name))
+    }
 }
 
 /// `wml_attribute` in the WML grammar.
 ///
 /// ```text
-/// wml_attribute := textdomain? wml_key_sequence '=' wml_value «nl»
+/// wml_attribute := textdomain? wml_key_sequence [ \t]* '=' [ \t]* wml_value [ \t]* («nl» | &closing_tag | EOF)
 /// ```
 #[derive(Debug)]
 struct Attribute<'a> {
@@ -245,16 +493,35 @@ struct Attribute<'a> {
     value: Value<'a>,
 }
 impl<'a> Attribute<'a> {
-    fn parse<'b, E: Effects>(e: &E, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, ()> {
+    fn parse<'b, E: Effects>(e: &E, cx: &Context<'_>, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, Failure> {
         let (rest, domain) = TextDomain::parse(e, input, offset)
             .map(|(rest, domain)| (rest, Some(domain)))
             .unwrap_or_else(|()| (input, None));
         let offset = |slc: &[u8]| slc.as_ptr() as usize - input.as_ptr() as usize + offset;
-        let rest = tagged_many0(b" ".or(b"\t"), rest);
-        let (rest, key_sequence) = KeySequence::parse(arena, rest, offset(rest))?;
-        let rest = tagged(b"=", rest)?;
-        let (rest, value) = Value::parse(e, arena, rest, offset(rest))?;
-        let rest = tagged(b"\n", rest)?;
+        let rest = inline_ws(rest);
+        let (rest, key_sequence) = KeySequence::parse(cx, arena, rest, offset(rest))?;
+        let after_key = rest;
+        // Names end before any whitespace, so it's only ever skipped here.
+        let rest = inline_ws(rest);
+        let rest = tagged(b"=", rest).map_err(|()| match after_key {
+            // A line which is just a name isn't an attribute.
+            [] | [b'\n' | b'\r', ..] => Failure::Mismatch,
+            [byte, ..] => invalid_name_char(*byte, offset(after_key)),
+        })?;
+        let rest = inline_ws(rest);
+        // Nothing else starts with `key=`, so from here on, failures are fatal.
+        let (rest, value) = Value::parse(e, cx, arena, rest, offset(rest))?;
+        let rest = inline_ws(rest);
+        // A closing tag may follow on the same line, but is left for the tag to consume.
+        // The last attribute needn't end its line, as in messages framed without a final newline.
+        let rest = match line_sep(rest) {
+            Ok(rest) => rest,
+            Err(()) if closing_tag_ahead(rest) || (cx.implicit_close && rest.starts_with(b"[/]")) => rest,
+            Err(()) if rest.is_empty() && !cx.partial => rest,
+            Err(()) => return Err(unexpected(rest, offset(rest))),
+        };
+        // Otherwise, a directive further back applies, if there's one not taken yet.
+        let domain = domain.or_else(|| cx.domain.take());
         Ok((rest, Self { domain, key_sequence, value }))
     }
 }
@@ -270,20 +537,15 @@ struct KeySequence<'a> {
     names: bump::Vec<'a, Name>,
 }
 impl<'a> KeySequence<'a> {
-    fn parse<'b>(arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, ()> {
-        let (rest, first) = Name::parse(input, offset)?;
+    fn parse<'b>(cx: &Context<'_>, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, ()> {
+        let (rest, first) = Name::parse_interned(cx, input, offset)?;
         let offset = |slc: &[u8]| slc.as_ptr() as usize - input.as_ptr() as usize + offset;
         let mut cursor = rest;
         let mut names = bump::Vec::new_in(arena);
-        loop {
-            match tagged(b",", cursor) {
-                Ok(rest) => {
-                    let (rest, name) = Name::parse(rest, offset(rest))?;
-                    names.push(name);
-                    cursor = rest;
-                },
-                Err(()) => break,
-            }
+        while let Ok(rest) = tagged(b",", cursor) {
+            let (rest, name) = Name::parse_interned(cx, rest, offset(rest))?;
+            names.push(name);
+            cursor = rest;
         }
         Ok((cursor, Self { first, names }))
     }
@@ -296,39 +558,35 @@ impl<'a> KeySequence<'a> {
 /// ```
 #[derive(Debug)]
 struct Value<'a> {
-    first: ValueComponent,
-    rest: bump::Vec<'a, (Option<TextDomain>, ValueComponent)>,
+    first: ValueComponent<'a>,
+    rest: bump::Vec<'a, (Option<TextDomain>, ValueComponent<'a>)>,
 }
 impl<'a> Value<'a> {
-    fn parse<'b, E: Effects>(e: &E, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, ()> {
-        let (rest, first) = ValueComponent::parse(e, input, offset)?;
+    fn parse<'b, E: Effects>(e: &E, cx: &Context<'_>, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, Failure> {
+        let (rest, first) = ValueComponent::parse(e, cx, arena, input, offset)?;
         let offset = |slc: &[u8]| slc.as_ptr() as usize - input.as_ptr() as usize + offset;
         let mut cursor = rest;
-        // Note: Since `Value` has no children that use the arena allocator,
-        // we don't need to worry about fragmentation here, regardless of whether
-        // we appropriately do `with_capacity_in` or not.
+        // Note: Only components a handler replaced allocate in the arena,
+        // so unless one does, we don't need to worry about fragmentation here,
+        // regardless of whether we appropriately do `with_capacity_in` or not.
         let mut vec = bump::Vec::new_in(arena);
         loop {
-            let rest = tagged_many0(b" ".or(b"\n").or(b"\t"), cursor);
+            let rest = blank(cursor);
             match tagged(b"+", rest) {
                 Ok(rest) => {
-                    let (rest, domain) = match tagged_many0(b"\n".or(b" ").or(b"\t"), rest) {
-                        rest => {
-                            // Check for textdomain, which is still optional at this point
-                            match TextDomain::parse(e, rest, offset(rest)) {
-                                Ok((rest, domain)) => {
-                                    (rest, Some(domain))
-                                },
-                                Err(()) => {
-                                    (rest, None)
-                                },
-                            }
+                    let rest = blank(rest);
+                    // Check for textdomain, which is still optional at this point
+                    let (rest, domain) = match TextDomain::parse(e, rest, offset(rest)) {
+                        Ok((rest, domain)) => {
+                            (rest, Some(domain))
+                        },
+                        Err(()) => {
+                            (rest, None)
                         },
-                        // Err(()) => (rest, None),
                     };
                     // Consume value component, not optional at this point
-                    let rest = tagged_many0(b"\n".or(b" ").or(b"\t"), rest);
-                    let (rest, next) = ValueComponent::parse(e, rest, offset(rest))?;
+                    let rest = blank(rest);
+                    let (rest, next) = ValueComponent::parse(e, cx, arena, rest, offset(rest))?;
                     vec.push((domain, next));
                     cursor = rest;
                 },
@@ -339,6 +597,31 @@ impl<'a> Value<'a> {
         }
         Ok((cursor, Self { first, rest: vec }))
     }
+    /// Concatenate every component, as the value is meant to be read.
+    ///
+    /// Only allocates when there's more than one component, or escapes to collapse.
+    fn resolve<'t>(&'t self, text: &'t [u8]) -> Cow<'t, [u8]> {
+        if self.rest.is_empty() {
+            return self.first.resolve(text)
+        }
+        let mut out = self.first.resolve(text).into_owned();
+        for (_, component) in self.rest.iter() {
+            out.extend_from_slice(&component.resolve(text));
+        }
+        Cow::Owned(out)
+    }
+    /// The bytes [`resolve`](Self::resolve) would give, without allocating.
+    fn bytes<'t>(&'t self, text: &'t [u8]) -> impl Iterator<Item = u8> + 't {
+        ::core::iter::once(&self.first)
+            .chain(self.rest.iter().map(|(_, component)| component))
+            .flat_map(move |component| component.bytes(text))
+    }
+    /// Where the whole value is in the document, from the start of its first component
+    /// to the end of its last, including quotes and `_` markers.
+    fn span(&self, text: &[u8]) -> Range<usize> {
+        let last = self.rest.last().map_or(&self.first, |(_, last)| last);
+        self.first.span(text).start .. last.span(text).end
+    }
 }
 
 /// `wml_value_component` in the WML grammar.
@@ -347,13 +630,33 @@ impl<'a> Value<'a> {
 /// wml_value_component := text | '_'? string | '_'? raw_string
 /// ```
 #[derive(Debug)]
-enum ValueComponent {
+enum ValueComponent<'a> {
     Text(Text),
     String(WString),
     RawString(RawString),
+    /// A component a handler gave different content for, copied into the arena.
+    Replaced(&'a ValueComponent<'a>, &'a [u8]),
 }
-impl ValueComponent {
-    fn parse<'a, E: Effects>(e: &E, input: &'a [u8], offset: usize) -> PResult<'a, Self, ()> {
+impl<'a> ValueComponent<'a> {
+    /// Parse a component, keeping whatever content `e` gives for it if that's different
+    /// from what's written.
+    fn parse<'b, E: Effects>(e: &E, cx: &Context<'_>, arena: &'a Bump, input: &'b [u8], offset: usize) -> PResult<'b, Self, Failure> {
+        let (rest, component) = Self::parse_component(input, offset)?;
+        let key = component.content();
+        let written = &input[key.idx - offset..][..key.len];
+        match e.get_bytes(key) {
+            // The fallback printer hands back the bytes as written, so check for that first.
+            EffectResult::Handled(bytes) if bytes.as_ptr() != written.as_ptr() && bytes != written => {
+                if cx.utf8 && ::core::str::from_utf8(bytes).is_err() {
+                    return Err(Failure::Fatal(ParseError::InvalidUtf8 { offset: key.idx }))
+                }
+                let content = arena.alloc_slice_copy(bytes);
+                Ok((rest, Self::Replaced(arena.alloc(component), content)))
+            },
+            _ => Ok((rest, component)),
+        }
+    }
+    fn parse_component(input: &[u8], offset: usize) -> PResult<'_, Self, Failure> {
         // TODO: fix order these are checked?
         let mut underscored = false;
         let (rest, offset) = match tagged(b"_", input) {
@@ -364,37 +667,132 @@ impl ValueComponent {
             Err(()) => (input, offset),
         };
         WString::parse(rest, offset).map(|(rest, s)| {
-            (rest, Self::String(s))
-        }).or_else(|()| {
-            RawString::parse(rest, offset).map(|(rest, r)| {
-                (rest, Self::RawString(r))
-            })
-        }).or_else(|()| {
-            if !underscored {
-                Text::parse(input, offset).map(|(rest, txt)| {
-                    (rest, Self::Text(txt))
-                })
-            } else {
-                Err(())
+            (rest, Self::String(WString { translatable: underscored, ..s }))
+        }).or_else(|failure| match failure {
+            Failure::Mismatch => RawString::parse(rest, offset).map(|(rest, r)| {
+                (rest, Self::RawString(RawString { translatable: underscored, ..r }))
+            }),
+            fatal => Err(fatal),
+        }).or_else(|failure| match failure {
+            Failure::Mismatch if !underscored => Text::parse(input, offset).map(|(rest, txt)| {
+                (rest, Self::Text(txt))
+            }).map_err(Failure::from),
+            failure => Err(failure),
+        })
+    }
+    /// Whether this component was marked for translation with `_`,
+    /// which only strings may be.
+    fn is_translatable(&self) -> bool {
+        match self {
+            Self::Text(_) => false,
+            Self::String(s) => s.translatable,
+            Self::RawString(r) => r.translatable,
+            Self::Replaced(component, _) => component.is_translatable(),
+        }
+    }
+    /// Where the content of this component is, not including quotes.
+    fn content(&self) -> StringKey {
+        match self {
+            Self::Text(t) => t.content,
+            Self::String(s) => s.content,
+            Self::RawString(r) => r.content,
+            Self::Replaced(component, _) => component.content(),
+        }
+    }
+    /// The component as parsed, and its content: the bytes a handler replaced it with,
+    /// if one did, or else what's written in the document.
+    fn written<'t>(&'t self, text: &'t [u8]) -> (&'t Self, &'t [u8]) {
+        match self {
+            Self::Replaced(component, content) => (component, content),
+            _ => (self, self.content().get(text)),
+        }
+    }
+    /// Get the content of this component, with surrounding whitespace trimmed
+    /// from unquoted text and quote escapes collapsed in strings.
+    ///
+    /// Content a handler replaced is treated the same way, as if it had been written there.
+    fn resolve<'t>(&'t self, text: &'t [u8]) -> Cow<'t, [u8]> {
+        let (component, content) = self.written(text);
+        component.resolve_content(content)
+    }
+    /// Resolve `content` the way this kind of component's content is.
+    fn resolve_content<'t>(&self, mut content: &'t [u8]) -> Cow<'t, [u8]> {
+        match self {
+            Self::Text(_) => {
+                while let [b' ' | b'\t' | b'\r', rest @ ..] = content {
+                    content = rest;
+                }
+                while let [rest @ .., b' ' | b'\t' | b'\r'] = content {
+                    content = rest;
+                }
+                Cow::Borrowed(content)
+            },
+            Self::String(_) => collapse_quotes(content),
+            Self::RawString(_) => Cow::Borrowed(content),
+            Self::Replaced(..) => unreachable!("replaced components aren't replaced again"),
+        }
+    }
+    /// The bytes [`resolve`](Self::resolve) would give, without allocating.
+    fn bytes<'t>(&'t self, text: &'t [u8]) -> impl Iterator<Item = u8> + 't {
+        let (content, escaped) = match self.written(text) {
+            (Self::String(_), content) => (content, true),
+            // Only strings have escapes, so nothing else allocates.
+            _ => match self.resolve(text) {
+                Cow::Borrowed(content) => (content, false),
+                Cow::Owned(_) => unreachable!(),
+            },
+        };
+        let mut content = content.iter().copied().peekable();
+        ::core::iter::from_fn(move || {
+            let byte = content.next()?;
+            // `""` is an escaped `"`
+            if escaped && byte == b'"' {
+                content.next_if_eq(&b'"');
             }
+            Some(byte)
         })
     }
+    /// Where this component is in the document, including any quotes and `_` marker,
+    /// but not the whitespace around unquoted text.
+    fn span(&self, text: &[u8]) -> Range<usize> {
+        match self {
+            Self::Replaced(component, _) => component.span(text),
+            Self::Text(t) => {
+                let content = t.content.get(text);
+                let space = |b: &&u8| matches!(b, b' ' | b'\t' | b'\r');
+                let leading = content.iter().take_while(space).count();
+                let trailing = content[leading..].iter().rev().take_while(space).count();
+                t.content.idx + leading .. t.content.idx + content.len() - trailing
+            },
+            Self::String(s) => s.content.idx - 1 - s.translatable as usize .. s.content.idx + s.content.len + 1,
+            Self::RawString(r) => r.content.idx - 2 - r.translatable as usize .. r.content.idx + r.content.len + 2,
+        }
+    }
 }
 
 /// `text` in the WML grammar.
 ///
 /// ```text
-/// text := [^+«nl»]*
+/// text := ([^+«nl»[] | '[' !closing_tag)*
+/// closing_tag := '[/' wml_name ']'
 /// ```
+///
+/// A `[` is only taken to end the text when it begins a complete closing tag,
+/// so `key=a[b` has the value `a[b`, but `key=a[/key]` closes the tag `key`.
+/// Wesnoth itself reads unquoted text to the end of the line, so this is
+/// only more lenient than it: anything it accepts is read the same way here.
+///
+/// Text may be empty, so `key=` with nothing else on its line is an attribute
+/// with an empty value, just like `key=""` and `key=<<>>`.
 #[derive(Debug)]
 struct Text {
     content: StringKey,
 }
 impl Text {
-    fn parse(input: &[u8], offset: usize) -> PResult<Self, ()> {
+    fn parse(input: &[u8], offset: usize) -> PResult<'_, Self, ()> {
         let mut cursor = input;
         while let &[a, ref rest @ ..] = cursor {
-            if a == b'+' || a == b'\n' {
+            if a == b'+' || a == b'\n' || (a == b'[' && closing_tag_ahead(cursor)) {
                 break
             } else {
                 cursor = rest;
@@ -414,21 +812,25 @@ impl Text {
 /// ```text
 /// string := '"' ([^"] | '""')* '"'
 /// ```
+///
+/// Strings may span several lines, like message bodies and map data do,
+/// so the newline ending an attribute is the first one after the closing quote.
 #[derive(Debug)]
 struct WString {
     content: StringKey,
+    /// Whether this string was marked for translation with `_`.
+    translatable: bool,
 }
 impl WString {
-    fn parse(input: &[u8], offset: usize) -> PResult<Self, ()> {
+    fn parse(input: &[u8], offset: usize) -> PResult<'_, Self, Failure> {
         let rest = tagged(b"\"", input)?;
         let mut cursor = rest;
-        while let &[a, b, ..] = cursor {
-            if a != b'"' {
-                cursor = &cursor[1..];
-            } else if b == b'"' {
-                cursor = &cursor[2..];
-            } else {
-                break
+        // Stops at the closing quote, or the end of the input if there isn't one.
+        loop {
+            match cursor {
+                [b'"', b'"', rest @ ..] => cursor = rest,
+                [b'"', ..] | [] => break,
+                [_, rest @ ..] => cursor = rest,
             }
         }
         let len = cursor.as_ptr() as usize - rest.as_ptr() as usize;
@@ -436,11 +838,32 @@ impl WString {
             idx: rest.as_ptr() as usize - input.as_ptr() as usize + offset,
             len,
         };
-        let rest = tagged(b"\"", cursor)?;
-        Ok((rest, Self { content }))
+        let rest = tagged(b"\"", cursor)
+            .map_err(|()| Failure::Fatal(ParseError::UnterminatedString { offset }))?;
+        Ok((rest, Self { content, translatable: false }))
     }
 }
 
+/// Collapse each `""` in the content of a string to `"`.
+///
+/// Only allocates when there's an escape to collapse.
+fn collapse_quotes(content: &[u8]) -> Cow<'_, [u8]> {
+    if !content.windows(2).any(|w| w == b"\"\"") {
+        return Cow::Borrowed(content)
+    }
+    let mut out = Vec::with_capacity(content.len());
+    let mut cursor = content;
+    while let [a, ref rest @ ..] = *cursor {
+        out.push(a);
+        // `""` is an escaped `"`
+        cursor = match (a, rest) {
+            (b'"', [b'"', rest @ ..]) => rest,
+            _ => rest,
+        };
+    }
+    Cow::Owned(out)
+}
+
 /// `raw_string` in the WML grammar.
 ///
 /// ```text
@@ -449,16 +872,19 @@ impl WString {
 #[derive(Debug)]
 struct RawString {
     content: StringKey,
+    /// Whether this string was marked for translation with `_`.
+    translatable: bool,
 }
 impl RawString {
-    fn parse(input: &[u8], offset: usize) -> PResult<Self, ()> {
+    fn parse(input: &[u8], offset: usize) -> PResult<'_, Self, Failure> {
         let rest = tagged(b"<<", input)?;
         let mut cursor = rest;
-        while let &[a, b, ..] = cursor {
-            if a != b'>' || b != b'>' {
-                cursor = &cursor[1..];
-            } else {
-                break
+        // Stops at the closing `>>`, or the end of the input if there isn't one.
+        // A `>` on its own, even right before the end, is content.
+        loop {
+            match cursor {
+                [b'>', b'>', ..] | [] => break,
+                [_, rest @ ..] => cursor = rest,
             }
         }
         let len = cursor.as_ptr() as usize - rest.as_ptr() as usize;
@@ -466,23 +892,26 @@ impl RawString {
             idx: rest.as_ptr() as usize - input.as_ptr() as usize + offset,
             len,
         };
-        // Note: Either this is true, or we hit EOF.
-        let rest = tagged(b">>", cursor)?;
-        Ok((rest, Self { content }))
+        let rest = tagged(b">>", cursor)
+            .map_err(|()| Failure::Fatal(ParseError::UnterminatedRawString { offset }))?;
+        Ok((rest, Self { content, translatable: false }))
     }
 }
 
 /// `textdomain` in the WML grammar.
 ///
 /// ```text
-/// textdomain = '#textdomain' [a-zA-Z0-9_-]+ «nl»
+/// textdomain = '#textdomain' [a-zA-Z0-9_-]+ («nl» | EOF)
 /// ```
-#[derive(Debug)]
+///
+/// The line ending may be left off at the very end of the input,
+/// since messages may be framed without one.
+#[derive(Debug, Copy, Clone)]
 struct TextDomain {
     name: StringKey,
 }
 impl TextDomain {
-    fn parse<'a, E: Effects>(e: &E, input: &'a [u8], offset: usize) -> PResult<'a, Self, ()> {
+    fn parse<'a, E: Effects>(_e: &E, input: &'a [u8], offset: usize) -> PResult<'a, Self, ()> {
         let rest = tagged(b"#textdomain", input)?;
         // TODO: verify that this is needed here,
         // or if we should just scroll over whitespace or something
@@ -497,7 +926,8 @@ impl TextDomain {
                 idx: rest.as_ptr() as usize - input.as_ptr() as usize + offset,
                 len,
             };
-            let rest = tagged(b"\n", cursor)?;
+            // Either way, the name can't run into whatever comes next.
+            let rest = if cursor.is_empty() { cursor } else { line_sep(cursor)? };
             Ok((rest, Self { name }))
         } else {
             Err(())
@@ -505,11 +935,52 @@ impl TextDomain {
     }
 }
 
+/// A document parsed with [`DocProcessor::parse_borrowed`],
+/// which can't outlive the buffer it was parsed from.
+///
+/// This is just a [`Doc`], whose lifetime covers both the processor and the buffer.
+pub type BorrowedDoc<'b> = Doc<'b>;
+
 /// A WML document.
 #[derive(Debug)]
 pub struct Doc<'a> {
     top: bump::Vec<'a, TagOrAttr<'a>>,
-    text: Vec<u8>,
+    /// The buffer the document was parsed from, which it owns unless
+    /// it was parsed with [`DocProcessor::parse_borrowed`].
+    text: Cow<'a, [u8]>,
+    /// Replacements for spans of `text`, sorted by where they start.
+    /// See [`Doc::set_attr_value`].
+    overrides: Vec<(Range<usize>, Vec<u8>)>,
+    /// Whether `text` was checked to be valid UTF-8, see [`Doc::is_utf8`].
+    utf8: bool,
+}
+
+/// Documents are equal when they hold the same tags and attributes in the same order,
+/// and the same values once resolved, regardless of how they're formatted.
+///
+/// Textdomains aren't compared.
+impl PartialEq for Doc<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        content_eq(&self.text, &self.top, &other.text, &other.top)
+    }
+}
+
+fn content_eq(a: &[u8], a_content: &[TagOrAttr<'_>], b: &[u8], b_content: &[TagOrAttr<'_>]) -> bool {
+    let name_eq = |x: &Name, y: &Name| x.content.get(a) == y.content.get(b);
+    a_content.len() == b_content.len() && a_content.iter().zip(b_content).all(|pair| match pair {
+        (TagOrAttr::Tag(x), TagOrAttr::Tag(y)) => {
+            name_eq(&x.name, &y.name) && x.merge == y.merge && content_eq(a, &x.content, b, &y.content)
+        },
+        (TagOrAttr::Attr(x), TagOrAttr::Attr(y)) => {
+            let (x_keys, y_keys) = (&x.key_sequence, &y.key_sequence);
+            if !name_eq(&x_keys.first, &y_keys.first) || x_keys.names.len() != y_keys.names.len()
+                || !x_keys.names.iter().zip(y_keys.names.iter()).all(|(x, y)| name_eq(x, y)) {
+                return false
+            }
+            x.value.resolve(a) == y.value.resolve(b)
+        },
+        _ => false,
+    })
 }
 
 // Parsing of a single document is inherently single threaded, so
@@ -521,49 +992,415 @@ pub struct DocProcessor {
     // The lifetime carrying collections of `bumpalo` are what forced me to
     // introduce this `DocProcessor` struct.
     arena: Bump,
-    // TODO: consider adding interner
+    limits: Limits,
+    /// Whether to parse with [`parse_iterative_in`] instead of [`parse_in`].
+    iterative: bool,
+    interner: Option<Interner>,
+    validate_utf8: bool,
+    implicit_close: bool,
+}
+
+impl Default for DocProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DocProcessor {
     pub fn new() -> Self {
         Self {
             arena: Bump::new(),
+            limits: Limits::default(),
+            iterative: false,
+            interner: None,
+            validate_utf8: false,
+            implicit_close: false,
         }
     }
-    /// Nuke all parsed stuff.
+    /// Reject documents where a single tag directly contains more than `max`
+    /// tags and attributes, bounding how large any one tag can get.
+    ///
+    /// Defaults to 65536.
+    pub fn with_max_children_per_tag(mut self, max: usize) -> Self {
+        self.limits.max_children_per_tag = max;
+        self
+    }
+    /// Reject documents with more than `max` tags and attributes in total, at any depth,
+    /// bounding how much of the arena any one document can take up.
+    ///
+    /// Defaults to 1048576.
+    pub fn with_max_nodes(mut self, max: usize) -> Self {
+        self.limits.max_nodes = max;
+        self
+    }
+    /// Reject documents with tags nested more than `max` deep.
+    ///
+    /// Parsing recurses once for every level of nesting, so this is what keeps
+    /// deeply nested input from overflowing the stack.
+    /// Defaults to 512, which needs a little over 1 MiB of stack in a debug build,
+    /// and much less in a release build.
+    /// With the `stacker` feature enabled, recursion grows the stack instead of overflowing it.
+    pub fn with_max_depth(mut self, max: usize) -> Self {
+        self.limits.max_depth = max;
+        self
+    }
+    /// Parse nested tags with a stack on the heap, instead of by recursion.
+    ///
+    /// This is somewhat slower, but needs no more native stack for deeper documents,
+    /// so [`with_max_depth`](Self::with_max_depth) may be raised as far as memory allows.
+    /// Documents are parsed exactly the same either way.
+    pub fn with_iterative_parsing(mut self) -> Self {
+        self.iterative = true;
+        self
+    }
+    /// Intern tag names and attribute keys while parsing, giving each distinct name a [`NameId`],
+    /// so tags are matched with their closing tags by comparing integers.
+    ///
+    /// Interning hashes every name, which costs more than the byte comparisons it saves
+    /// (see the `parse_users` benchmark), so it's only worth it when the ids are
+    /// compared many times after parsing.
+    /// Interned names are kept for as long as the processor is, so only use this for input
+    /// with a limited vocabulary of names, like messages from Wesnoth clients.
+    pub fn with_interning(mut self) -> Self {
+        self.interner = Some(Interner::default());
+        self
+    }
+    /// Reject input which isn't entirely valid UTF-8, checking it all once before parsing.
+    ///
+    /// The document remembers this, see [`Doc::is_utf8`], so getting strings out of it
+    /// doesn't check each one again.
+    pub fn with_utf8_validation(mut self) -> Self {
+        self.validate_utf8 = true;
+        self
+    }
+    /// Accept `[/]` as closing whatever tag is open, as some hand written WML does,
+    /// instead of requiring the tag's name to be repeated.
+    ///
+    /// Unquoted text isn't ended by `[/]`, so `key=a[/]` still has the value `a[/]`.
+    pub fn with_implicit_close(mut self) -> Self {
+        self.implicit_close = true;
+        self
+    }
+    /// Get the name `id` was given for, if this processor gave it out.
+    pub fn resolve_name(&self, id: NameId) -> Option<String> {
+        self.interner.as_ref()?.resolve(id)
+    }
+    /// How many distinct names have been interned, if interning is on.
+    pub fn interned_names(&self) -> Option<usize> {
+        self.interner.as_ref().map(Interner::len)
+    }
+    /// Nuke all parsed stuff, apart from interned names.
     /// See [`Bump::reset`].
     pub fn reset(&mut self) {
         self.arena.reset()
     }
-    pub fn parse(&self, buf: Vec<u8>) -> Result<Doc<'_>, ()> {
-        // TODO: this would benefit from `with_capacity_in`
-        let mut top = bump::Vec::new_in(&self.arena);
-        let mut cursor = &*buf;
-        let printer = effects::Printer::new(cursor);
-        let offset = |slc: &[u8]| slc.as_ptr() as usize - buf.as_ptr() as usize;
-        while let Ok((rest, tag_or_attr)) = TagOrAttr::parse(&printer, &self.arena, cursor, offset(cursor)) {
-            cursor = rest;
-            top.push(tag_or_attr);
-        }
-        // Check if there's input we failed to parse.
-        if offset(cursor) == buf.len() {
-            Ok(Doc {
-                top,
-                text: buf,
-            })
+    /// How many bytes of the arena are taken up by parsed documents, since it was last reset.
+    ///
+    /// For a processor that's kept around, this is what [`reset`](Self::reset) would give back to reuse.
+    pub fn allocated_bytes(&self) -> usize {
+        self.arena.allocated_bytes()
+    }
+    /// [`reset`](Self::reset), but only if more than `threshold` bytes are taken up by parsed documents,
+    /// so a long running processor can reset now and then, rather than after every document.
+    ///
+    /// Returns whether it did.
+    pub fn reset_if_over(&mut self, threshold: usize) -> bool {
+        let over = self.allocated_bytes() > threshold;
+        if over {
+            self.reset();
+        }
+        over
+    }
+    pub fn parse(&self, buf: Vec<u8>) -> Result<Doc<'_>, ParseError> {
+        self.parse_cow(Cow::Owned(buf), &NullHandler, false)
+    }
+    /// Parse `buf`, run `f` on the document, then [`reset`](Self::reset), whether or not parsing succeeded.
+    ///
+    /// Nothing borrowed from the document can escape `f`, so this is the way to handle a stream of
+    /// messages without the arena growing with every one of them.
+    ///
+    /// ```
+    /// let mut processor = wml::DocProcessor::new();
+    /// let tags = processor.parse_scoped(Vec::from("[version]\n[/version]\n"), |doc| doc.children().count());
+    /// assert_eq!(tags, Ok(1));
+    /// assert_eq!(processor.allocated_bytes(), 0);
+    /// ```
+    pub fn parse_scoped<R>(&mut self, buf: Vec<u8>, f: impl FnOnce(&Doc<'_>) -> R) -> Result<R, ParseError> {
+        let res = self.parse(buf).map(|doc| f(&doc));
+        self.reset();
+        res
+    }
+    /// Like [`parse`](Self::parse), but resolving strings through `e` while parsing,
+    /// falling back to a [`Printer`] for whatever it doesn't handle.
+    /// See [`Effects`].
+    pub fn parse_with<E: Effects>(&self, buf: Vec<u8>, e: &E) -> Result<Doc<'_>, ParseError> {
+        self.parse_cow(Cow::Owned(buf), e, false)
+    }
+    /// Like [`parse`](Self::parse), but borrowing the buffer instead of taking it,
+    /// for when it's already held somewhere that outlives the document.
+    ///
+    /// ```
+    /// let processor = wml::DocProcessor::new();
+    /// let doc = processor.parse_borrowed(b"[version]\n[/version]\n").unwrap();
+    /// assert_eq!(doc.children().count(), 1);
+    /// ```
+    pub fn parse_borrowed<'b>(&'b self, buf: &'b [u8]) -> Result<BorrowedDoc<'b>, ParseError> {
+        self.parse_cow(Cow::Borrowed(buf), &NullHandler, false)
+    }
+    /// Read all of `reader` and parse it, as when loading a document from a file.
+    ///
+    /// ```
+    /// let processor = wml::DocProcessor::new();
+    /// let doc = processor.parse_reader(::std::io::Cursor::new("[version]\n[/version]\n")).unwrap();
+    /// assert_eq!(doc.len(), 1);
+    /// ```
+    pub fn parse_reader<R: Read>(&self, mut reader: R) -> Result<Doc<'_>, ReadError> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        Ok(self.parse(buf)?)
+    }
+    /// Like [`parse_borrowed`](Self::parse_borrowed), but stopping after the last complete
+    /// top level tag or attribute instead of rejecting what follows it, which is returned too.
+    ///
+    /// This is for buffers which may end with the start of another document.
+    /// Anything which can't be parsed is left in the rest, so the only errors are
+    /// from going over this processor's limits.
+    /// An attribute at the very end isn't complete until its line ends,
+    /// since more of its value may be yet to come.
+    ///
+    /// ```
+    /// let processor = wml::DocProcessor::new();
+    /// let (doc, rest) = processor.parse_prefix(b"[version]\n[/version]\n[login]\nuser").unwrap();
+    /// assert_eq!(doc.children().count(), 1);
+    /// assert_eq!(rest, b"[login]\nuser");
+    /// ```
+    pub fn parse_prefix<'b>(&'b self, buf: &'b [u8]) -> Result<(BorrowedDoc<'b>, &'b [u8]), ParseError> {
+        let doc = self.parse_cow(Cow::Borrowed(buf), &NullHandler, true)?;
+        let rest = &buf[doc.text.len()..];
+        Ok((doc, rest))
+    }
+    /// Parse `buf`, or with `prefix`, as much of it as can be, see [`parse_prefix`](Self::parse_prefix).
+    fn parse_cow<'b, E: Effects>(&'b self, buf: Cow<'b, [u8]>, e: &E, prefix: bool) -> Result<Doc<'b>, ParseError> {
+        // How much of `buf` is known to be valid UTF-8.
+        let valid = if self.validate_utf8 {
+            match ::core::str::from_utf8(&buf) {
+                Ok(_) => buf.len(),
+                // A prefix can end partway through a character, which will be left in the rest.
+                Err(e) if prefix && e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(ParseError::InvalidUtf8 { offset: e.valid_up_to() }),
+            }
         } else {
-            dbg!(offset(cursor), buf.len());
-            Err(())
+            0
+        };
+        let nodes = Cell::new(0);
+        let cx = Context {
+            limits: &self.limits,
+            interner: self.interner.as_ref(),
+            nodes: &nodes,
+            implicit_close: self.implicit_close,
+            partial: prefix,
+            domain: &Cell::new(None),
+            utf8: self.validate_utf8,
+        };
+        let mut doc = if self.iterative {
+            parse_iterative_in(e, &self.arena, &cx, buf, prefix)?
+        } else {
+            parse_in(e, &self.arena, &cx, buf, prefix)?
+        };
+        doc.utf8 = self.validate_utf8 && doc.text.len() <= valid;
+        Ok(doc)
+    }
+}
+
+/// Get the name of the first tag in `buf`, without parsing the document,
+/// for deciding what to do with a message.
+///
+/// Returns `None` if anything but whitespace comes before the first tag,
+/// and doesn't check anything after its name.
+///
+/// ```
+/// assert_eq!(wml::peek_first_tag_name(b"\n[login]\nusername=\"lol\"\n[/login]\n"), Some("login"));
+/// ```
+pub fn peek_first_tag_name(buf: &[u8]) -> Option<&str> {
+    let rest = blank(buf);
+    let rest = tagged(b"[", rest).ok()?;
+    let (rest, name) = Name::parse(rest, buf.len() - rest.len()).ok()?;
+    tagged(b"]", rest).ok()?;
+    // Names are always ASCII.
+    ::core::str::from_utf8(name.content.get(buf)).ok()
+}
+
+/// Parse a document into `arena`.
+///
+/// With `prefix`, stop at the first top level tag or attribute which doesn't parse,
+/// and cut `buf` off there, instead of failing.
+fn parse_in<'a, E: Effects>(e: &E, arena: &'a Bump, cx: &Context<'_>, buf: Cow<'a, [u8]>, prefix: bool) -> Result<Doc<'a>, ParseError> {
+    // Everything after the first top level tag is allocated after this would be,
+    // so growing it in the arena would leave its old buffers behind.
+    let mut top = Vec::new();
+    let mut cursor = &*buf;
+    let printer = Printer::new(cursor);
+    let e = e.or(&printer);
+    let offset = |slc: &[u8]| slc.as_ptr() as usize - buf.as_ptr() as usize;
+    // Where the last top level tag or attribute ended with no directive waiting, for `prefix`.
+    let mut boundary = cursor;
+    loop {
+        if cx.domain.get().is_none() {
+            boundary = cursor;
+        }
+        cursor = between_nodes_in(cx, cursor, offset(cursor));
+        match TagOrAttr::parse(&e, cx, 0, arena, cursor, offset(cursor)) {
+            Ok((rest, tag_or_attr)) => {
+                cx.count_node(offset(cursor))?;
+                cursor = rest;
+                top.push(tag_or_attr);
+            },
+            Err(Failure::Mismatch) => break,
+            Err(Failure::Fatal(e)) if prefix && !e.is_limit() => break,
+            Err(Failure::Fatal(e)) => return Err(e),
+        }
+    }
+    // A directive left waiting is kept for the attribute it's for, in the rest of the input.
+    if prefix && cx.domain.get().is_some() {
+        cursor = boundary;
+    }
+    let rest = offset(cursor);
+    let buf = if prefix { truncate(buf, rest) } else { buf };
+    finish(arena, top, buf, rest)
+}
+
+/// Like [`parse_in`], but keeping a stack of open tags on the heap instead of recursing.
+fn parse_iterative_in<'a, E: Effects>(e: &E, arena: &'a Bump, cx: &Context<'_>, buf: Cow<'a, [u8]>, prefix: bool) -> Result<Doc<'a>, ParseError> {
+    let mut top = Vec::new();
+    // Tags which have been opened but not closed, innermost last,
+    // with where they were opened and their content so far.
+    let mut open = Vec::<((Name, bool), usize, bump::Vec<'a, TagOrAttr<'a>>)>::new();
+    let mut cursor = &*buf;
+    // Where the last complete top level tag or attribute ended with no directive waiting, for `prefix`.
+    let mut boundary = cursor;
+    let printer = Printer::new(cursor);
+    let e = e.or(&printer);
+    let offset = |slc: &[u8]| slc.as_ptr() as usize - buf.as_ptr() as usize;
+    // Add a finished tag or attribute, which started at `start`, to the innermost open tag, or the top level.
+    let push = |open: &mut Vec<((Name, bool), usize, bump::Vec<'a, TagOrAttr<'a>>)>, top: &mut Vec<TagOrAttr<'a>>, start, node| {
+        match open.last_mut() {
+            Some((_, at, content)) => {
+                if content.len() == cx.limits.max_children_per_tag {
+                    return Err(ParseError::TooManyChildren { at: *at })
+                }
+                cx.count_node(start)?;
+                content.push(node);
+            },
+            None => {
+                cx.count_node(start)?;
+                top.push(node);
+            },
         }
+        Ok(())
+    };
+    loop {
+        if open.is_empty() && cx.domain.get().is_none() {
+            boundary = cursor;
+        }
+        cursor = between_nodes_in(cx, cursor, offset(cursor));
+        match Tag::open(cx, open.len(), cursor, offset(cursor)) {
+            Ok((rest, name)) => {
+                open.push((name, offset(cursor), bump::Vec::new_in(arena)));
+                cursor = rest;
+                continue
+            },
+            Err(Failure::Mismatch) => (),
+            Err(Failure::Fatal(e)) if prefix && !e.is_limit() => {
+                cursor = boundary;
+                break
+            },
+            Err(Failure::Fatal(e)) => return Err(e),
+        }
+        match TagOrAttr::parse_attr(&e, cx, arena, cursor, offset(cursor)) {
+            Ok((rest, attr)) => {
+                push(&mut open, &mut top, offset(cursor), attr)?;
+                cursor = rest;
+                continue
+            },
+            Err(Failure::Mismatch) => (),
+            Err(Failure::Fatal(e)) if prefix && !e.is_limit() => {
+                cursor = boundary;
+                break
+            },
+            Err(Failure::Fatal(e)) => return Err(e),
+        }
+        // Anything else must close the innermost open tag.
+        match open.pop() {
+            Some(((name, merge), start, content)) => match Tag::close(cx, &name, &buf, 0, cursor, offset(cursor)) {
+                Ok(rest) => {
+                    cursor = rest;
+                    push(&mut open, &mut top, start, TagOrAttr::Tag(Tag { name, merge, content }))?;
+                },
+                Err(Failure::Fatal(e)) if prefix && !e.is_limit() => {
+                    cursor = boundary;
+                    break
+                },
+                Err(Failure::Fatal(e)) => return Err(e),
+                Err(Failure::Mismatch) => unreachable!("closing tags only fail fatally"),
+            },
+            None => break,
+        }
+    }
+    if prefix && cx.domain.get().is_some() {
+        cursor = boundary;
+    }
+    let rest = offset(cursor);
+    let buf = if prefix { truncate(buf, rest) } else { buf };
+    finish(arena, top, buf, rest)
+}
+
+/// Cut `buf` off at `len`, for a document which was only parsed that far.
+fn truncate(buf: Cow<'_, [u8]>, len: usize) -> Cow<'_, [u8]> {
+    match buf {
+        Cow::Borrowed(buf) => Cow::Borrowed(&buf[..len]),
+        Cow::Owned(mut buf) => {
+            buf.truncate(len);
+            Cow::Owned(buf)
+        },
+    }
+}
+
+/// Check that a document was parsed up to its end, at `rest`,
+/// and move its top level into `arena`.
+fn finish<'a>(arena: &'a Bump, top: Vec<TagOrAttr<'a>>, buf: Cow<'a, [u8]>, rest: usize) -> Result<Doc<'a>, ParseError> {
+    let cursor = &buf[rest..];
+    let offset = |slc: &[u8]| slc.as_ptr() as usize - buf.as_ptr() as usize;
+    // A closing tag here can't have a matching opening tag,
+    // or it would've been consumed along with it.
+    if let Ok(rest) = tagged(b"[/", cursor) {
+        if let Ok((_, name)) = Name::parse(rest, offset(rest)) {
+            return Err(ParseError::UnexpectedClosingTag { name_span: name.content.span() })
+        }
+    }
+    // Check if there's input we failed to parse.
+    if offset(cursor) == buf.len() {
+        let mut top_in = bump::Vec::with_capacity_in(top.len(), arena);
+        top_in.extend(top);
+        Ok(Doc {
+            top: top_in,
+            text: buf,
+            overrides: Vec::new(),
+            utf8: false,
+        })
+    } else {
+        Err(ParseError::TrailingInput { offset: offset(cursor) })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::array::IntoIter;
-    use crate::DocProcessor;
+    use crate::{collapse_quotes, peek_first_tag_name, DocProcessor, Failure, NodeRef, NullHandler, ParseError, RawString, TextDomain, WString};
+    use crate::test_util::{first_tag, tags, USER, USERS};
+    use ::std::borrow::Cow;
 
     #[test]
+    #[allow(deprecated)]
     fn parse_attr() {
         let processor = DocProcessor::new();
         let input = IntoIter::new(*b"lol=\"hello\"\n").collect::<Vec<u8>>();
@@ -573,7 +1410,7 @@ mod tests {
     #[test]
     fn parse_users() {
         let processor = DocProcessor::new();
-        let users = Vec::from("[user]\navailable=\"yes\"\nforum_id=\"0\"\ngame_id=\"0\"\nlocation=\"\"\nmoderator=\"no\"\nname=\"lol\"\nregistered=\"no\"\nstatus=\"lobby\"\n[/user]\n[user]\navailable=\"yes\"\nforum_id=\"0\"\ngame_id=\"0\"\nlocation=\"\"\nmoderator=\"no\"\nname=\"haha\"\nregistered=\"no\"\nstatus=\"lobby\"\n[/user]\n");
+        let users = Vec::from(USERS);
         let _doc = processor.parse(users).unwrap();
     }
 
@@ -583,4 +1420,476 @@ mod tests {
         let game_list = Vec::from("[gamelist]\n\n[/gamelist]");
         let _doc = processor.parse(game_list).unwrap();
     }
+
+    #[test]
+    fn too_many_children() {
+        let processor = DocProcessor::new().with_max_children_per_tag(3);
+        let doc = Vec::from("[user]\na=\"1\"\nb=\"2\"\nc=\"3\"\n[/user]\n");
+        processor.parse(doc).unwrap();
+        let doc = Vec::from("[gamelist]\n[user]\na=\"1\"\nb=\"2\"\nc=\"3\"\nd=\"4\"\n[/user]\n[/gamelist]\n");
+        assert_eq!(processor.parse(doc).unwrap_err(), ParseError::TooManyChildren { at: 11 });
+    }
+
+    #[test]
+    fn arena_usage() {
+        let mut processor = DocProcessor::new();
+        let empty = processor.allocated_bytes();
+        processor.parse(USER.repeat(200).into_bytes()).unwrap();
+        let parsed = processor.allocated_bytes();
+        assert!(parsed > empty);
+        assert!(!processor.reset_if_over(parsed));
+        assert_eq!(processor.allocated_bytes(), parsed);
+        assert!(processor.reset_if_over(parsed / 2));
+        assert!(processor.allocated_bytes() <= empty + 64);
+        processor.parse(USER.repeat(200).into_bytes()).unwrap();
+        processor.reset();
+        assert!(processor.allocated_bytes() <= empty + 64);
+    }
+
+    #[test]
+    fn scoped_parsing() {
+        let mut processor = DocProcessor::new();
+        for n in 1..=50 {
+            let users = processor.parse_scoped(USER.repeat(n).into_bytes(), |doc| doc.children().count());
+            assert_eq!(users, Ok(n));
+            assert_eq!(processor.allocated_bytes(), 0);
+        }
+        assert!(processor.parse_scoped(Vec::from("[user]\n"), |_| ()).is_err());
+        assert_eq!(processor.allocated_bytes(), 0);
+    }
+
+    #[test]
+    fn too_many_nodes() {
+        let limit = 100;
+        let attrs = |n| (0..n).map(|i| format!("a{}=\"{}\"\n", i, i)).collect::<String>();
+        for processor in [DocProcessor::new(), DocProcessor::new().with_iterative_parsing()] {
+            let processor = processor.with_max_nodes(limit);
+            processor.parse(attrs(limit).into_bytes()).unwrap();
+            let doc = attrs(limit + 1);
+            let at = doc.rfind("a100=").unwrap();
+            assert_eq!(processor.parse(doc.into_bytes()).unwrap_err(), ParseError::TooManyNodes { at });
+            // Tags count too, however deep they are.
+            let doc = format!("[a]\n[b]\n{}[/b]\n[/a]\n", attrs(limit - 2));
+            processor.parse(doc.clone().into_bytes()).unwrap();
+            let at = doc.len();
+            let doc = format!("{}x=1\n", doc);
+            assert_eq!(processor.parse(doc.into_bytes()).unwrap_err(), ParseError::TooManyNodes { at });
+        }
+    }
+
+    #[test]
+    fn mixed_separators() {
+        let processor = DocProcessor::new();
+        let expected = processor.parse(Vec::from("a=\"1\"\nb=\"2\"\nc=\"3\"\n[t]\nd=\"4\"\ne=\"5\" + \"6\"\n[/t]\n")).unwrap();
+        let mixed = concat!(
+            " \t\r\n",
+            "a=1\t \r\n",
+            "\tb = \"2\" \r\n \r\n",
+            "  \t# a comment\r\n",
+            "c\t=\t3\r\r\n",
+            "[t]\r\n",
+            "\t\td=4 \t\r\n",
+            "\t\te=\"5\" \r\n \t+ \r\n\t\"6\"\r\n",
+            "[/t] \r\n\r\n",
+        );
+        for processor in [DocProcessor::new(), DocProcessor::new().with_iterative_parsing()] {
+            assert_eq!(processor.parse(Vec::from(mixed)).unwrap(), expected);
+        }
+        assert_eq!(peek_first_tag_name(b" \r\n\t\r\n[t]\r\n[/t]\r\n"), Some("t"));
+    }
+
+    #[test]
+    fn stray_closing_tag() {
+        let processor = DocProcessor::new();
+        let err = processor.parse(Vec::from("[a][/a][/b]")).unwrap_err();
+        assert_eq!(err, ParseError::UnexpectedClosingTag { name_span: 9..10 });
+    }
+
+    #[test]
+    fn bracket_in_text() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from("[key]\nkey=a[b\n[/key]\n")).unwrap();
+        let tag = first_tag(&doc);
+        assert_eq!(tag.get_all("key").collect::<Vec<_>>(), [&b"a[b"[..]]);
+
+        let doc = processor.parse(Vec::from("[key]\nkey=a[/key]\n")).unwrap();
+        let tag = first_tag(&doc);
+        assert_eq!(tag.get_all("key").collect::<Vec<_>>(), [&b"a"[..]]);
+    }
+
+    #[test]
+    fn escaped_quotes() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "a=\"no escapes\"\n",
+            "b=\"she said \"\"hi\"\"\"\n",
+            "c=\"\"\"\"\"\"\"\"\"\"\n",
+            "d=\"x\"\"\"\"y\"\n",
+        ))).unwrap();
+        let values = doc.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr.value(),
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert_eq!(values, [&b"no escapes"[..], b"she said \"hi\"", b"\"\"\"\"", b"x\"\"y"]);
+        assert!(matches!(values[0], Cow::Borrowed(_)));
+        assert!(matches!(values[1], Cow::Owned(_)));
+    }
+
+    #[test]
+    fn string_ends() {
+        let string = |input: &'static [u8]| WString::parse(input, 0).map(|(rest, s)| (collapse_quotes(s.content.get(input)), rest));
+        assert_eq!(string(b"\"a\"").unwrap(), (Cow::Borrowed(&b"a"[..]), &b""[..]));
+        assert_eq!(string(b"\"\"").unwrap(), (Cow::Borrowed(&b""[..]), &b""[..]));
+        assert_eq!(string(b"\"a\"\"b\"\n").unwrap(), (Cow::Owned(b"a\"b".to_vec()), &b"\n"[..]));
+        assert_eq!(string(b"\"\"\"\"").unwrap(), (Cow::Owned(b"\"".to_vec()), &b""[..]));
+        for unterminated in [&b"\"abc"[..], b"\"", b"\"a\"\""] {
+            assert!(matches!(
+                string(unterminated),
+                Err(Failure::Fatal(ParseError::UnterminatedString { offset: 0 })),
+            ));
+        }
+        let err = DocProcessor::new().parse(Vec::from("[a]\nb=\"abc")).unwrap_err();
+        assert_eq!(err, ParseError::UnterminatedString { offset: 6 });
+    }
+
+    #[test]
+    fn raw_string_ends() {
+        let raw = |input: &'static [u8]| RawString::parse(input, 0).map(|(rest, r)| (r.content.get(input), rest));
+        assert_eq!(raw(b"<<a>b>>").unwrap(), (&b"a>b"[..], &b""[..]));
+        assert_eq!(raw(b"<<a>>").unwrap(), (&b"a"[..], &b""[..]));
+        assert_eq!(raw(b"<<>>>\n").unwrap(), (&b""[..], &b">\n"[..]));
+        assert_eq!(raw(b"<<a > b>>\n").unwrap(), (&b"a > b"[..], &b"\n"[..]));
+        for unterminated in [&b"<<abc"[..], b"<<", b"<<a>"] {
+            assert!(matches!(
+                raw(unterminated),
+                Err(Failure::Fatal(ParseError::UnterminatedRawString { offset: 0 })),
+            ));
+        }
+        let err = DocProcessor::new().parse(Vec::from("[a]\nb=_<<abc\n[/a]\n")).unwrap_err();
+        assert_eq!(err, ParseError::UnterminatedRawString { offset: 7 });
+    }
+
+    #[test]
+    fn multi_line_string() {
+        let source = "[message]\nmsg=\"line one\nline \"\"two\"\"\n\"\nsender=\"x\"\n[/message]\n";
+        for processor in [DocProcessor::new(), DocProcessor::new().with_iterative_parsing()] {
+            let doc = processor.parse(Vec::from(source)).unwrap();
+            let tag = first_tag(&doc);
+            assert_eq!(tag.children().count(), 2);
+            assert_eq!(tag.get_all("msg").collect::<Vec<_>>(), [&b"line one\nline \"two\"\n"[..]]);
+            assert_eq!(tag.get_all("sender").collect::<Vec<_>>(), [&b"x"[..]]);
+        }
+    }
+
+    #[test]
+    fn textdomain_at_end() {
+        let processor = DocProcessor::new();
+        for source in ["[a]\nb=1\n[/a]\n#textdomain wesnoth-lib", "#textdomain wesnoth-lib\n", "#textdomain wesnoth-lib"] {
+            processor.parse(Vec::from(source)).unwrap();
+        }
+        let (rest, domain) = TextDomain::parse(&NullHandler, b"#textdomain wesnoth-lib", 0).unwrap();
+        assert_eq!((rest, domain.name.get(b"#textdomain wesnoth-lib")), (&b""[..], &b"wesnoth-lib"[..]));
+        // The name has to end the line.
+        assert!(TextDomain::parse(&NullHandler, b"#textdomain wesnoth-lib[a]\n[/a]\n", 0).is_err());
+        let err = processor.parse(Vec::from("#textdomain wesnoth-lib[a]\n[/a]\n")).unwrap_err();
+        assert_eq!(err, ParseError::TrailingInput { offset: 0 });
+        let doc = processor.parse(Vec::from("#textdomain wesnoth-lib\na=1\n")).unwrap();
+        assert_eq!(doc.children().count(), 1);
+    }
+
+    #[test]
+    fn textdomain_between_tags() {
+        for processor in [DocProcessor::new(), DocProcessor::new().with_iterative_parsing()] {
+            // Before a tag, the directive applies to the first attribute in it.
+            let doc = processor.parse(Vec::from("#textdomain wesnoth\n[a]\nx=_\"hi\"\n[/a]\n")).unwrap();
+            match doc.find("a/x") {
+                Some(NodeRef::Attr(x)) => assert_eq!(x.textdomain(), Some("wesnoth")),
+                other => panic!("{:?}", other),
+            }
+            // Before a closing tag, it applies to the next attribute after the tag.
+            let doc = processor.parse(Vec::from("[a]\nx=_\"hi\"\n#textdomain wesnoth-lib\n[/a]\n[b]\n[/b]\ny=_\"yo\"\n")).unwrap();
+            let domains = doc.translatable_strings().map(|(domain, _)| domain).collect::<Vec<_>>();
+            assert_eq!(domains, [None, Some("wesnoth-lib")]);
+            // Left waiting at the end of a prefix, it's kept in the rest for the attribute after it.
+            let (doc, rest) = processor.parse_prefix(b"a=1\n#textdomain wesnoth\n[b]\nc=_\"d").unwrap();
+            assert_eq!(doc.len(), 1);
+            assert_eq!(rest, b"#textdomain wesnoth\n[b]\nc=_\"d");
+        }
+    }
+
+    #[test]
+    fn space_around_equals() {
+        let processor = DocProcessor::new();
+        for source in ["a = \"b\"\n", "a= \"b\" \t\n", "a =\"b\"\n", "a\t=\t_<<b>>\n", "a =  b \n"] {
+            let doc = processor.parse(Vec::from(source)).unwrap();
+            let attr = match doc.children().next() {
+                Some(NodeRef::Attr(attr)) => attr,
+                _ => panic!("expected an attribute"),
+            };
+            assert_eq!(attr.key_str(), Ok("a"));
+            assert_eq!(attr.value(), &b"b"[..]);
+        }
+        let doc = processor.parse(Vec::from("a = \nb =\"\"\n")).unwrap();
+        assert!(doc.children().all(|node| matches!(node, NodeRef::Attr(attr) if attr.value().is_empty())));
+    }
+
+    #[test]
+    fn blank_lines() {
+        let source = "\n  \n[a]\n\n  x=1\n \t\r\n  [b]\n  [/b]\n[/a]\n   \n\t\n  [c]\n[/c]  \n  # done\n\n";
+        for processor in [DocProcessor::new(), DocProcessor::new().with_iterative_parsing()] {
+            let doc = processor.parse(Vec::from(source)).unwrap();
+            assert_eq!(doc, processor.parse(Vec::from("[a]\nx=1\n[b]\n[/b]\n[/a]\n[c]\n[/c]\n")).unwrap());
+        }
+    }
+
+    #[test]
+    fn parse_borrowed() {
+        static USERS: &[u8] = b"[user]\nname=\"lol\"\n[/user]\n[user]\nname=\"haha\"\n[/user]\n";
+        let processor = DocProcessor::new();
+        let borrowed = processor.parse_borrowed(USERS).unwrap();
+        assert_eq!(borrowed, processor.parse(USERS.to_vec()).unwrap());
+        let mut out = Vec::new();
+        borrowed.write_to(&mut out).unwrap();
+        assert_eq!(out, USERS);
+        let err = processor.parse_borrowed(b"[user]\n[/users]\n").unwrap_err();
+        assert!(matches!(err, ParseError::MismatchedTag { .. }));
+    }
+
+    #[test]
+    fn interned_names() {
+        let processor = DocProcessor::new().with_interning();
+        let users = "[user]\nname=\"lol\"\n[/user]\n[user]\nname,id=\"haha,1\"\n[/user]\n";
+        let doc = processor.parse(Vec::from(users)).unwrap();
+        let tags = tags(&doc).collect::<Vec<_>>();
+        let user = tags[0].name_id().unwrap();
+        assert_eq!(tags[1].name_id(), Some(user));
+        assert_eq!(processor.resolve_name(user).as_deref(), Some("user"));
+        let keys = tags.iter().map(|tag| match tag.get("name") {
+            Some(NodeRef::Attr(attr)) => attr.key_ids().collect::<Vec<_>>(),
+            _ => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert_eq!(keys[0][0], keys[1][0]);
+        assert_eq!(processor.resolve_name(keys[1][1]).as_deref(), Some("id"));
+        assert_eq!(processor.interned_names(), Some(3));
+        assert_eq!(doc, DocProcessor::new().parse(Vec::from(users)).unwrap());
+
+        let err = processor.parse(Vec::from("[user]\n[/name]\n")).unwrap_err();
+        assert!(matches!(err, ParseError::MismatchedTag { .. }));
+        assert_eq!(DocProcessor::new().parse(Vec::from(users)).unwrap().children().find_map(|node| match node {
+            NodeRef::Tag(tag) => tag.name_id(),
+            NodeRef::Attr(_) => None,
+        }), None);
+    }
+
+    #[test]
+    fn top_level_allocated_once() {
+        for mut processor in [DocProcessor::new(), DocProcessor::new().with_iterative_parsing()] {
+            // Warm up the arena, so growing doesn't spill into new chunks.
+            drop(processor.parse(USER.repeat(1000).into_bytes()).unwrap());
+            processor.reset();
+            let doc = processor.parse(USER.repeat(500).into_bytes()).unwrap();
+            // Measured while the document is alive, since dropping it frees the top level,
+            // which is allocated last.
+            let used = processor.arena.allocated_bytes();
+            // Each user, and each of its 8 attributes, takes one slot, and nothing is wasted.
+            assert_eq!(used, 500 * 9 * ::core::mem::size_of::<crate::TagOrAttr>());
+            drop(doc);
+        }
+    }
+
+    #[test]
+    fn parse_prints_nothing() {
+        // Output from tests is captured, so this runs again in a process where it isn't.
+        if ::std::env::var_os("WML_PARSE_PRINTS_NOTHING").is_some() {
+            let processor = DocProcessor::new();
+            processor.parse(Vec::from("[user]\nname=\"lol\"\n[/user]\n")).unwrap();
+            processor.parse(Vec::from("[user]\nname=\"lol\"\n[/user]\n@")).unwrap_err();
+            return
+        }
+        let output = ::std::process::Command::new(::std::env::current_exe().unwrap())
+            .args(["--exact", "tests::parse_prints_nothing", "--nocapture", "--quiet", "--test-threads=1"])
+            .env("WML_PARSE_PRINTS_NOTHING", "1")
+            .output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+        // All that's left is the test harness' own summary.
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.lines().all(|line| {
+            line.is_empty() || line == "." || line.starts_with("running ") || line.starts_with("test result: ")
+        }), "{}", stdout);
+    }
+
+    #[test]
+    fn peek_tag_name() {
+        assert_eq!(peek_first_tag_name(b"[version]\nversion=\"1.16\"\n[/version]\n"), Some("version"));
+        assert_eq!(peek_first_tag_name(b" \r\n\t[create]\n"), Some("create"));
+        assert_eq!(peek_first_tag_name(b"version=\"1.16\"\n[version]\n[/version]\n"), None);
+        assert_eq!(peek_first_tag_name(b"[/version]\n"), None);
+        assert_eq!(peek_first_tag_name(b""), None);
+    }
+
+    #[test]
+    fn parse_crlf() {
+        let processor = DocProcessor::new();
+        let crlf = processor.parse(USERS.replace('\n', "\r\n").into_bytes()).unwrap();
+        assert_eq!(crlf, processor.parse(Vec::from(USERS)).unwrap());
+        let doc = processor.parse(Vec::from("x=a +\r\n#textdomain wesnoth\r\n _\"b\"\r\ny=1\r\n")).unwrap();
+        assert_eq!(doc, processor.parse(Vec::from("x=ab\ny=1\n")).unwrap());
+        assert!(processor.parse(Vec::from("[user]\rname=\"lol\"\r[/user]\r")).is_err());
+    }
+
+    #[test]
+    fn parse_comments() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "# leading comment\n",
+            "[user]\n",
+            "name=\"lol # not a comment\"\n",
+            "\t# inline comment\n",
+            "bio=<<#also not>>\n",
+            "#textdomain wesnoth\n",
+            "status=_\"lobby\"\n",
+            "[/user]\n",
+            "#trailing comment",
+        ))).unwrap();
+        let plain = processor.parse(Vec::from("[user]\nname=\"lol # not a comment\"\nbio=<<#also not>>\nstatus=\"lobby\"\n[/user]\n")).unwrap();
+        assert_eq!(doc, plain);
+    }
+
+    #[test]
+    fn structured_errors() {
+        let processor = DocProcessor::new();
+        let err = |doc: &str| processor.parse(Vec::from(doc)).unwrap_err();
+        assert_eq!(err("[a]\n[b]\n[/a]\n[/b]\n"), ParseError::MismatchedTag {
+            open: "b".into(),
+            close: "a".into(),
+            offset: 8,
+        });
+        assert_eq!(err("[a]\nx=\"1\n[/a]\n"), ParseError::UnterminatedString { offset: 6 });
+        assert_eq!(err("x=\"1\"2\n"), ParseError::UnexpectedByte { offset: 5, found: Some(b'2') });
+        assert_eq!(err("[a]\nx=1\n"), ParseError::UnexpectedByte { offset: 8, found: None });
+        assert_eq!(err("[a]\n[/a]\n&\n"), ParseError::TrailingInput { offset: 9 });
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let processor = DocProcessor::new();
+        let depth = 10_000;
+        let doc = format!("{}{}", "[a]\n".repeat(depth), "[/a]\n".repeat(depth));
+        assert_eq!(processor.parse(doc.into_bytes()).unwrap_err(), ParseError::DepthExceeded { at: 512 * 4 });
+        let processor = DocProcessor::new().with_max_depth(3);
+        processor.parse(Vec::from("[a]\n[a]\n[a]\n[/a]\n[/a]\n[/a]\n")).unwrap();
+        assert!(processor.parse(Vec::from("[a]\n[a]\n[a]\n[a]\n[/a]\n[/a]\n[/a]\n[/a]\n")).is_err());
+    }
+
+    #[test]
+    fn iterative_matches_recursive() {
+        let recursive = DocProcessor::new().with_max_children_per_tag(3).with_max_depth(4);
+        let iterative = DocProcessor::new().with_max_children_per_tag(3).with_max_depth(4).with_iterative_parsing();
+        let docs = [
+            "[user]\nname=\"lol\"\n# comment\n[info]\n\tbio=<<hi>>\n[/info]\n[/user]\nversion=1\n",
+            "[a][b][/b][/a][/c]",
+            "[a]\n[b]\n[/a]\n[/b]\n",
+            "[a]\nx=1\n",
+            "[a]\nx=1\ny=2\nz=3\nw=4\n[/a]\n",
+            "[a]\n[a]\n[a]\n[a]\n[a]\n[/a]\n[/a]\n[/a]\n[/a]\n[/a]\n",
+            "[a]\n[/a]\n&",
+        ];
+        for doc in docs {
+            let expected = recursive.parse(Vec::from(doc));
+            assert_eq!(iterative.parse(Vec::from(doc)), expected, "{:?}", doc);
+        }
+    }
+
+    #[test]
+    fn iterative_deep_nesting() {
+        let processor = DocProcessor::new().with_max_depth(usize::MAX).with_iterative_parsing();
+        let depth = 100_000;
+        let doc = format!("{}{}", "[a]\n".repeat(depth), "[/a]\n".repeat(depth));
+        let doc = processor.parse(doc.into_bytes()).unwrap();
+        assert_eq!(doc.tag_names().len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "stacker")]
+    fn stacker_deep_nesting() {
+        let processor = DocProcessor::new().with_max_depth(usize::MAX);
+        let depth = 100_000;
+        let doc = format!("{}{}", "[a]\n".repeat(depth), "[/a]\n".repeat(depth));
+        let doc = processor.parse(doc.into_bytes()).unwrap();
+        assert_eq!(doc.tag_names().len(), 1);
+    }
+
+    #[test]
+    fn parse_prefix() {
+        let first = "[version]\nversion=\"1.16\"\n[/version]\n";
+        let second = "[login]\nusername=\"lo";
+        let input = format!("{}{}", first, second);
+        for processor in [DocProcessor::new(), DocProcessor::new().with_iterative_parsing()] {
+            let (doc, rest) = processor.parse_prefix(input.as_bytes()).unwrap();
+            assert_eq!(doc, processor.parse(Vec::from(first)).unwrap());
+            assert_eq!(rest, second.as_bytes());
+            // The rest can be parsed once the document is complete.
+            let second = format!("{}l\"\n[/login]\n", String::from_utf8_lossy(rest));
+            let (doc, rest) = processor.parse_prefix(second.as_bytes()).unwrap();
+            match doc.find("login/username") {
+                Some(NodeRef::Attr(username)) => assert_eq!(username, "lol"),
+                other => panic!("{:?}", other),
+            }
+            assert!(rest.is_empty());
+        }
+        assert!(DocProcessor::new().parse(input.into_bytes()).is_err());
+        let processor = DocProcessor::new().with_max_depth(1);
+        assert_eq!(processor.parse_prefix(b"[a]\n[b]\n[/b]\n[/a]\n").unwrap_err(), ParseError::DepthExceeded { at: 4 });
+    }
+
+    #[test]
+    fn invalid_name_chars() {
+        for processor in [DocProcessor::new(), DocProcessor::new().with_iterative_parsing()] {
+            let cases: [(&[u8], usize, u8); 5] = [
+                (b"[na me]\n[/na me]\n", 3, b' '),
+                (b"key with space=x\n", 3, b' '),
+                (b"[a]\nkey$=x\n[/a]\n", 7, b'$'),
+                (b"a,b c=1,2\n", 3, b' '),
+                (b"[a]\n[/a-b]\n", 7, b'-'),
+            ];
+            for (doc, offset, byte) in cases {
+                let e = processor.parse(Vec::from(doc)).unwrap_err();
+                assert_eq!(e, ParseError::InvalidNameChar { offset, byte }, "{:?}", String::from_utf8_lossy(doc));
+            }
+            // A line which is just a name is still an error, but not a bad name.
+            assert_eq!(processor.parse(Vec::from("key\n")).unwrap_err(), ParseError::TrailingInput { offset: 0 });
+            assert!(processor.parse(Vec::from("key = x\n")).is_ok());
+        }
+    }
+
+    #[test]
+    fn implicit_close() {
+        let strict = DocProcessor::new();
+        for processor in [DocProcessor::new().with_implicit_close(), DocProcessor::new().with_implicit_close().with_iterative_parsing()] {
+            let expected = strict.parse(Vec::from("[a]x=\"1\"[/a]")).unwrap();
+            assert_eq!(processor.parse(Vec::from("[a]x=\"1\"[/]")).unwrap(), expected);
+            let expected = strict.parse(Vec::from("[a]\n[b]\ny=2\n[/b]\n[/a]\n")).unwrap();
+            assert_eq!(processor.parse(Vec::from("[a]\n[b]\ny=2\n[/]\n[/]\n")).unwrap(), expected);
+            // Named closing tags are still checked.
+            assert!(matches!(processor.parse(Vec::from("[a]\n[/b]\n")), Err(ParseError::MismatchedTag { .. })));
+            assert!(processor.parse(Vec::from("[/]\n")).is_err());
+        }
+        assert!(strict.parse(Vec::from("[a]x=\"1\"[/]")).is_err());
+    }
+
+    #[test]
+    fn last_attribute_at_eof() {
+        for processor in [DocProcessor::new(), DocProcessor::new().with_iterative_parsing()] {
+            let doc = processor.parse(Vec::from("a=\"b\"")).unwrap();
+            assert_eq!(doc, processor.parse(Vec::from("a=\"b\"\n")).unwrap());
+            assert!(processor.parse(Vec::from("a=1\nb=2")).is_ok());
+            // Attributes still need separating from each other.
+            assert!(processor.parse(Vec::from("a=\"b\" c=\"d\"")).is_err());
+            // A prefix may not have all of its last attribute yet.
+            let (doc, rest) = processor.parse_prefix(b"a=1\nb=2").unwrap();
+            assert_eq!(doc.len(), 1);
+            assert_eq!(rest, b"b=2");
+        }
+    }
 }
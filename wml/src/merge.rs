@@ -0,0 +1,150 @@
+//! Applying WML's `[+tag]` merge rules to combine documents.
+use crate::{Doc, DocBuilder, DocProcessor, NodeRef, ParseError};
+use ::std::borrow::Cow;
+
+/// A tag or attribute of a merged document, before it's written out.
+enum Merged<'d> {
+    Tag { name: &'d str, content: Vec<Merged<'d>> },
+    Attr { key: &'d str, value: Cow<'d, [u8]> },
+}
+
+impl DocProcessor {
+    /// Apply `overlay` on top of `base`, the way WML combines config assembled from fragments.
+    ///
+    /// Going through `base` and then `overlay` in order:
+    /// - an attribute replaces the value of an earlier attribute with the same key in the same tag,
+    ///   or is added after everything else there if there isn't one,
+    /// - a merge tag, `[+name]`, has its content applied in the same way to the last `[name]`
+    ///   before it in the same tag, or is added as a plain `[name]` if there isn't one,
+    /// - and any other tag is added after everything else.
+    ///
+    /// Key sequences are split up into an attribute for each key, as [`AttrRef::expand`](crate::AttrRef::expand) does.
+    /// Values are written as quoted strings, so translation markers and textdomains aren't kept.
+    /// This recurses once for every level of nesting, so don't use it on documents
+    /// too deep to parse without [`with_iterative_parsing`](Self::with_iterative_parsing).
+    ///
+    /// ```
+    /// let processor = wml::DocProcessor::new();
+    /// let base = processor.parse_borrowed(b"[a]\nx=\"1\"\n[/a]\n").unwrap();
+    /// let overlay = processor.parse_borrowed(b"[+a]\ny=\"2\"\n[/a]\n").unwrap();
+    /// let merged = processor.merge(&base, &overlay).unwrap();
+    /// assert_eq!(merged, processor.parse_borrowed(b"[a]\nx=\"1\"\ny=\"2\"\n[/a]\n").unwrap());
+    /// ```
+    pub fn merge(&self, base: &Doc<'_>, overlay: &Doc<'_>) -> Result<Doc<'_>, ParseError> {
+        let mut merged = Vec::new();
+        apply(&mut merged, base.children());
+        apply(&mut merged, overlay.children());
+        let mut builder = DocBuilder::new();
+        write(&mut builder, &merged);
+        self.parse(builder.finish())
+    }
+}
+
+fn apply<'d>(target: &mut Vec<Merged<'d>>, content: impl Iterator<Item = NodeRef<'d>>) {
+    for node in content {
+        match node {
+            NodeRef::Attr(attr) => {
+                for (key, value) in attr.expand() {
+                    let old = target.iter_mut().find_map(|node| match node {
+                        Merged::Attr { key: old_key, value } if *old_key == key => Some(value),
+                        _ => None,
+                    });
+                    match old {
+                        Some(old) => *old = value,
+                        None => target.push(Merged::Attr { key, value }),
+                    }
+                }
+            },
+            NodeRef::Tag(tag) => {
+                // Names are always ASCII.
+                let name = tag.name_str().unwrap();
+                let last = match tag.is_merge() {
+                    true => target.iter_mut().rev().find_map(|node| match node {
+                        Merged::Tag { name: last_name, content } if *last_name == name => Some(content),
+                        _ => None,
+                    }),
+                    false => None,
+                };
+                match last {
+                    Some(content) => apply(content, tag.children()),
+                    None => {
+                        let mut content = Vec::new();
+                        apply(&mut content, tag.children());
+                        target.push(Merged::Tag { name, content });
+                    },
+                }
+            },
+        }
+    }
+}
+
+fn write(builder: &mut DocBuilder, content: &[Merged<'_>]) {
+    for node in content {
+        match node {
+            Merged::Tag { name, content } => {
+                builder.push_tag(name);
+                write(builder, content);
+                builder.close_tag(name);
+            },
+            Merged::Attr { key, value } => {
+                builder.push_attr_bytes(key, value);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DocProcessor;
+    use crate::test_util::first_tag;
+
+    #[test]
+    fn merge_tags() {
+        let processor = DocProcessor::new();
+        let base = processor.parse(Vec::from("[a]\nx=\"1\"\n[/a]\n")).unwrap();
+        let overlay = processor.parse(Vec::from("[+a]\ny=\"2\"\n[/a]\n")).unwrap();
+        assert!(first_tag(&overlay).is_merge());
+        let mut written = Vec::new();
+        overlay.write_to(&mut written).unwrap();
+        assert_eq!(written, b"[+a]\ny=\"2\"\n[/a]\n");
+        let merged = processor.merge(&base, &overlay).unwrap();
+        assert_eq!(merged, processor.parse(Vec::from("[a]\nx=\"1\"\ny=\"2\"\n[/a]\n")).unwrap());
+        // A merge tag is still different from a plain one.
+        assert_ne!(overlay, processor.parse(Vec::from("[a]\ny=\"2\"\n[/a]\n")).unwrap());
+    }
+
+    #[test]
+    fn merge_keeps_bytes() {
+        let processor = DocProcessor::new();
+        let base = processor.parse(b"[a]\nx=\"l\xffl\"\n[/a]\n".to_vec()).unwrap();
+        let overlay = processor.parse(b"[+a]\ny=<<\xfe\"\xfe>>\n[/a]\n".to_vec()).unwrap();
+        let merged = processor.merge(&base, &overlay).unwrap();
+        let mut written = Vec::new();
+        merged.write_to(&mut written).unwrap();
+        assert_eq!(written, b"[a]\nx=\"l\xffl\"\ny=\"\xfe\"\"\xfe\"\n[/a]\n");
+    }
+
+    #[test]
+    fn merge_rules() {
+        let processor = DocProcessor::new().with_iterative_parsing();
+        let base = processor.parse(Vec::from(concat!(
+            "id=\"base\"\n",
+            "[side]\nside=1\n[unit]\ntype=\"Elvish Fighter\"\n[/unit]\n[/side]\n",
+            "[side]\nside=2\ncontroller=\"ai\"\n[/side]\n",
+        ))).unwrap();
+        let overlay = processor.parse(Vec::from(concat!(
+            "[+side]\ncontroller,team_name=\"human,south\"\n[+unit]\nx=1\n[/unit]\n[/side]\n",
+            "id=\"overlay\"\n",
+            "[side]\nside=3\n[/side]\n",
+            "[+event]\nname=\"start\"\n[/event]\n",
+        ))).unwrap();
+        let merged = processor.merge(&base, &overlay).unwrap();
+        assert_eq!(merged, processor.parse(Vec::from(concat!(
+            "id=\"overlay\"\n",
+            "[side]\nside=1\n[unit]\ntype=\"Elvish Fighter\"\n[/unit]\n[/side]\n",
+            "[side]\nside=2\ncontroller=\"human\"\nteam_name=\"south\"\n[unit]\nx=1\n[/unit]\n[/side]\n",
+            "[side]\nside=3\n[/side]\n",
+            "[event]\nname=\"start\"\n[/event]\n",
+        ))).unwrap());
+    }
+}
@@ -0,0 +1,121 @@
+//! Parsing without [`DocProcessor::reset`](crate::DocProcessor::reset),
+//! by giving each document its own arena, borrowed from a pool.
+use crate::{parse_in, Context, Doc, Limits, NullHandler, ParseError};
+use ::bumpalo::Bump;
+use ::core::cell::Cell;
+use ::core::mem::ManuallyDrop;
+use ::std::borrow::Cow;
+use ::std::sync::Mutex;
+
+/// A pool of arenas, each document parsed through it getting one to itself.
+///
+/// When a [`PooledDoc`] is dropped, its arena is reset and returned to the pool,
+/// so memory is still reused between documents, but there's nothing to reset by hand.
+///
+/// The pool is `Send + Sync`, so one pool may be shared between threads,
+/// which only contend on it briefly to check arenas out and back in.
+/// Arenas are never freed while the pool is alive, so it holds on to
+/// as many as were ever in use at once.
+#[derive(Debug, Default)]
+pub struct ArenaPool {
+    // Boxed so documents can borrow an arena without pinning the `PooledDoc` in place.
+    #[allow(clippy::vec_box)]
+    arenas: Mutex<Vec<Box<Bump>>>,
+    limits: Limits,
+}
+
+impl ArenaPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// See [`DocProcessor::with_max_children_per_tag`](crate::DocProcessor::with_max_children_per_tag).
+    pub fn with_max_children_per_tag(mut self, max: usize) -> Self {
+        self.limits.max_children_per_tag = max;
+        self
+    }
+    /// See [`DocProcessor::with_max_nodes`](crate::DocProcessor::with_max_nodes).
+    pub fn with_max_nodes(mut self, max: usize) -> Self {
+        self.limits.max_nodes = max;
+        self
+    }
+    pub fn parse(&self, buf: Vec<u8>) -> Result<PooledDoc<'_>, ParseError> {
+        let arena = self.arenas.lock().unwrap().pop().unwrap_or_default();
+        let nodes = Cell::new(0);
+        let cx = Context { limits: &self.limits, interner: None, nodes: &nodes, implicit_close: false, partial: false, domain: &Cell::new(None), utf8: false };
+        let doc = parse_in(&NullHandler, &arena, &cx, Cow::Owned(buf), false).map(|doc| {
+            // Safety: `PooledDoc` only hands out the document at lifetimes bounded by
+            // its own, and drops it before the arena is reset or freed.
+            unsafe { ::core::mem::transmute::<Doc<'_>, Doc<'static>>(doc) }
+        });
+        let doc = match doc {
+            Ok(doc) => doc,
+            Err(e) => {
+                self.give_back(arena);
+                return Err(e)
+            },
+        };
+        Ok(PooledDoc {
+            doc: ManuallyDrop::new(doc),
+            arena: ManuallyDrop::new(arena),
+            pool: self,
+        })
+    }
+    fn give_back(&self, mut arena: Box<Bump>) {
+        arena.reset();
+        self.arenas.lock().unwrap().push(arena);
+    }
+}
+
+/// A document parsed by an [`ArenaPool`], holding the arena it was parsed into.
+pub struct PooledDoc<'p> {
+    // Borrows from `arena`, and must be dropped before it.
+    doc: ManuallyDrop<Doc<'static>>,
+    arena: ManuallyDrop<Box<Bump>>,
+    pool: &'p ArenaPool,
+}
+
+impl PooledDoc<'_> {
+    pub fn doc(&self) -> &Doc<'_> {
+        &self.doc
+    }
+}
+
+impl ::core::fmt::Debug for PooledDoc<'_> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+        f.debug_tuple("PooledDoc").field(self.doc()).finish()
+    }
+}
+
+impl Drop for PooledDoc<'_> {
+    fn drop(&mut self) {
+        // Safety: Neither field is used again after this.
+        unsafe {
+            ManuallyDrop::drop(&mut self.doc);
+            self.pool.give_back(ManuallyDrop::take(&mut self.arena));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArenaPool;
+
+    #[test]
+    fn arenas_recycled() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ArenaPool>();
+
+        let pool = ArenaPool::new();
+        for i in 0..100 {
+            let doc = pool.parse(format!("[user]\nname=\"{}\"\n[/user]\n", i).into_bytes()).unwrap();
+            assert_eq!(doc.doc().children().count(), 1);
+        }
+        assert_eq!(pool.arenas.lock().unwrap().len(), 1);
+
+        let held = (0..3).map(|_| pool.parse(Vec::from("a=1\n")).unwrap()).collect::<Vec<_>>();
+        assert!(pool.arenas.lock().unwrap().is_empty());
+        drop(held);
+        assert!(pool.parse(Vec::from("[/a]\n")).is_err());
+        assert_eq!(pool.arenas.lock().unwrap().len(), 3);
+    }
+}
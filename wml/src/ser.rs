@@ -0,0 +1,432 @@
+//! Writing Rust types as WML with `serde`.
+use crate::DocBuilder;
+use crate::builder::is_name;
+use ::core::fmt;
+use ::serde::ser::{self, Impossible, Serialize};
+
+/// Why a value couldn't be serialized as WML.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializeError(String);
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl ::std::error::Error for SerializeError {}
+
+impl ser::Error for SerializeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+/// Serialize `value` as a WML document.
+///
+/// - A struct becomes a tag named after it, lowercased, so `Error` becomes `[error]`.
+///   `#[serde(rename = "...")]` gives it another name.
+///   A sequence of structs becomes one tag for each, and a map becomes the top level of the document.
+/// - Inside that, fields holding scalars become attributes, written as [`DocBuilder::push_attr`] does.
+///   Booleans are written as `yes` or `no`.
+/// - Fields holding structs or maps become tags named after the field,
+///   and fields holding sequences of them become a tag for each.
+///   A sequence of scalars becomes one attribute, with the scalars separated by commas.
+/// - Fields holding `None`, or an empty sequence, are left out.
+///
+/// Enums can only be written as attribute values, with unit variants written by name.
+/// Every tag and attribute name, including map keys, must be a valid WML name,
+/// made of ASCII letters, digits and `_`, or nothing is written.
+///
+/// ```
+/// #[derive(serde::Serialize)]
+/// struct Error {
+///     message: String,
+/// }
+/// let error = wml::to_vec(&Error { message: "Nope.".into() }).unwrap();
+/// assert_eq!(error, b"[error]\nmessage=\"Nope.\"\n[/error]\n");
+/// ```
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, SerializeError> {
+    let mut builder = DocBuilder::new();
+    match value.serialize(ValueSerializer)? {
+        Value::Tag { name, fields } => write_top(&mut builder, name, fields),
+        Value::List(items) => items.into_iter().try_for_each(|item| match item {
+            Value::Tag { name: name @ Some(_), fields } => write_top(&mut builder, name, fields),
+            _ => Err(ser::Error::custom("only sequences of structs can be written as documents")),
+        }),
+        _ => Err(ser::Error::custom("only structs, maps, and sequences of structs can be written as documents")),
+    }?;
+    Ok(builder.finish())
+}
+
+/// Write a struct, named `name`, as a tag, or a map, with no name, as the top level.
+fn write_top(builder: &mut DocBuilder, name: Option<&str>, fields: Vec<(String, Value)>) -> Result<(), SerializeError> {
+    match name {
+        Some(name) => {
+            let name = name.to_ascii_lowercase();
+            check_name(&name)?;
+            builder.push_tag(&name);
+            write_fields(builder, fields)?;
+            builder.close_tag(&name);
+            Ok(())
+        },
+        None => write_fields(builder, fields),
+    }
+}
+
+/// Fail unless `name` is a valid WML name, so a key can't smuggle other WML into the document.
+fn check_name(name: &str) -> Result<(), SerializeError> {
+    if is_name(name) {
+        Ok(())
+    } else {
+        Err(ser::Error::custom(format!("{:?} isn't a valid WML name", name)))
+    }
+}
+
+fn write_fields(builder: &mut DocBuilder, fields: Vec<(String, Value)>) -> Result<(), SerializeError> {
+    for (key, value) in fields {
+        if !matches!(value, Value::None) {
+            check_name(&key)?;
+        }
+        match value {
+            Value::None => (),
+            Value::Scalar(value) => {
+                builder.push_attr(&key, value);
+            },
+            Value::Tag { fields, .. } => {
+                builder.push_tag(&key);
+                write_fields(builder, fields)?;
+                builder.close_tag(&key);
+            },
+            Value::List(items) if items.iter().all(|item| matches!(item, Value::Scalar(_))) => {
+                if items.is_empty() {
+                    continue
+                }
+                let items = items.into_iter().map(|item| match item {
+                    Value::Scalar(value) => value,
+                    _ => unreachable!(),
+                });
+                builder.push_attr(&key, items.collect::<Vec<_>>().join(","));
+            },
+            Value::List(items) => {
+                for item in items {
+                    match item {
+                        Value::Tag { fields, .. } => {
+                            builder.push_tag(&key);
+                            write_fields(builder, fields)?;
+                            builder.close_tag(&key);
+                        },
+                        _ => return Err(ser::Error::custom(format!("`{}` mixes tags with other values", key))),
+                    }
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+/// What a value serializes to, before it's known where it goes.
+enum Value {
+    /// `None`, which isn't written at all.
+    None,
+    Scalar(String),
+    /// A struct, with its name, or a map, without one.
+    Tag { name: Option<&'static str>, fields: Vec<(String, Value)> },
+    List(Vec<Value>),
+}
+
+struct ValueSerializer;
+
+impl ValueSerializer {
+    fn scalar(value: impl fmt::Display) -> Result<Value, SerializeError> {
+        Ok(Value::Scalar(value.to_string()))
+    }
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = Impossible<Value, SerializeError>;
+    type SerializeMap = TagSerializer;
+    type SerializeStruct = TagSerializer;
+    type SerializeStructVariant = Impossible<Value, SerializeError>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, SerializeError> {
+        Self::scalar(if v { "yes" } else { "no" })
+    }
+    fn serialize_i8(self, v: i8) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_i16(self, v: i16) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_i32(self, v: i32) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_i64(self, v: i64) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_i128(self, v: i128) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_u8(self, v: u8) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_u16(self, v: u16) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_u32(self, v: u32) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_u64(self, v: u64) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_u128(self, v: u128) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_f32(self, v: f32) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_f64(self, v: f64) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_char(self, v: char) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_str(self, v: &str) -> Result<Value, SerializeError> {
+        Self::scalar(v)
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, SerializeError> {
+        let v = ::core::str::from_utf8(v).map_err(|_| ser::Error::custom("bytes aren't valid UTF-8"))?;
+        Self::scalar(v)
+    }
+    fn serialize_none(self) -> Result<Value, SerializeError> {
+        Ok(Value::None)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, SerializeError> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Value, SerializeError> {
+        Self::scalar("")
+    }
+    /// Unit structs are empty tags, like `[mustlogin]\n[/mustlogin]\n`.
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Value, SerializeError> {
+        Ok(Value::Tag { name: Some(name), fields: Vec::new() })
+    }
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<Value, SerializeError> {
+        Self::scalar(variant)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Value, SerializeError> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _value: &T,
+    ) -> Result<Value, SerializeError> {
+        Err(unsupported_variant(name, variant))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer, SerializeError> {
+        Ok(ListSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer, SerializeError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<ListSerializer, SerializeError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, SerializeError> {
+        Err(unsupported_variant(name, variant))
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<TagSerializer, SerializeError> {
+        Ok(TagSerializer { name: None, fields: Vec::with_capacity(len.unwrap_or(0)), key: None })
+    }
+    fn serialize_struct(self, name: &'static str, len: usize) -> Result<TagSerializer, SerializeError> {
+        Ok(TagSerializer { name: Some(name), fields: Vec::with_capacity(len), key: None })
+    }
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, SerializeError> {
+        Err(unsupported_variant(name, variant))
+    }
+}
+
+fn unsupported_variant(name: &str, variant: &str) -> SerializeError {
+    ser::Error::custom(format!("{}::{} can't be written as WML, only unit variants can", name, variant))
+}
+
+struct ListSerializer(Vec<Value>);
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        self.0.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+    fn end(self) -> Result<Value, SerializeError> {
+        Ok(Value::List(self.0))
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+    fn end(self) -> Result<Value, SerializeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+    fn end(self) -> Result<Value, SerializeError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct TagSerializer {
+    name: Option<&'static str>,
+    fields: Vec<(String, Value)>,
+    /// The key of the map entry being serialized.
+    key: Option<String>,
+}
+
+impl ser::SerializeMap for TagSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerializeError> {
+        match key.serialize(ValueSerializer)? {
+            Value::Scalar(key) => {
+                self.key = Some(key);
+                Ok(())
+            },
+            _ => Err(ser::Error::custom("map keys must be strings or numbers")),
+        }
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        let key = self.key.take().ok_or_else(|| ser::Error::custom("map value given before its key"))?;
+        self.fields.push((key, value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+    fn end(self) -> Result<Value, SerializeError> {
+        Ok(Value::Tag { name: self.name, fields: self.fields })
+    }
+}
+
+impl ser::SerializeStruct for TagSerializer {
+    type Ok = Value;
+    type Error = SerializeError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), SerializeError> {
+        self.fields.push((key.into(), value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+    fn end(self) -> Result<Value, SerializeError> {
+        Ok(Value::Tag { name: self.name, fields: self.fields })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_vec;
+    use crate::{from_doc, DocProcessor};
+    use ::serde::{Deserialize, Serialize};
+    use ::std::collections::BTreeMap;
+
+    #[test]
+    fn serialize_error() {
+        #[derive(Serialize)]
+        struct Error {
+            message: String,
+        }
+        let error = to_vec(&Error { message: "You said \"hi\".".into() }).unwrap();
+        assert_eq!(String::from_utf8(error).unwrap(), "[error]\nmessage=\"You said \"\"hi\"\".\"\n[/error]\n");
+
+        #[derive(Serialize)]
+        struct MustLogin;
+        assert_eq!(to_vec(&MustLogin).unwrap(), b"[mustlogin]\n[/mustlogin]\n");
+    }
+
+    #[test]
+    fn hostile_keys() {
+        let mut map = BTreeMap::new();
+        map.insert("a=\"1\"\n[admin]\nx", "y");
+        let err = to_vec(&map).unwrap_err();
+        assert_eq!(err.to_string(), "\"a=\\\"1\\\"\\n[admin]\\nx\" isn't a valid WML name");
+        let mut map = BTreeMap::new();
+        map.insert("bad name", BTreeMap::from([("x", "y")]));
+        assert!(to_vec(&map).is_err());
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Game {
+            id: u32,
+            name: String,
+            password: Option<bool>,
+            observer: bool,
+            #[serde(default)]
+            slot: Vec<Slot>,
+        }
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Slot {
+            #[serde(rename = "type")]
+            kind: Kind,
+        }
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[serde(rename_all = "lowercase")]
+        enum Kind {
+            Human,
+            Ai,
+        }
+        let games = vec![
+            Game {
+                id: 1,
+                name: "first".into(),
+                password: None,
+                observer: true,
+                slot: vec![Slot { kind: Kind::Human }, Slot { kind: Kind::Ai }],
+            },
+            Game { id: 2, name: "second".into(), password: Some(false), observer: false, slot: Vec::new() },
+        ];
+        let out = to_vec(&games).unwrap();
+        assert_eq!(String::from_utf8(out.clone()).unwrap(), concat!(
+            "[game]\nid=\"1\"\nname=\"first\"\nobserver=\"yes\"\n",
+            "[slot]\ntype=\"human\"\n[/slot]\n[slot]\ntype=\"ai\"\n[/slot]\n[/game]\n",
+            "[game]\nid=\"2\"\nname=\"second\"\npassword=\"no\"\nobserver=\"no\"\n[/game]\n",
+        ));
+        let processor = DocProcessor::new();
+        let doc = processor.parse(out).unwrap();
+        assert_eq!(from_doc::<Vec<Game>>(&doc).unwrap(), games);
+
+        let mut top = BTreeMap::new();
+        top.insert("version", vec!["1.16.0"]);
+        top.insert("sides", vec!["1", "2"]);
+        top.insert("none", Vec::new());
+        assert_eq!(to_vec(&top).unwrap(), b"sides=\"1,2\"\nversion=\"1.16.0\"\n");
+        assert!(to_vec(&1).is_err());
+        assert!(to_vec(&[1, 2]).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! Writing parsed documents back out as WML.
+use crate::{Attribute, Doc, TagOrAttr, ValueComponent};
+use ::std::io::{self, Write};
+
+impl Doc<'_> {
+    /// Serialize this document as canonical WML.
+    ///
+    /// Tags and attributes are written in the order they were parsed, one per line,
+    /// with nothing indented and every line ending in `\n`.
+    /// Key sequences and `#textdomain` directives are kept. Values are normalized:
+    /// - Quoted strings are written as they appeared, `key="value"`.
+    /// - Unquoted text is trimmed and written as a quoted string, escaping `"` as `""`.
+    /// - Raw strings are written as raw strings, `<<value>>`, so their content is untouched.
+    /// - Strings marked for translation keep their `_`, as in `_"value"`.
+    /// - Components joined with `+` are joined with ` + `, or with `+` and a line break
+    ///   when a `#textdomain` comes between them.
+    /// - Comments and blank lines aren't kept by the parser, so they aren't written.
+    ///
+    /// Input already written this way is reproduced byte for byte.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        Out { w, indent: b"", depth: 0 }.content(&self.text, &self.top)
+    }
+    /// Like [`write_to`](Self::write_to), but with each line indented by `indent`
+    /// once for every tag it's inside, for people to read.
+    ///
+    /// Lines within multi-line strings aren't indented, since that would change their values.
+    pub fn write_pretty<W: Write>(&self, w: &mut W, indent: &str) -> io::Result<()> {
+        Out { w, indent: indent.as_bytes(), depth: 0 }.content(&self.text, &self.top)
+    }
+}
+
+/// Where a document is being written, and how deep in it the writer is.
+struct Out<'w, W> {
+    w: &'w mut W,
+    indent: &'w [u8],
+    depth: usize,
+}
+
+impl<W: Write> Out<'_, W> {
+    /// Indent the start of a line for the current depth.
+    fn line(&mut self) -> io::Result<()> {
+        for _ in 0..self.depth {
+            self.w.write_all(self.indent)?;
+        }
+        Ok(())
+    }
+    fn content(&mut self, text: &[u8], content: &[TagOrAttr<'_>]) -> io::Result<()> {
+        for node in content {
+            match node {
+                TagOrAttr::Tag(tag) => {
+                    let name = tag.name.content.get(text);
+                    self.line()?;
+                    self.w.write_all(if tag.merge { b"[+" } else { b"[" })?;
+                    self.w.write_all(name)?;
+                    self.w.write_all(b"]\n")?;
+                    self.depth += 1;
+                    self.content(text, &tag.content)?;
+                    self.depth -= 1;
+                    self.line()?;
+                    self.w.write_all(b"[/")?;
+                    self.w.write_all(name)?;
+                    self.w.write_all(b"]\n")?;
+                },
+                TagOrAttr::Attr(attr) => self.attribute(text, attr)?,
+            }
+        }
+        Ok(())
+    }
+    fn attribute(&mut self, text: &[u8], attr: &Attribute<'_>) -> io::Result<()> {
+        if let Some(domain) = &attr.domain {
+            self.line()?;
+            self.w.write_all(b"#textdomain ")?;
+            self.w.write_all(domain.name.get(text))?;
+            self.w.write_all(b"\n")?;
+        }
+        let keys = &attr.key_sequence;
+        self.line()?;
+        self.w.write_all(keys.first.content.get(text))?;
+        for name in keys.names.iter() {
+            self.w.write_all(b",")?;
+            self.w.write_all(name.content.get(text))?;
+        }
+        self.w.write_all(b"=")?;
+        write_component(self.w, text, &attr.value.first)?;
+        for (domain, component) in attr.value.rest.iter() {
+            match domain {
+                Some(domain) => {
+                    self.w.write_all(b" +\n")?;
+                    self.line()?;
+                    self.w.write_all(b"#textdomain ")?;
+                    self.w.write_all(domain.name.get(text))?;
+                    self.w.write_all(b"\n")?;
+                    self.line()?;
+                },
+                None => self.w.write_all(b" + ")?,
+            }
+            write_component(self.w, text, component)?;
+        }
+        self.w.write_all(b"\n")
+    }
+}
+
+fn write_component<W: Write>(w: &mut W, text: &[u8], component: &ValueComponent<'_>) -> io::Result<()> {
+    if component.is_translatable() {
+        w.write_all(b"_")?;
+    }
+    match component {
+        // Already escaped the way we want it.
+        ValueComponent::String(s) => {
+            w.write_all(b"\"")?;
+            w.write_all(s.content.get(text))?;
+            w.write_all(b"\"")
+        },
+        ValueComponent::RawString(r) => {
+            w.write_all(b"<<")?;
+            w.write_all(r.content.get(text))?;
+            w.write_all(b">>")
+        },
+        // Whatever a handler replaced content with may need escaping, even in a string.
+        ValueComponent::Text(_) | ValueComponent::Replaced(..) => {
+            w.write_all(b"\"")?;
+            for chunk in component.resolve(text).split_inclusive(|&b| b == b'"') {
+                w.write_all(chunk)?;
+                if chunk.ends_with(b"\"") {
+                    w.write_all(b"\"")?;
+                }
+            }
+            w.write_all(b"\"")
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DocProcessor, NodeRef};
+    use crate::test_util::USERS;
+    use ::proptest::prelude::*;
+
+    #[test]
+    fn write_round_trip() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(USERS)).unwrap();
+        let mut out = Vec::new();
+        doc.write_to(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), USERS);
+    }
+
+    #[test]
+    fn write_normalized() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "# comment\n",
+            "[message]\n",
+            "\t#textdomain wesnoth\n",
+            "a,b=  say \"hi\"  +_\"x\"\"y\"+<<raw\n\"text\">>\n",
+            "c=\"1\" +\n#textdomain wesnoth-lib\n\"2\"\n",
+            "[/message]\n",
+        ))).unwrap();
+        let mut out = Vec::new();
+        doc.write_to(&mut out).unwrap();
+        let expected = concat!(
+            "[message]\n",
+            "#textdomain wesnoth\n",
+            "a,b=\"say \"\"hi\"\"\" + _\"x\"\"y\" + <<raw\n\"text\">>\n",
+            "c=\"1\" +\n#textdomain wesnoth-lib\n\"2\"\n",
+            "[/message]\n",
+        );
+        assert_eq!(String::from_utf8(out.clone()).unwrap(), expected);
+        assert_eq!(processor.parse(out).unwrap(), doc);
+    }
+
+    #[test]
+    fn write_indented() {
+        let processor = DocProcessor::new();
+        let source = "[gamelist]\n[game]\nname=\"x\"\ndesc=<<two\nlines>>\n[/game]\nsize=1\n[/gamelist]\n";
+        let doc = processor.parse(Vec::from(source)).unwrap();
+        let mut out = Vec::new();
+        doc.write_pretty(&mut out, "  ").unwrap();
+        assert_eq!(String::from_utf8(out.clone()).unwrap(), concat!(
+            "[gamelist]\n",
+            "  [game]\n",
+            "    name=\"x\"\n",
+            "    desc=<<two\nlines>>\n",
+            "  [/game]\n",
+            "  size=\"1\"\n",
+            "[/gamelist]\n",
+        ));
+        assert_eq!(processor.parse(out).unwrap(), doc);
+    }
+
+    /// A tag or attribute to generate WML from, for checking that parsing and writing agree.
+    #[derive(Debug, Clone)]
+    enum Node {
+        Tag(String, Vec<Node>),
+        Attr { domain: Option<String>, keys: Vec<String>, components: Vec<(Option<String>, Component)> },
+    }
+
+    #[derive(Debug, Clone)]
+    enum Component {
+        Text(String),
+        String { translatable: bool, content: String },
+        Raw { translatable: bool, content: String },
+    }
+    impl Component {
+        /// The value this resolves to once parsed.
+        fn value(&self) -> &str {
+            match self {
+                Self::Text(content) | Self::String { content, .. } | Self::Raw { content, .. } => content,
+            }
+        }
+        fn is_translatable(&self) -> bool {
+            matches!(self, Self::String { translatable: true, .. } | Self::Raw { translatable: true, .. })
+        }
+    }
+
+    /// Strings of `len` characters from `alphabet`.
+    fn chars(alphabet: &'static str, len: ::core::ops::Range<usize>) -> impl Strategy<Value = String> {
+        prop::collection::vec(prop::sample::select(alphabet.chars().collect::<Vec<_>>()), len)
+            .prop_map(|chars| chars.into_iter().collect())
+    }
+
+    fn component() -> impl Strategy<Value = Component> {
+        prop_oneof![
+            // Text starting with a quote would be a string instead.
+            chars("ab z,0\"#", 1..8).prop_filter_map("blank text", |text| {
+                let text = text.trim();
+                (!text.is_empty() && !text.starts_with('"')).then(|| Component::Text(text.into()))
+            }),
+            (any::<bool>(), chars("ab \"\n+[]#", 0..8))
+                .prop_map(|(translatable, content)| Component::String { translatable, content }),
+            // A `>` right before the closing `>>` would be read as closing it instead.
+            (any::<bool>(), chars("ab >\"\n", 0..8).prop_filter("ends raw string", |content| {
+                !content.contains(">>") && !content.ends_with('>')
+            })).prop_map(|(translatable, content)| Component::Raw { translatable, content }),
+        ]
+    }
+
+    fn node() -> impl Strategy<Value = Node> {
+        let name = || chars("abxz_09", 1..6);
+        let domain = || chars("wesnoth-_", 1..8);
+        let attr = (
+            prop::option::of(domain()),
+            prop::collection::vec(name(), 1..3),
+            component(),
+            prop::collection::vec((prop::option::of(domain()), component()), 0..3),
+        ).prop_map(|(domain, keys, first, rest)| {
+            let components = ::core::iter::once((None, first)).chain(rest).collect();
+            Node::Attr { domain, keys, components }
+        });
+        attr.prop_recursive(4, 32, 4, move |inner| {
+            (name(), prop::collection::vec(inner, 0..4)).prop_map(|(name, content)| Node::Tag(name, content))
+        })
+    }
+
+    /// Write `nodes` as WML, with its own spacing, so it isn't already in canonical form.
+    fn render(nodes: &[Node], out: &mut String) {
+        for node in nodes {
+            match node {
+                Node::Tag(name, content) => {
+                    out.push_str(&format!("[{}]\n", name));
+                    render(content, out);
+                    out.push_str(&format!("[/{}]\n", name));
+                },
+                Node::Attr { domain, keys, components } => {
+                    if let Some(domain) = domain {
+                        out.push_str(&format!("#textdomain {}\n", domain));
+                    }
+                    out.push_str(&keys.join(","));
+                    out.push_str(" = ");
+                    for (n, (domain, component)) in components.iter().enumerate() {
+                        match domain {
+                            Some(domain) => out.push_str(&format!("+\n#textdomain {}\n", domain)),
+                            None if n > 0 => out.push_str(" +  "),
+                            None => (),
+                        }
+                        if component.is_translatable() {
+                            out.push('_');
+                        }
+                        match component {
+                            Component::Text(text) => out.push_str(text),
+                            Component::String { content, .. } => out.push_str(&format!("\"{}\"", content.replace('"', "\"\""))),
+                            Component::Raw { content, .. } => out.push_str(&format!("<<{}>>", content)),
+                        }
+                    }
+                    out.push('\n');
+                },
+            }
+        }
+    }
+
+    /// Check that `parsed` holds exactly what `nodes` describes.
+    fn check<'d>(parsed: impl Iterator<Item = NodeRef<'d>>, nodes: &[Node]) {
+        let parsed = parsed.collect::<Vec<_>>();
+        assert_eq!(parsed.len(), nodes.len());
+        for (parsed, node) in parsed.into_iter().zip(nodes) {
+            match (parsed, node) {
+                (NodeRef::Tag(tag), Node::Tag(name, content)) => {
+                    assert_eq!(tag.name_str(), Ok(&**name));
+                    check(tag.children(), content);
+                },
+                (NodeRef::Attr(attr), Node::Attr { domain, keys, components }) => {
+                    assert_eq!(attr.key_str(), Ok(&*keys.join(",")));
+                    assert_eq!(attr.textdomain(), domain.as_deref());
+                    let parsed = attr.components().collect::<Vec<_>>();
+                    assert_eq!(parsed.len(), components.len());
+                    for (n, (parsed, (domain, component))) in parsed.into_iter().zip(components).enumerate() {
+                        assert_eq!(&*parsed.value(), component.value().as_bytes());
+                        assert_eq!(parsed.is_translatable(), component.is_translatable());
+                        if n > 0 {
+                            assert_eq!(parsed.textdomain(), domain.as_deref());
+                        }
+                    }
+                },
+                (parsed, node) => panic!("parsed {:?} for {:?}", parsed, node),
+            }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn write_parse_round_trip(nodes in prop::collection::vec(node(), 0..5)) {
+            let processor = DocProcessor::new();
+            let mut source = String::new();
+            render(&nodes, &mut source);
+            let doc = processor.parse(source.into_bytes()).unwrap();
+            check(doc.children(), &nodes);
+            let mut out = Vec::new();
+            doc.write_to(&mut out).unwrap();
+            let written = processor.parse(out.clone()).unwrap();
+            check(written.children(), &nodes);
+            prop_assert_eq!(&written, &doc);
+            // Canonical output is reproduced exactly.
+            let mut again = Vec::new();
+            written.write_to(&mut again).unwrap();
+            prop_assert_eq!(again, out);
+            let mut pretty = Vec::new();
+            doc.write_pretty(&mut pretty, "\t").unwrap();
+            prop_assert_eq!(&processor.parse(pretty).unwrap(), &doc);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Output in the formatting produced by `simple_wml`, the WML library used by `wesnothd`.
+use crate::{Attribute, Doc, Effects, Printer, StringKey, TagOrAttr, ValueComponent};
+
+impl Doc<'_> {
+    /// Serialize this document exactly as `simple_wml` would.
+    ///
+    /// `simple_wml` normalizes documents in a few ways when it writes them back out:
+    /// - Within a tag (and at the top level), every attribute is written before any child tag.
+    ///   Attributes keep their relative order, as do child tags.
+    /// - Every value is written as a single double quoted string, `key="value"`,
+    ///   with `"` escaped as `""`. Concatenated components (`+`) are joined,
+    ///   raw strings (`<<...>>`) are turned into quoted strings, and unquoted text
+    ///   has surrounding whitespace trimmed.
+    /// - Translation markers (`_`) and `#textdomain` directives are dropped,
+    ///   since `simple_wml` doesn't keep them.
+    /// - Key sequences (`a,b=...`) are written as they appear in the source.
+    /// - Every line, including the last, ends in a single `\n`, and nothing is indented.
+    pub fn to_simple_wml_compatible(&self) -> Vec<u8> {
+        let printer = Printer::new(&self.text);
+        let mut out = Vec::with_capacity(self.text.len());
+        write_content(&printer, &self.top, &|_| true, &mut out);
+        out
+    }
+    /// Like [`to_simple_wml_compatible`](Self::to_simple_wml_compatible), but keeping only
+    /// the top level tags named in `names`, for relaying part of a document.
+    ///
+    /// Top level attributes are kept if any of their keys are in `names`.
+    /// Everything inside a kept tag is kept.
+    pub fn to_simple_wml_retaining(&self, names: &[&str]) -> Vec<u8> {
+        let printer = Printer::new(&self.text);
+        let allowed = |key: StringKey| names.iter().any(|name| key.get(&self.text) == name.as_bytes());
+        let keep = |node: &TagOrAttr<'_>| match node {
+            TagOrAttr::Tag(tag) => allowed(tag.name.content),
+            TagOrAttr::Attr(attr) => {
+                let keys = &attr.key_sequence;
+                ::core::iter::once(&keys.first).chain(keys.names.iter()).any(|key| allowed(key.content))
+            },
+        };
+        let mut out = Vec::new();
+        write_content(&printer, &self.top, &keep, &mut out);
+        out
+    }
+}
+
+/// Write every node in `content` that `keep` accepts, and everything inside them.
+fn write_content<E: Effects>(e: &E, content: &[TagOrAttr<'_>], keep: &dyn Fn(&TagOrAttr<'_>) -> bool, out: &mut Vec<u8>) {
+    for node in content.iter().filter(|node| keep(node)) {
+        if let TagOrAttr::Attr(attr) = node {
+            write_attribute(e, attr, out);
+        }
+    }
+    for node in content.iter().filter(|node| keep(node)) {
+        if let TagOrAttr::Tag(tag) = node {
+            let name = e.get_bytes(tag.name.content).unwrap();
+            out.extend_from_slice(if tag.merge { b"[+" } else { b"[" });
+            out.extend_from_slice(name);
+            out.extend_from_slice(b"]\n");
+            write_content(e, &tag.content, &|_| true, out);
+            out.extend_from_slice(b"[/");
+            out.extend_from_slice(name);
+            out.extend_from_slice(b"]\n");
+        }
+    }
+}
+
+fn write_attribute<E: Effects>(e: &E, attr: &Attribute<'_>, out: &mut Vec<u8>) {
+    let keys = &attr.key_sequence;
+    out.extend_from_slice(e.get_bytes(keys.first.content).unwrap());
+    for name in keys.names.iter() {
+        out.push(b',');
+        out.extend_from_slice(e.get_bytes(name.content).unwrap());
+    }
+    out.extend_from_slice(b"=\"");
+    let value = &attr.value;
+    for component in ::core::iter::once(&value.first).chain(value.rest.iter().map(|(_, c)| c)) {
+        match component {
+            // Already escaped the way we want it.
+            ValueComponent::String(s) => out.extend_from_slice(e.get_bytes(s.content).unwrap()),
+            ValueComponent::RawString(r) => write_escaped(e.get_bytes(r.content).unwrap(), out),
+            ValueComponent::Text(t) => write_escaped(trim(e.get_bytes(t.content).unwrap()), out),
+            ValueComponent::Replaced(component, content) => write_escaped(&component.resolve_content(content), out),
+        }
+    }
+    out.extend_from_slice(b"\"\n");
+}
+
+fn write_escaped(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        if byte == b'"' {
+            out.push(b'"');
+        }
+        out.push(byte);
+    }
+}
+
+fn trim(mut bytes: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t' | b'\r', rest @ ..] = bytes {
+        bytes = rest;
+    }
+    while let [rest @ .., b' ' | b'\t' | b'\r'] = bytes {
+        bytes = rest;
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DocProcessor;
+    use crate::test_util::USER;
+
+    #[test]
+    fn simple_wml_formatting() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "[user]\n",
+            "[info]\n",
+            "x=  1 \n",
+            "[/info]\n",
+            "name=_\"lol\"\n",
+            "#textdomain wesnoth\n",
+            "bio=<<say \"hi\">> + \" and \"\"bye\"\"\"\n",
+            "[/user]\n",
+            "a,b=\"c\"\n",
+        ))).unwrap();
+        assert_eq!(String::from_utf8(doc.to_simple_wml_compatible()).unwrap(), concat!(
+            "a,b=\"c\"\n",
+            "[user]\n",
+            "name=\"lol\"\n",
+            "bio=\"say \"\"hi\"\" and \"\"bye\"\"\"\n",
+            "[info]\n",
+            "x=\"1\"\n",
+            "[/info]\n",
+            "[/user]\n",
+        ));
+    }
+
+    #[test]
+    fn simple_wml_round_trip() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(USER)).unwrap();
+        assert_eq!(doc.to_simple_wml_compatible(), USER.as_bytes());
+    }
+
+    #[test]
+    fn retain_gamelist() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "[user]\nname=\"a\"\n[/user]\n",
+            "[gamelist]\n[game]\nname=\"x\"\n[/game]\n[/gamelist]\n",
+            "gamelist=\"yes\"\n",
+            "version=\"1.16\"\n",
+            "[user]\nname=\"b\"\n[/user]\n",
+        ))).unwrap();
+        assert_eq!(String::from_utf8(doc.to_simple_wml_retaining(&["gamelist"])).unwrap(), concat!(
+            "gamelist=\"yes\"\n",
+            "[gamelist]\n[game]\nname=\"x\"\n[/game]\n[/gamelist]\n",
+        ));
+    }
+}
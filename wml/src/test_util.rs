@@ -0,0 +1,30 @@
+//! Fixtures and helpers shared by the tests in this crate.
+use crate::{Doc, NodeRef, TagRef};
+
+/// A `[user]` tag named `$name`, as the server lists users in the lobby.
+macro_rules! user {
+    ($name:literal) => {
+        concat!(
+            "[user]\navailable=\"yes\"\nforum_id=\"0\"\ngame_id=\"0\"\nlocation=\"\"\n",
+            "moderator=\"no\"\nname=\"", $name, "\"\nregistered=\"no\"\nstatus=\"lobby\"\n[/user]\n",
+        )
+    };
+}
+
+/// One user in the lobby, `lol`.
+pub(crate) const USER: &str = user!("lol");
+/// Two users in the lobby, `lol` and then `haha`.
+pub(crate) const USERS: &str = concat!(user!("lol"), user!("haha"));
+
+/// The top level of `doc`, which must all be tags.
+pub(crate) fn tags<'d>(doc: &'d Doc<'_>) -> impl Iterator<Item = TagRef<'d>> {
+    doc.children().map(|node| match node {
+        NodeRef::Tag(tag) => tag,
+        NodeRef::Attr(_) => panic!("expected a tag"),
+    })
+}
+
+/// The first thing at the top level of `doc`, which must be a tag.
+pub(crate) fn first_tag<'d>(doc: &'d Doc<'_>) -> TagRef<'d> {
+    tags(doc).next().expect("expected a tag")
+}
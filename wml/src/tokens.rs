@@ -0,0 +1,353 @@
+//! Line at a time tokenization, for syntax highlighting in editors.
+//!
+//! Unlike [`DocProcessor::parse`](crate::DocProcessor::parse), this never fails:
+//! anything which can't be where it is gets an [`TokenKind::Invalid`] token,
+//! so a half typed document still highlights sensibly.
+use crate::{closing_tag_ahead, Name};
+use ::core::ops::Range;
+
+/// What kind of thing a [`Token`] is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    /// `[`, `[/` or `]` around a tag name.
+    Bracket,
+    /// The name of a tag.
+    TagName,
+    /// An attribute key.
+    Key,
+    /// `=`, `,` between keys, `+` between value components, or a `_` translation marker.
+    Operator,
+    /// A value component: unquoted text, or a string or raw string with its delimiters.
+    /// A string spanning several lines gets a token on each of them.
+    Value,
+    /// A `#textdomain` directive.
+    Directive,
+    /// A comment, running to the end of the line.
+    Comment,
+    /// Something which can't appear where it does, running to the end of the line.
+    Invalid,
+}
+
+/// A token within a single line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Where the token is, in bytes from the start of the line.
+    pub span: Range<usize>,
+}
+
+/// What the tokenizer carries from the end of one line to the start of the next.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum LineState {
+    /// Expecting a tag or attribute, as at the start of a document.
+    #[default]
+    Start,
+    /// The previous line ended with a `+`, so another value component is coming.
+    Continuation,
+    /// Inside a quoted string.
+    InString,
+    /// Inside a raw string.
+    InRawString,
+}
+
+/// Tokenize one line, given the state the previous line ended in.
+///
+/// Returns the line's tokens, in order, and the state to tokenize the next line with.
+/// A trailing `\n` or `\r\n` on `line` is ignored.
+///
+/// ```
+/// use wml::{tokenize_line, LineState, TokenKind};
+/// let (tokens, state) = tokenize_line(b"text=<<multiple", LineState::Start);
+/// assert_eq!(state, LineState::InRawString);
+/// assert_eq!(tokens.last().unwrap().kind, TokenKind::Value);
+/// let (_, state) = tokenize_line(b"lines>>", state);
+/// assert_eq!(state, LineState::Start);
+/// ```
+pub fn tokenize_line(line: &[u8], state: LineState) -> (Vec<Token>, LineState) {
+    let mut lexer = Lexer { line, pos: 0, tokens: Vec::new() };
+    let state = match state {
+        LineState::Start => lexer.statements(),
+        LineState::Continuation => lexer.continuation(),
+        LineState::InString => lexer.resume(Lexer::string_body, LineState::InString),
+        LineState::InRawString => lexer.resume(Lexer::raw_string_body, LineState::InRawString),
+    };
+    (lexer.tokens, state)
+}
+
+struct Lexer<'l> {
+    line: &'l [u8],
+    pos: usize,
+    tokens: Vec<Token>,
+}
+impl<'l> Lexer<'l> {
+    fn rest(&self) -> &'l [u8] {
+        &self.line[self.pos..]
+    }
+    fn push(&mut self, kind: TokenKind, start: usize) {
+        self.tokens.push(Token { kind, span: start .. self.pos });
+    }
+    fn take(&mut self, kind: TokenKind, len: usize) {
+        self.pos += len;
+        self.push(kind, self.pos - len);
+    }
+    fn skip_space(&mut self) {
+        while let [b' ' | b'\t' | b'\r' | b'\n', ..] = self.rest() {
+            self.pos += 1;
+        }
+    }
+    /// Everything left on the line, without the line ending.
+    fn take_line(&mut self, kind: TokenKind) {
+        let mut len = self.rest().len();
+        while let [.., b'\r' | b'\n'] = &self.rest()[..len] {
+            len -= 1;
+        }
+        self.take(kind, len);
+        self.pos = self.line.len();
+    }
+
+    /// Tags, attributes and comments, until the end of the line.
+    fn statements(&mut self) -> LineState {
+        loop {
+            self.skip_space();
+            match self.rest() {
+                [] => return LineState::Start,
+                [b'#', ..] => {
+                    let kind = if self.rest().starts_with(b"#textdomain") {
+                        TokenKind::Directive
+                    } else {
+                        TokenKind::Comment
+                    };
+                    self.take_line(kind);
+                },
+                [b'[', b'/' | b'+', ..] => {
+                    self.take(TokenKind::Bracket, 2);
+                    self.tag_name();
+                },
+                [b'[', ..] => {
+                    self.take(TokenKind::Bracket, 1);
+                    self.tag_name();
+                },
+                _ => match self.keys() {
+                    Ok(()) => match self.value() {
+                        LineState::Start => (),
+                        state => return state,
+                    },
+                    Err(()) => self.take_line(TokenKind::Invalid),
+                },
+            }
+        }
+    }
+    fn tag_name(&mut self) {
+        if let Ok((rest, _)) = Name::parse(self.rest(), 0) {
+            self.take(TokenKind::TagName, self.rest().len() - rest.len());
+        }
+        match self.rest() {
+            [b']', ..] => self.take(TokenKind::Bracket, 1),
+            _ => self.take_line(TokenKind::Invalid),
+        }
+    }
+    /// A key sequence and the `=` after it.
+    fn keys(&mut self) -> Result<(), ()> {
+        loop {
+            let (rest, _) = Name::parse(self.rest(), 0)?;
+            self.take(TokenKind::Key, self.rest().len() - rest.len());
+            self.skip_space();
+            match self.rest() {
+                [b',', ..] => {
+                    self.take(TokenKind::Operator, 1);
+                    self.skip_space();
+                },
+                [b'=', ..] => {
+                    self.take(TokenKind::Operator, 1);
+                    return Ok(())
+                },
+                _ => return Err(()),
+            }
+        }
+    }
+    /// Value components joined by `+`, until the value ends or the line does.
+    ///
+    /// Returns [`LineState::Start`] with input left on the line if the value
+    /// ended at a closing tag.
+    fn value(&mut self) -> LineState {
+        loop {
+            self.skip_space();
+            if let Some(state) = self.component() {
+                return state
+            }
+            if let Some(state) = self.after_component() {
+                return state
+            }
+        }
+    }
+    /// A single value component, unless a string in it doesn't end on this line.
+    fn component(&mut self) -> Option<LineState> {
+        let marked = matches!(self.rest(), [b'_', b'"', ..]) || self.rest().starts_with(b"_<<");
+        if marked {
+            self.take(TokenKind::Operator, 1);
+        }
+        let start = self.pos;
+        if self.rest().starts_with(b"\"") {
+            self.pos += 1;
+            if !self.string_body(start) {
+                return Some(LineState::InString)
+            }
+        } else if self.rest().starts_with(b"<<") {
+            self.pos += 2;
+            if !self.raw_string_body(start) {
+                return Some(LineState::InRawString)
+            }
+        } else {
+            let mut end = self.pos;
+            while let [a, ..] = self.rest() {
+                if *a == b'+' || (*a == b'[' && closing_tag_ahead(self.rest())) {
+                    break
+                }
+                self.pos += 1;
+                if !matches!(a, b' ' | b'\t' | b'\r' | b'\n') {
+                    end = self.pos;
+                }
+            }
+            self.pos = end;
+            if end > start {
+                self.push(TokenKind::Value, start);
+            }
+        }
+        None
+    }
+    /// A `+` and whatever follows it on this line, or the end of the value.
+    fn after_component(&mut self) -> Option<LineState> {
+        self.skip_space();
+        match self.rest() {
+            [b'+', ..] => {
+                self.take(TokenKind::Operator, 1);
+                self.skip_space();
+                self.rest().is_empty().then_some(LineState::Continuation)
+            },
+            [] => Some(LineState::Start),
+            rest if closing_tag_ahead(rest) => Some(LineState::Start),
+            _ => {
+                self.take_line(TokenKind::Invalid);
+                Some(LineState::Start)
+            },
+        }
+    }
+    /// The start of a line following a trailing `+`.
+    fn continuation(&mut self) -> LineState {
+        self.skip_space();
+        if self.rest().is_empty() {
+            return LineState::Continuation
+        }
+        if self.rest().starts_with(b"#textdomain") {
+            self.take_line(TokenKind::Directive);
+            return LineState::Continuation
+        }
+        match self.value() {
+            LineState::Start => self.statements(),
+            state => state,
+        }
+    }
+    /// The start of a line inside a string which began on an earlier line.
+    fn resume(&mut self, body: fn(&mut Self, usize) -> bool, inside: LineState) -> LineState {
+        if !body(self, 0) {
+            return inside
+        }
+        let state = match self.after_component() {
+            Some(state) => state,
+            None => self.value(),
+        };
+        match state {
+            LineState::Start => self.statements(),
+            state => state,
+        }
+    }
+    /// The rest of a quoted string, with its token starting at `start`.
+    ///
+    /// Returns whether the string ended on this line.
+    fn string_body(&mut self, start: usize) -> bool {
+        let mut cursor = self.rest();
+        let ended = loop {
+            match cursor {
+                [b'"', b'"', rest @ ..] => cursor = rest,
+                [b'"', rest @ ..] => {
+                    cursor = rest;
+                    break true
+                },
+                [_, rest @ ..] => cursor = rest,
+                [] => break false,
+            }
+        };
+        self.pos = self.line.len() - cursor.len();
+        self.push(TokenKind::Value, start);
+        ended
+    }
+    /// The rest of a raw string, with its token starting at `start`.
+    ///
+    /// Returns whether the string ended on this line.
+    fn raw_string_body(&mut self, start: usize) -> bool {
+        let rest = self.rest();
+        let ended = match rest.windows(2).position(|w| w == b">>") {
+            Some(end) => {
+                self.pos += end + 2;
+                true
+            },
+            None => {
+                self.pos = self.line.len();
+                false
+            },
+        };
+        self.push(TokenKind::Value, start);
+        ended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tokenize_line, LineState, Token, TokenKind};
+
+    fn tokenize(input: &str) -> Vec<(Vec<(TokenKind, &str)>, LineState)> {
+        let mut state = LineState::Start;
+        input.lines().map(|line| {
+            let (tokens, next) = tokenize_line(line.as_bytes(), state);
+            state = next;
+            let tokens = tokens.into_iter().map(|Token { kind, span }| (kind, &line[span])).collect();
+            (tokens, state)
+        }).collect()
+    }
+
+    #[test]
+    fn raw_string_across_lines() {
+        use TokenKind::*;
+        let lines = tokenize(concat!(
+            "[message]\n",
+            "text=_<<first\n",
+            "second\n",
+            "third>> + \"x\" +\n",
+            "#textdomain wesnoth-lib\n",
+            "\"y\"\"\n",
+            "z\"[/message] # done\n",
+        ));
+        assert_eq!(lines, [
+            (vec![(Bracket, "["), (TagName, "message"), (Bracket, "]")], LineState::Start),
+            (vec![(Key, "text"), (Operator, "="), (Operator, "_"), (Value, "<<first")], LineState::InRawString),
+            (vec![(Value, "second")], LineState::InRawString),
+            (vec![(Value, "third>>"), (Operator, "+"), (Value, "\"x\""), (Operator, "+")], LineState::Continuation),
+            (vec![(Directive, "#textdomain wesnoth-lib")], LineState::Continuation),
+            (vec![(Value, "\"y\"\"")], LineState::InString),
+            (vec![(Value, "z\""), (Bracket, "[/"), (TagName, "message"), (Bracket, "]"), (Comment, "# done")], LineState::Start),
+        ]);
+    }
+
+    #[test]
+    fn keys_and_text() {
+        use TokenKind::*;
+        let lines = tokenize("a, b = some text + more\nkey=a[b[/tag]\n@oops\n");
+        assert_eq!(lines, [
+            (vec![(Key, "a"), (Operator, ","), (Key, "b"), (Operator, "="),
+                (Value, "some text"), (Operator, "+"), (Value, "more")], LineState::Start),
+            (vec![(Key, "key"), (Operator, "="), (Value, "a[b"),
+                (Bracket, "[/"), (TagName, "tag"), (Bracket, "]")], LineState::Start),
+            (vec![(Invalid, "@oops")], LineState::Start),
+        ]);
+    }
+}
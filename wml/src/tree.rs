@@ -0,0 +1,820 @@
+//! Borrowed views into a parsed [`Doc`], for reading it from outside the crate.
+use crate::{Attribute, Doc, NameId, StrPrinter, Tag, TagOrAttr, TextDomain, ValueComponent};
+use ::core::ops::Range;
+use ::core::str::Utf8Error;
+use ::std::borrow::Cow;
+use ::std::collections::BTreeSet;
+
+/// A tag or attribute in a parsed [`Doc`].
+#[derive(Debug, Copy, Clone)]
+pub enum NodeRef<'d> {
+    Tag(TagRef<'d>),
+    Attr(AttrRef<'d>),
+}
+impl<'d> NodeRef<'d> {
+    fn new(node: &'d TagOrAttr<'d>, text: &'d [u8], utf8: bool) -> Self {
+        match node {
+            TagOrAttr::Tag(tag) => Self::Tag(TagRef::new(tag, text, utf8)),
+            TagOrAttr::Attr(attr) => Self::Attr(AttrRef::new(attr, text, utf8)),
+        }
+    }
+    /// Where this node's name is in the buffer the document was parsed from:
+    /// a tag's name, without the brackets, or an attribute's key, as [`AttrRef::key_span`] gives it.
+    pub fn span(self) -> Range<usize> {
+        match self {
+            Self::Tag(tag) => tag.name_span(),
+            Self::Attr(attr) => attr.key_span(),
+        }
+    }
+}
+
+/// A tag in a parsed [`Doc`], along with everything inside it.
+#[derive(Debug, Copy, Clone)]
+pub struct TagRef<'d> {
+    tag: &'d Tag<'d>,
+    text: &'d [u8],
+    /// Whether `text` is known to be valid UTF-8, see [`Doc::is_utf8`].
+    utf8: bool,
+}
+impl<'d> TagRef<'d> {
+    pub(crate) fn new(tag: &'d Tag<'d>, text: &'d [u8], utf8: bool) -> Self {
+        Self { tag, text, utf8 }
+    }
+    /// The name of this tag.
+    pub fn name_str(self) -> Result<&'d str, Utf8Error> {
+        ::core::str::from_utf8(self.tag.name.content.get(self.text))
+    }
+    /// Where this tag's name is in the buffer the document was parsed from, in its opening tag.
+    pub fn name_span(self) -> Range<usize> {
+        self.tag.name.content.span()
+    }
+    /// Whether this is a merge tag, written `[+name]`.
+    pub fn is_merge(self) -> bool {
+        self.tag.merge
+    }
+    /// The interned id of this tag's name, if it was parsed
+    /// [`with_interning`](crate::DocProcessor::with_interning).
+    pub fn name_id(self) -> Option<NameId> {
+        self.tag.name.id
+    }
+    /// The tags and attributes directly inside this tag, in order.
+    pub fn children(self) -> impl Iterator<Item = NodeRef<'d>> {
+        let (text, utf8) = (self.text, self.utf8);
+        self.tag.content.iter().map(move |node| NodeRef::new(node, text, utf8))
+    }
+    /// The last tag or attribute directly inside this tag named `name`.
+    ///
+    /// It's the last because a later attribute with the same key overrides an earlier one.
+    /// See [`duplicate_keys`](Self::duplicate_keys) to find out whether that happened.
+    /// An attribute with a key sequence matches any of its keys.
+    pub fn get(self, name: &str) -> Option<NodeRef<'d>> {
+        self.children_named(name).last()
+    }
+    /// Like [`get`](Self::get), but ignoring ASCII case, so `NAME` finds a `name` attribute.
+    pub fn get_ignore_ascii_case(self, name: &str) -> Option<NodeRef<'d>> {
+        let text = self.text;
+        self.tag.content.iter().rev()
+            .find(|node| is_named_by(node, text, name.as_bytes(), <[u8]>::eq_ignore_ascii_case))
+            .map(|node| NodeRef::new(node, text, self.utf8))
+    }
+    /// Every key given by more than one of the attributes directly inside this tag,
+    /// in the order they first appear.
+    ///
+    /// Each key of a key sequence counts separately.
+    pub fn duplicate_keys(self) -> Vec<&'d str> {
+        let text = self.text;
+        let mut seen = BTreeSet::new();
+        let mut duplicates = Vec::new();
+        let keys = self.tag.content.iter().flat_map(|node| match node {
+            TagOrAttr::Attr(attr) => {
+                let keys = &attr.key_sequence;
+                Some(::core::iter::once(&keys.first).chain(keys.names.iter()))
+            },
+            TagOrAttr::Tag(_) => None,
+        }).flatten();
+        for key in keys {
+            // Names are always ASCII.
+            let key = ::core::str::from_utf8(key.content.get(text)).unwrap();
+            if !seen.insert(key) && !duplicates.contains(&key) {
+                duplicates.push(key);
+            }
+        }
+        duplicates
+    }
+    /// Every tag or attribute directly inside this tag named `name`, in order.
+    ///
+    /// This is [`get`](Self::get) for repeated names. For the values of repeated
+    /// attributes, see [`get_all`](Self::get_all).
+    pub fn children_named<'k>(self, name: &'k str) -> impl Iterator<Item = NodeRef<'d>> + 'k where 'd: 'k {
+        let (text, utf8) = (self.text, self.utf8);
+        self.tag.content.iter()
+            .filter(move |node| is_named(node, text, name.as_bytes()))
+            .map(move |node| NodeRef::new(node, text, utf8))
+    }
+    /// Every value this tag's attributes give to `key`, in the order they appear.
+    ///
+    /// An attribute with a key sequence, like `a,b=1,2`, assigns its value positionally:
+    /// the value is split on commas, and `a` gets `1` while `b` gets `2`.
+    /// A key with no corresponding piece of the value gets an empty value.
+    ///
+    /// Attributes of nested tags aren't included.
+    pub fn get_all<'k>(self, key: &'k str) -> impl Iterator<Item = Cow<'d, [u8]>> + 'k where 'd: 'k {
+        let text = self.text;
+        self.tag.content.iter().filter_map(move |node| match node {
+            TagOrAttr::Attr(attr) => value_of(attr, text, key.as_bytes()),
+            TagOrAttr::Tag(_) => None,
+        })
+    }
+}
+
+/// Check whether `node` is a tag named `name`, or an attribute with `name` as a key.
+pub(crate) fn is_named(node: &TagOrAttr<'_>, text: &[u8], name: &[u8]) -> bool {
+    is_named_by(node, text, name, <[u8]>::eq)
+}
+
+/// Like [`is_named`], but comparing names with `eq`.
+fn is_named_by(node: &TagOrAttr<'_>, text: &[u8], name: &[u8], eq: fn(&[u8], &[u8]) -> bool) -> bool {
+    match node {
+        TagOrAttr::Tag(tag) => eq(tag.name.content.get(text), name),
+        TagOrAttr::Attr(attr) => {
+            let keys = &attr.key_sequence;
+            ::core::iter::once(&keys.first).chain(keys.names.iter()).any(|key| eq(key.content.get(text), name))
+        },
+    }
+}
+
+/// Get the value `attr` assigns to `key`, if any.
+fn value_of<'d>(attr: &'d Attribute<'_>, text: &'d [u8], key: &[u8]) -> Option<Cow<'d, [u8]>> {
+    let keys = &attr.key_sequence;
+    if keys.names.is_empty() {
+        return (keys.first.content.get(text) == key).then(|| attr.value.resolve(text))
+    }
+    let position = ::core::iter::once(&keys.first).chain(keys.names.iter())
+        .position(|name| name.content.get(text) == key)?;
+    Some(piece_of(&attr.value.resolve(text), position))
+}
+
+/// Get the `n`th comma separated piece of `value`, only allocating if `value` did.
+pub(crate) fn piece_of<'v>(value: &Cow<'v, [u8]>, n: usize) -> Cow<'v, [u8]> {
+    match value {
+        Cow::Borrowed(value) => Cow::Borrowed(nth_piece(value, n)),
+        Cow::Owned(value) => Cow::Owned(nth_piece(value, n).to_vec()),
+    }
+}
+
+/// Get the `n`th comma separated piece of `value`, or nothing if it doesn't have that many.
+fn nth_piece(value: &[u8], n: usize) -> &[u8] {
+    value.split(|&b| b == b',').nth(n).unwrap_or(b"")
+}
+
+/// An attribute in a parsed [`Doc`].
+#[derive(Debug, Copy, Clone)]
+pub struct AttrRef<'d> {
+    attr: &'d Attribute<'d>,
+    text: &'d [u8],
+    /// Whether `text` is known to be valid UTF-8, see [`Doc::is_utf8`].
+    utf8: bool,
+}
+impl<'d> AttrRef<'d> {
+    pub(crate) fn new(attr: &'d Attribute<'d>, text: &'d [u8], utf8: bool) -> Self {
+        Self { attr, text, utf8 }
+    }
+    /// The key of this attribute.
+    ///
+    /// For a key sequence, like `a,b=1,2`, this is the whole sequence as written, `a,b`.
+    pub fn key_str(self) -> Result<&'d str, Utf8Error> {
+        ::core::str::from_utf8(&self.text[self.key_span()])
+    }
+    /// Where this attribute's key is in the buffer the document was parsed from.
+    ///
+    /// For a key sequence, this covers the whole sequence, like [`key_str`](Self::key_str).
+    pub fn key_span(self) -> Range<usize> {
+        let keys = &self.attr.key_sequence;
+        let last = keys.names.last().unwrap_or(&keys.first);
+        keys.first.content.idx .. last.content.idx + last.content.len
+    }
+    /// Where this attribute's value is in the buffer the document was parsed from,
+    /// from the start of its first component to the end of its last,
+    /// including quotes and `_` markers, but not the whitespace around unquoted text.
+    pub fn value_span(self) -> Range<usize> {
+        self.attr.value.span(self.text)
+    }
+    /// The interned ids of this attribute's keys, in order, if it was parsed
+    /// [`with_interning`](crate::DocProcessor::with_interning).
+    pub fn key_ids(self) -> impl Iterator<Item = NameId> + 'd {
+        let keys = &self.attr.key_sequence;
+        ::core::iter::once(&keys.first).chain(keys.names.iter()).filter_map(|name| name.id)
+    }
+    /// Each key of this attribute along with the value it assigns to that key.
+    ///
+    /// A single key gets the whole value. A key sequence, like `x,y,z="1,2,3"`, assigns
+    /// the value positionally, as [`TagRef::get_all`] does: it's split on commas, and `x` gets `1`,
+    /// `y` gets `2`, and `z` gets `3`. When there are more keys than pieces of the value,
+    /// the keys left over get an empty value, and when there are more pieces than keys,
+    /// the pieces left over are dropped.
+    pub fn expand(self) -> impl Iterator<Item = (&'d str, Cow<'d, [u8]>)> {
+        let keys = &self.attr.key_sequence;
+        let text = self.text;
+        let value = self.value();
+        let single = keys.names.is_empty();
+        ::core::iter::once(&keys.first).chain(keys.names.iter()).enumerate().map(move |(n, key)| {
+            // Names are always ASCII.
+            let key = ::core::str::from_utf8(key.content.get(text)).unwrap();
+            let value = if single { value.clone() } else { piece_of(&value, n) };
+            (key, value)
+        })
+    }
+    /// The value of this attribute, with every `+` joined component concatenated.
+    ///
+    /// Quoted strings have `""` collapsed to `"`, raw strings are taken verbatim,
+    /// and unquoted text has surrounding whitespace trimmed.
+    /// `_` translation markers aren't part of the value, so they're dropped.
+    /// An empty value, however it's written, is the empty string, which
+    /// [`Doc::write_to`](crate::Doc::write_to) writes as `key=""`.
+    ///
+    /// Only allocates when there's more than one component, or escapes to collapse.
+    pub fn value(self) -> Cow<'d, [u8]> {
+        self.attr.value.resolve(self.text)
+    }
+    /// Like [`value`](Self::value), as a string.
+    ///
+    /// This is only checked for being valid UTF-8 if the whole document wasn't already,
+    /// see [`Doc::is_utf8`].
+    pub fn value_str(self) -> Result<Cow<'d, str>, Utf8Error> {
+        if self.utf8 {
+            // Safety: the whole buffer is valid UTF-8, and values are cut out of it
+            // at ASCII delimiters, with only ASCII bytes taken out of them.
+            return Ok(match self.value() {
+                Cow::Borrowed(value) => Cow::Borrowed(unsafe { ::core::str::from_utf8_unchecked(value) }),
+                Cow::Owned(value) => Cow::Owned(unsafe { String::from_utf8_unchecked(value) }),
+            })
+        }
+        Ok(match self.value() {
+            Cow::Borrowed(value) => Cow::Borrowed(::core::str::from_utf8(value)?),
+            Cow::Owned(value) => Cow::Owned(String::from_utf8(value).map_err(|e| e.utf8_error())?),
+        })
+    }
+    /// The value of this attribute as an integer, like `forum_id="0"`.
+    pub fn as_i64(self) -> Option<i64> {
+        self.value_str().ok()?.trim().parse().ok()
+    }
+    /// The value of this attribute as a number, like `x="1.5"`.
+    pub fn as_f64(self) -> Option<f64> {
+        self.value_str().ok()?.trim().parse().ok()
+    }
+    /// The value of this attribute as a boolean, like `available="yes"`.
+    ///
+    /// Accepts `yes`, `true` and `on`, or `no`, `false` and `off`, ignoring case.
+    pub fn as_bool(self) -> Option<bool> {
+        parse_bool(&self.value_str().ok()?)
+    }
+    /// The textdomain given by a `#textdomain` directive before this attribute,
+    /// and after the attribute before it in the document, if any.
+    pub fn textdomain(self) -> Option<&'d str> {
+        self.attr.domain.as_ref().map(|domain| domain_str(domain, self.text))
+    }
+    /// The components of this attribute's value, which are joined with `+`, in order.
+    pub fn components(self) -> impl Iterator<Item = ComponentRef<'d>> {
+        let value = &self.attr.value;
+        let text = self.text;
+        ::core::iter::once((self.attr.domain.as_ref(), &value.first))
+            .chain(value.rest.iter().map(|(domain, component)| (domain.as_ref(), component)))
+            .map(move |(domain, component)| ComponentRef { component, domain, text })
+    }
+}
+
+/// Compare the value of an attribute, resolved like [`AttrRef::value`], with a string,
+/// without allocating, like `attr == "yes"`.
+impl PartialEq<str> for AttrRef<'_> {
+    fn eq(&self, other: &str) -> bool {
+        self.attr.value.bytes(self.text).eq(other.bytes())
+    }
+}
+impl PartialEq<&str> for AttrRef<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        *self == **other
+    }
+}
+
+/// Read a boolean the way [`AttrRef::as_bool`] does.
+pub(crate) fn parse_bool(value: &str) -> Option<bool> {
+    let value = value.trim();
+    if ["yes", "true", "on"].iter().any(|b| value.eq_ignore_ascii_case(b)) {
+        Some(true)
+    } else if ["no", "false", "off"].iter().any(|b| value.eq_ignore_ascii_case(b)) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Names of textdomains are always ASCII.
+fn domain_str<'d>(domain: &TextDomain, text: &'d [u8]) -> &'d str {
+    ::core::str::from_utf8(domain.name.get(text)).unwrap()
+}
+
+/// One of the `+` joined components of an attribute's value.
+#[derive(Debug, Copy, Clone)]
+pub struct ComponentRef<'d> {
+    component: &'d ValueComponent<'d>,
+    domain: Option<&'d TextDomain>,
+    text: &'d [u8],
+}
+impl<'d> ComponentRef<'d> {
+    /// The content of this component, resolved like [`AttrRef::value`].
+    pub fn value(self) -> Cow<'d, [u8]> {
+        self.component.resolve(self.text)
+    }
+    /// The textdomain given by a `#textdomain` directive directly before this component, if any.
+    ///
+    /// For the first component, that's the one before the whole attribute.
+    /// Where there isn't one, the domain set last before it in the document applies.
+    pub fn textdomain(self) -> Option<&'d str> {
+        self.domain.map(|domain| domain_str(domain, self.text))
+    }
+    /// Whether this component was marked for translation with `_`, like `_"Hero"`.
+    pub fn is_translatable(self) -> bool {
+        self.component.is_translatable()
+    }
+}
+
+impl Doc<'_> {
+    /// The top level tags and attributes of this document, in order.
+    ///
+    /// ```
+    /// use wml::{DocProcessor, NodeRef};
+    /// let processor = DocProcessor::new();
+    /// let doc = processor.parse(Vec::from(concat!(
+    ///     "[user]\navailable=\"yes\"\nforum_id=\"0\"\ngame_id=\"0\"\nlocation=\"\"\n",
+    ///     "moderator=\"no\"\nname=\"lol\"\nregistered=\"no\"\nstatus=\"lobby\"\n[/user]\n",
+    ///     "[user]\navailable=\"yes\"\nforum_id=\"0\"\ngame_id=\"0\"\nlocation=\"\"\n",
+    ///     "moderator=\"no\"\nname=\"haha\"\nregistered=\"no\"\nstatus=\"lobby\"\n[/user]\n",
+    /// ))).unwrap();
+    /// let users = doc.children().filter(|node| matches!(node, NodeRef::Tag(_))).count();
+    /// assert_eq!(users, 2);
+    /// ```
+    pub fn children(&self) -> impl Iterator<Item = NodeRef<'_>> {
+        self.top.iter().map(move |node| NodeRef::new(node, &self.text, self.utf8))
+    }
+    /// How many tags and attributes are at the top level of this document.
+    pub fn len(&self) -> usize {
+        self.top.len()
+    }
+    /// Whether there's nothing at the top level of this document, so nothing in it at all.
+    pub fn is_empty(&self) -> bool {
+        self.top.is_empty()
+    }
+    /// Whether this document was checked to be valid UTF-8 as it was parsed,
+    /// [`with_utf8_validation`](crate::DocProcessor::with_utf8_validation).
+    ///
+    /// If it was, [`AttrRef::value_str`] doesn't check values again.
+    pub fn is_utf8(&self) -> bool {
+        self.utf8
+    }
+    /// A [`StrPrinter`] over the buffer this document was parsed from, if it's valid UTF-8,
+    /// for getting strings out of it without checking them again.
+    pub fn str_printer(&self) -> Option<StrPrinter<'_>> {
+        // Safety: it was checked when parsing, and hasn't changed since.
+        self.utf8.then(|| StrPrinter::new(unsafe { ::core::str::from_utf8_unchecked(&self.text) }))
+    }
+    /// The last node at `path`, a `/` separated list of names, like `gamelist/game/name`.
+    ///
+    /// A later duplicate overrides an earlier one, as with [`TagRef::get`].
+    /// See [`find_all`](Self::find_all).
+    pub fn find(&self, path: &str) -> Option<NodeRef<'_>> {
+        self.find_all(path).last()
+    }
+    /// Like [`find`](Self::find), but ignoring ASCII case in each name of `path`.
+    pub fn find_ignore_ascii_case(&self, path: &str) -> Option<NodeRef<'_>> {
+        self.find_all_by(path, <[u8]>::eq_ignore_ascii_case).last()
+    }
+    /// Every node at `path`, a `/` separated list of names, in document order.
+    ///
+    /// Each name but the last picks out tags, starting from the top level of the document,
+    /// and the last picks out tags or attributes inside them, just as [`TagRef::get`] does.
+    /// So `gamelist/game/name` finds the `name` attribute of every `[game]` in every `[gamelist]`,
+    /// as well as any `[name]` tags there.
+    ///
+    /// An empty name, as in an empty path or one ending in `/`, matches nothing.
+    pub fn find_all<'s, 'p>(&'s self, path: &'p str) -> impl Iterator<Item = NodeRef<'s>> + 'p where 's: 'p {
+        self.find_all_by(path, <[u8]>::eq)
+    }
+    /// Like [`find_all`](Self::find_all), but comparing names with `eq`.
+    fn find_all_by<'s, 'p>(&'s self, path: &'p str, eq: fn(&[u8], &[u8]) -> bool) -> impl Iterator<Item = NodeRef<'s>> + 'p where 's: 'p {
+        let text = &self.text[..];
+        let (parents, last) = match path.rsplit_once('/') {
+            Some((parents, last)) => (Some(parents), last),
+            None => (None, path),
+        };
+        let mut contents: Box<dyn Iterator<Item = &'s [TagOrAttr<'s>]> + 'p> = Box::new(::core::iter::once(&self.top[..]));
+        for name in parents.into_iter().flat_map(|parents| parents.split('/')) {
+            contents = Box::new(contents.flat_map(move |content| content.iter().filter_map(move |node| match node {
+                TagOrAttr::Tag(tag) if eq(tag.name.content.get(text), name.as_bytes()) => Some(&tag.content[..]),
+                _ => None,
+            })));
+        }
+        contents.flat_map(move |content| {
+            content.iter()
+                .filter(move |node| is_named_by(node, text, last.as_bytes(), eq))
+                .map(move |node| NodeRef::new(node, text, self.utf8))
+        })
+    }
+    /// The name of every tag in this document, at any depth.
+    pub fn tag_names(&self) -> BTreeSet<&str> {
+        let mut names = BTreeSet::new();
+        let mut pending = vec![&self.top[..]];
+        while let Some(content) = pending.pop() {
+            for node in content {
+                if let TagOrAttr::Tag(tag) = node {
+                    // Names are always ASCII.
+                    names.insert(::core::str::from_utf8(tag.name.content.get(&self.text)).unwrap());
+                    pending.push(&tag.content);
+                }
+            }
+        }
+        names
+    }
+    /// Every attribute in this document, at any depth, in document order.
+    fn attributes(&self) -> impl Iterator<Item = AttrRef<'_>> {
+        let text = &self.text[..];
+        let mut open = vec![self.top.iter()];
+        ::core::iter::from_fn(move || loop {
+            match open.last_mut()?.next() {
+                Some(TagOrAttr::Tag(tag)) => open.push(tag.content.iter()),
+                Some(TagOrAttr::Attr(attr)) => return Some(AttrRef::new(attr, text, self.utf8)),
+                None => {
+                    open.pop();
+                },
+            }
+        })
+    }
+    /// Every string marked for translation with `_` in this document, in document order,
+    /// along with the textdomain it's translated in, for building translation catalogs.
+    ///
+    /// That's the domain set by the last `#textdomain` directive before the string in the document, if any.
+    /// Strings that aren't valid UTF-8 have the invalid parts replaced, as [`String::from_utf8_lossy`] does.
+    pub fn translatable_strings(&self) -> impl Iterator<Item = (Option<&str>, Cow<'_, str>)> {
+        self.attributes()
+            .flat_map(AttrRef::components)
+            .scan(None, |domain, component| {
+                if let Some(set) = component.textdomain() {
+                    *domain = Some(set);
+                }
+                Some((*domain, component))
+            })
+            .filter(|(_, component)| component.is_translatable())
+            .map(|(domain, component)| (domain, match component.value() {
+                Cow::Borrowed(value) => String::from_utf8_lossy(value),
+                Cow::Owned(value) => Cow::Owned(String::from_utf8_lossy(&value).into_owned()),
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DocProcessor, NodeRef};
+    use crate::test_util::{first_tag, tags};
+    use ::std::borrow::Cow;
+
+    #[test]
+    fn get_all_in_order() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "[filter_group]\n",
+            "filter=\"a\"\n",
+            "[filter_group]\nfilter=\"nested\"\n[/filter_group]\n",
+            "other=\"x\"\n",
+            "filter=\"b\"\n",
+            "side,filter=\"1,c\"\n",
+            "filter,side,type=\"d\"\n",
+            "[/filter_group]\n",
+        ))).unwrap();
+        let tag = first_tag(&doc);
+        let filters = tag.get_all("filter").collect::<Vec<_>>();
+        assert_eq!(filters, [&b"a"[..], b"b", b"c", b"d"]);
+        let sides = tag.get_all("side").collect::<Vec<_>>();
+        assert_eq!(sides, [&b"1"[..], b""]);
+        assert_eq!(tag.get_all("missing").count(), 0);
+    }
+
+    #[test]
+    fn names_and_keys() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from("[user]\nname=\"lol\"\nside,type=1,2\n[/user]\n")).unwrap();
+        let tag = first_tag(&doc);
+        assert_eq!(tag.name_str(), Ok("user"));
+        let keys = tag.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr.key_str().unwrap(),
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert_eq!(keys, ["name", "side,type"]);
+    }
+
+    #[test]
+    fn resolved_values() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "a=\"foo\" + _\"bar\"\n",
+            "b=<<say \"hi\">> + \"\"\"bye\"\"\" +   text \n",
+            "c=\"plain\"\n",
+        ))).unwrap();
+        let values = doc.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr.value_str().unwrap(),
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert_eq!(values, ["foobar", "say \"hi\"\"bye\"text", "plain"]);
+        assert!(matches!(values[2], Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn empty_values() {
+        let processor = DocProcessor::new();
+        let source = "[user]\na=\nb=\"\"\nc=<<>>\nd=   \n[/user]\n";
+        let doc = processor.parse(Vec::from(source)).unwrap();
+        let tag = first_tag(&doc);
+        let values = tag.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr.value(),
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert_eq!(values, [&b""[..]; 4]);
+        assert!(values.iter().all(|value| matches!(value, Cow::Borrowed(_))));
+        let mut out = Vec::new();
+        doc.write_to(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[user]\na=\"\"\nb=\"\"\nc=<<>>\nd=\"\"\n[/user]\n");
+    }
+
+    #[test]
+    fn textdomains() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "#textdomain wesnoth\n",
+            "msg=_\"Hello\" + #textdomain wesnoth-units\n_\"World\" + \"!\"\n",
+            "plain=\"x\"\n",
+        ))).unwrap();
+        let attrs = doc.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr,
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert_eq!(attrs[0].textdomain(), Some("wesnoth"));
+        let components = attrs[0].components()
+            .map(|component| (component.value(), component.textdomain()))
+            .collect::<Vec<_>>();
+        assert_eq!(components, [
+            (Cow::Borrowed(&b"Hello"[..]), Some("wesnoth")),
+            (Cow::Borrowed(&b"World"[..]), Some("wesnoth-units")),
+            (Cow::Borrowed(&b"!"[..]), None),
+        ]);
+        assert_eq!(attrs[1].textdomain(), None);
+        assert_eq!(attrs[1].components().count(), 1);
+    }
+
+    #[test]
+    fn translatable() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from("name=_\"Hero\"\nname=\"Hero\"\nname=_<<Hero>> + Hero\n")).unwrap();
+        let marked = doc.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr.components().map(|c| c.is_translatable()).collect::<Vec<_>>(),
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert_eq!(marked, [vec![true], vec![false], vec![true, false]]);
+    }
+
+    #[test]
+    fn typed_values() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "forum_id=\" 42 \"\n",
+            "x=-1.5\n",
+            "available=\"Yes\"\n",
+            "moderator=off\n",
+            "name=\"lol\"\n",
+        ))).unwrap();
+        let attrs = doc.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr,
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert_eq!(attrs[0].as_i64(), Some(42));
+        assert_eq!(attrs[0].as_f64(), Some(42.0));
+        assert_eq!(attrs[1].as_f64(), Some(-1.5));
+        assert_eq!(attrs[1].as_i64(), None);
+        assert_eq!(attrs[2].as_bool(), Some(true));
+        assert_eq!(attrs[3].as_bool(), Some(false));
+        assert_eq!(attrs[4].as_bool(), None);
+        assert_eq!(attrs[4].as_i64(), None);
+    }
+
+    #[test]
+    fn get_by_name() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "[gamelist]\n[/gamelist]\n",
+            "[user]\n[info]\n[/info]\nname=\"a\"\nname=\"b\"\nside,type=1,2\n[info]\n[/info]\n[/user]\n",
+        ))).unwrap();
+        let mut tags = tags(&doc);
+        let (gamelist, user) = (tags.next().unwrap(), tags.next().unwrap());
+        assert!(gamelist.get("name").is_none());
+        match user.get("name") {
+            Some(NodeRef::Attr(attr)) => assert_eq!(attr.key_str(), Ok("name")),
+            _ => panic!("expected an attribute"),
+        }
+        assert!(matches!(user.get("type"), Some(NodeRef::Attr(_))));
+        assert!(matches!(user.get("info"), Some(NodeRef::Tag(_))));
+        assert_eq!(user.children_named("info").count(), 2);
+        assert_eq!(user.children_named("name").count(), 2);
+        assert!(user.get("nam").is_none());
+    }
+
+    #[test]
+    fn nested_tag_names() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "[gamelist]\n[game]\n[side]\n[ai]\n[/ai]\n[/side]\n[side]\n[/side]\n[/game]\n[/gamelist]\n",
+            "[user]\nname=\"a\"\n[/user]\n",
+        ))).unwrap();
+        assert_eq!(doc.tag_names().into_iter().collect::<Vec<_>>(), ["ai", "game", "gamelist", "side", "user"]);
+    }
+
+    #[test]
+    fn find_paths() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "[gamelist]\n",
+            "[game]\nid=\"1\"\nname=\"first\"\n[/game]\n",
+            "[game]\nid=\"2\"\n[name]\n[/name]\n[/game]\n",
+            "[/gamelist]\n",
+            "[gamelist]\n[game]\nid=\"3\"\n[/game]\n[/gamelist]\n",
+            "id=\"top\"\n",
+        ))).unwrap();
+        let id = |node: NodeRef<'_>| match node {
+            NodeRef::Attr(attr) => attr.value_str().unwrap().into_owned(),
+            NodeRef::Tag(tag) => format!("[{}]", tag.name_str().unwrap()),
+        };
+        assert_eq!(doc.find("gamelist/game/id").map(id).as_deref(), Some("3"));
+        assert_eq!(doc.find_all("gamelist/game/id").map(id).collect::<Vec<_>>(), ["1", "2", "3"]);
+        assert_eq!(doc.find_all("gamelist/game/name").map(id).collect::<Vec<_>>(), ["first", "[name]"]);
+        assert_eq!(doc.find_all("gamelist/game").count(), 3);
+        assert_eq!(doc.find("id").map(id).as_deref(), Some("top"));
+        assert!(doc.find("game/id").is_none());
+        assert!(doc.find("gamelist/id").is_none());
+        assert!(doc.find("gamelist/").is_none());
+        assert!(doc.find("").is_none());
+    }
+
+    #[test]
+    fn translatable_strings() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "id=\"intro\"\n",
+            "untranslated=_\"Nowhere\"\n",
+            "[message]\n",
+            "#textdomain wesnoth\n",
+            "speaker=\"narrator\"\n",
+            "message=_\"Say \"\"hi\"\".\" + \" \" + #textdomain wesnoth-units\n_\"Elvish Fighter\"\n",
+            "[/message]\n",
+            "name=_\"Still units\"\n",
+            "[a]\n[b]\n#textdomain wesnoth\nc=_<<Deep>>\nd=plain\n[/b]\n[/a]\n",
+        ))).unwrap();
+        let strings = doc.translatable_strings().collect::<Vec<_>>();
+        assert_eq!(strings, [
+            (None, Cow::Borrowed("Nowhere")),
+            (Some("wesnoth"), Cow::Owned("Say \"hi\".".into())),
+            (Some("wesnoth-units"), Cow::Borrowed("Elvish Fighter")),
+            (Some("wesnoth-units"), Cow::Borrowed("Still units")),
+            (Some("wesnoth"), Cow::Borrowed("Deep")),
+        ]);
+        // A directive before the first tag applies to the strings inside it.
+        let doc = processor.parse(Vec::from("#textdomain wesnoth-help\n[topic]\ntitle=_\"Help\"\n[/topic]\n")).unwrap();
+        assert_eq!(doc.translatable_strings().collect::<Vec<_>>(), [(Some("wesnoth-help"), Cow::Borrowed("Help"))]);
+    }
+
+    #[test]
+    fn expand_key_sequences() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from("x,y,z=\"1,2,3\"\nname=\"a,b\"\nside,type=\"1\" + \"\"\nfirst,second=1,2,3\n")).unwrap();
+        let expanded = doc.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr.expand().collect::<Vec<_>>(),
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        let pairs = |pairs: &[(&'static str, &'static str)]| {
+            pairs.iter().map(|&(key, value)| (key, Cow::Borrowed(value.as_bytes()))).collect::<Vec<_>>()
+        };
+        assert_eq!(expanded, [
+            pairs(&[("x", "1"), ("y", "2"), ("z", "3")]),
+            pairs(&[("name", "a,b")]),
+            pairs(&[("side", "1"), ("type", "")]),
+            pairs(&[("first", "1"), ("second", "2")]),
+        ]);
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "[user]\na=\"1\"\nb=\"x\"\na=\"2\"\n[a]\n[/a]\nb,c=y,z\na=\"3\"\n[/user]\n",
+            "[user]\na=\"1\"\n[inner]\na=\"2\"\n[/inner]\n[/user]\n",
+        ))).unwrap();
+        let mut tags = tags(&doc);
+        let (user, other) = (tags.next().unwrap(), tags.next().unwrap());
+        match user.get("a") {
+            Some(NodeRef::Attr(attr)) => assert_eq!(attr.value(), &b"3"[..]),
+            _ => panic!("expected an attribute"),
+        }
+        assert_eq!(user.duplicate_keys(), ["a", "b"]);
+        assert!(other.duplicate_keys().is_empty());
+    }
+
+    #[test]
+    fn borrowed_strings() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from("name=\"lol\"\nname=\"a\"\"b\"\nname=\"a\" + \"b\"\nname=<<a\"\"b>>\n")).unwrap();
+        let values = doc.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr.value_str().unwrap(),
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert_eq!(values, ["lol", "a\"b", "ab", "a\"\"b"]);
+        assert!(matches!(values[0], Cow::Borrowed("lol")));
+        assert!(matches!(values[1], Cow::Owned(_)));
+        assert!(matches!(values[2], Cow::Owned(_)));
+        assert!(matches!(values[3], Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn compare_with_str() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "available=\"yes\"\n",
+            "quote=\"a\"\"b\"\"\"\n",
+            "joined=\"a\"\"\" + <<b\"\">> + c \n",
+            "empty=\n",
+        ))).unwrap();
+        let attrs = doc.children().map(|node| match node {
+            NodeRef::Attr(attr) => attr,
+            NodeRef::Tag(_) => panic!("expected an attribute"),
+        }).collect::<Vec<_>>();
+        assert!(attrs[0] == "yes");
+        assert!(attrs[0] != "no");
+        assert!(attrs[0] != "ye");
+        assert!(attrs[0] != "yess");
+        assert!(attrs[1] == "a\"b\"");
+        assert!(attrs[1] != "a\"\"b\"\"");
+        assert!(attrs[2] == *"a\"b\"\"c");
+        assert!(attrs[3] == "");
+        for attr in attrs {
+            assert!(attr == *attr.value_str().unwrap());
+        }
+    }
+
+    #[test]
+    fn top_level_len() {
+        let processor = DocProcessor::new();
+        for empty in ["", "\n", "# just a comment\n"] {
+            let doc = processor.parse(Vec::from(empty)).unwrap();
+            assert_eq!((doc.len(), doc.is_empty()), (0, true));
+        }
+        let doc = processor.parse(Vec::from("[version]\n[/version]\n")).unwrap();
+        assert_eq!((doc.len(), doc.is_empty()), (1, false));
+        let user = "[user]\navailable=\"yes\"\nname=\"lol\"\n[/user]\n";
+        let doc = processor.parse(format!("[gamelist]\n[/gamelist]\n{}version=\"1.16\"\n", user.repeat(3)).into_bytes()).unwrap();
+        assert_eq!((doc.len(), doc.is_empty()), (5, false));
+    }
+
+    #[test]
+    fn ignore_ascii_case() {
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from("[Login]\nname=\"lol\"\n[/Login]\n")).unwrap();
+        let login = match doc.children().next() {
+            Some(NodeRef::Tag(login)) => login,
+            other => panic!("{:?}", other),
+        };
+        assert!(login.get("NAME").is_none());
+        match login.get_ignore_ascii_case("NAME") {
+            Some(NodeRef::Attr(name)) => assert_eq!(name, "lol"),
+            other => panic!("{:?}", other),
+        }
+        assert!(doc.find("login/name").is_none());
+        assert!(matches!(doc.find_ignore_ascii_case("LOGIN/Name"), Some(NodeRef::Attr(_))));
+        assert!(doc.find_ignore_ascii_case("login/nam").is_none());
+    }
+
+    #[test]
+    fn spans() {
+        let processor = DocProcessor::new();
+        let source = "[login]\nname=\"lol\"\n  a,b = x +_<<y>>\n[/login]\n";
+        let doc = processor.parse(Vec::from(source)).unwrap();
+        let login = doc.children().next().unwrap();
+        assert_eq!(&source[login.span()], "login");
+        let attrs = match login {
+            NodeRef::Tag(login) => login.children().collect::<Vec<_>>(),
+            NodeRef::Attr(_) => unreachable!(),
+        };
+        assert_eq!(&source[attrs[0].span()], "name");
+        match attrs[..] {
+            [NodeRef::Attr(name), NodeRef::Attr(ab)] => {
+                assert_eq!(&source[name.value_span()], "\"lol\"");
+                assert_eq!(&source[ab.key_span()], "a,b");
+                assert_eq!(&source[ab.value_span()], "x +_<<y>>");
+            },
+            _ => panic!("{:?}", attrs),
+        }
+    }
+}
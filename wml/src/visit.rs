@@ -0,0 +1,108 @@
+//! Walking every node of a parsed [`Doc`] in one pass.
+use crate::{AttrRef, Doc, TagOrAttr, TagRef};
+
+/// Callbacks for the nodes of a [`Doc`], in document order, made by [`Doc::accept`].
+///
+/// Every method does nothing by default, so only the ones needed have to be implemented.
+pub trait Visitor<'d> {
+    /// Called on reaching a tag, before anything inside it.
+    fn visit_tag_start(&mut self, tag: TagRef<'d>) {
+        let _ = tag;
+    }
+    /// Called on leaving a tag, after everything inside it.
+    fn visit_tag_end(&mut self, tag: TagRef<'d>) {
+        let _ = tag;
+    }
+    fn visit_attribute(&mut self, attr: AttrRef<'d>) {
+        let _ = attr;
+    }
+}
+
+impl Doc<'_> {
+    /// Walk this whole document with `visitor`.
+    ///
+    /// Open tags are kept on a stack on the heap rather than walked recursively,
+    /// so however deeply nested the document is, this can't overflow the native stack.
+    pub fn accept<'d>(&'d self, visitor: &mut impl Visitor<'d>) {
+        let text = &self.text[..];
+        let mut open = vec![(None, self.top.iter())];
+        while let Some((_, content)) = open.last_mut() {
+            match content.next() {
+                Some(TagOrAttr::Tag(tag)) => {
+                    let tag_ref = TagRef::new(tag, text, self.utf8);
+                    visitor.visit_tag_start(tag_ref);
+                    open.push((Some(tag_ref), tag.content.iter()));
+                },
+                Some(TagOrAttr::Attr(attr)) => visitor.visit_attribute(AttrRef::new(attr, text, self.utf8)),
+                None => {
+                    if let Some((Some(tag), _)) = open.pop() {
+                        visitor.visit_tag_end(tag);
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Visitor;
+    use crate::{AttrRef, DocProcessor, TagRef};
+
+    #[test]
+    fn keys_in_order() {
+        #[derive(Default)]
+        struct Keys<'d> {
+            keys: Vec<&'d str>,
+            events: Vec<String>,
+        }
+        impl<'d> Visitor<'d> for Keys<'d> {
+            fn visit_tag_start(&mut self, tag: TagRef<'d>) {
+                self.events.push(format!("[{}]", tag.name_str().unwrap()));
+            }
+            fn visit_tag_end(&mut self, tag: TagRef<'d>) {
+                self.events.push(format!("[/{}]", tag.name_str().unwrap()));
+            }
+            fn visit_attribute(&mut self, attr: AttrRef<'d>) {
+                self.keys.push(attr.key_str().unwrap());
+                self.events.push(attr.key_str().unwrap().into());
+            }
+        }
+        let processor = DocProcessor::new();
+        let doc = processor.parse(Vec::from(concat!(
+            "version=\"1.16.0\"\n",
+            "[gamelist]\n[game]\nid=\"1\"\nname=\"x\"\n[/game]\n[game]\n[/game]\nsize=\"2\"\n[/gamelist]\n",
+            "a,b=1,2\n",
+        ))).unwrap();
+        let mut keys = Keys::default();
+        doc.accept(&mut keys);
+        assert_eq!(keys.keys, ["version", "id", "name", "size", "a,b"]);
+        assert_eq!(keys.events, [
+            "version", "[gamelist]", "[game]", "id", "name", "[/game]", "[game]", "[/game]", "size", "[/gamelist]", "a,b",
+        ]);
+    }
+
+    #[test]
+    fn deep_visit() {
+        struct Depth {
+            depth: usize,
+            max: usize,
+        }
+        impl Visitor<'_> for Depth {
+            fn visit_tag_start(&mut self, _: TagRef<'_>) {
+                self.depth += 1;
+                self.max = self.max.max(self.depth);
+            }
+            fn visit_tag_end(&mut self, _: TagRef<'_>) {
+                self.depth -= 1;
+            }
+        }
+        let processor = DocProcessor::new().with_max_depth(usize::MAX).with_iterative_parsing();
+        let depth = 100_000;
+        let doc = format!("{}{}", "[a]\n".repeat(depth), "[/a]\n".repeat(depth));
+        let doc = processor.parse(doc.into_bytes()).unwrap();
+        let mut visitor = Depth { depth: 0, max: 0 };
+        doc.accept(&mut visitor);
+        assert_eq!((visitor.depth, visitor.max), (0, depth));
+    }
+}